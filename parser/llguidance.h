@@ -9,6 +9,8 @@
 
 typedef struct LlgConstraint LlgConstraint;
 
+typedef struct LlgLexer LlgLexer;
+
 typedef struct LlgTokenizer LlgTokenizer;
 
 typedef struct LlgParserLimits {
@@ -38,6 +40,27 @@ typedef struct LlgParserLimits {
    * Default: 500_000 (a few megabytes of JSON)
    */
   size_t max_grammar_size;
+  /**
+   * Maximum number of times the parser can backtrack tokens
+   * already generated by the LLM (over the whole generation).
+   * Default: no limit (usize::MAX)
+   */
+  size_t max_backtracks;
+  /**
+   * Maximum number of bytes generated by the LLM (over the whole generation).
+   * Once reached, the generation stops (as accepting if the grammar allows it).
+   * Default: no limit (usize::MAX)
+   */
+  size_t max_output_bytes;
+  /**
+   * Maximum number of Earley items in a row that wait for a lexeme
+   * (that is, possible interpretations of the input so far).
+   * When exceeded, the shortest matches (items that started last) are pruned.
+   * The pruned items are only scanned when none of the kept ones accepts
+   * the next lexeme, so lexemes allowed by the mask are never rejected.
+   * Default: no limit (usize::MAX)
+   */
+  size_t max_live_interpretations;
 } LlgParserLimits;
 
 typedef struct LlgConstraintInit {
@@ -169,6 +192,31 @@ typedef struct LlgTokenizerInit {
   const void *tokenize_user_data;
 } LlgTokenizerInit;
 
+/**
+ * Represents result from llg_lexer_advance() and llg_lexer_finish()
+ */
+typedef struct LlgLexerResult {
+  /**
+   * 0 - the byte was consumed, and no lexeme ended yet
+   * 1 - a lexeme ended (described by the fields below)
+   * -1 - the byte (or end of input) is not allowed here
+   */
+  int32_t kind;
+  /**
+   * The lexeme that ended; see llg_lexer_lexeme_name()
+   */
+  uint32_t lexeme_idx;
+  /**
+   * The number of bytes at the end of the lexeme matched by its stop (lookahead)
+   */
+  uint32_t hidden_len;
+  /**
+   * Does the byte passed to llg_lexer_advance() belong to the next lexeme?
+   * If so, it was already fed to the next lexeme.
+   */
+  bool byte_next_row;
+} LlgLexerResult;
+
 #ifdef __cplusplus
 extern "C" {
 #endif // __cplusplus
@@ -212,7 +260,7 @@ struct LlgConstraint *llg_new_constraint_lark(const struct LlgConstraintInit *in
 
 /**
  * Create a new constraint with specified type
- * Type can be one of "regex", "json_schema" (or "json"), "lark", "llguidance" (or "guidance")
+ * Type can be one of "regex", "json_schema" (or "json"), "lark", "gbnf", "llguidance" (or "guidance")
  * Always returns a non-null value. Call llg_get_error() on the result to check for errors.
  */
 struct LlgConstraint *llg_new_constraint_any(const struct LlgConstraintInit *init,
@@ -300,6 +348,67 @@ void llg_free_constraint(struct LlgConstraint *cc);
  */
 const char *llg_flush_logs(struct LlgConstraint *cc);
 
+/**
+ * Create a new lexer from a spec in the lexer DSL format.
+ * Each line defines a lexeme, as in `ID: /[a-z]+/` or `KW_IF: "if"`;
+ * `SKIP: /[ \t]+/` defines the whitespace to skip.
+ * All lexemes are allowed everywhere.
+ * Always returns a non-null value. Call llg_lexer_get_error() on the result to check for errors.
+ */
+struct LlgLexer *llg_new_lexer(const char *spec);
+
+/**
+ * Get the error message from the lexer or null if there is no error.
+ * After it returns a non-null value, it will always return it until the lexer is freed
+ * using llg_free_lexer() (at which point the pointer will be invalid).
+ */
+const char *llg_lexer_get_error(const struct LlgLexer *lx);
+
+/**
+ * Move the lexer to the start state, before the first byte of a lexeme.
+ * This is done automatically when the lexer is created and after each lexeme.
+ * Returns 0 on success and -1 on error (use llg_lexer_get_error() to get the exact error).
+ */
+int32_t llg_lexer_start_state(struct LlgLexer *lx);
+
+/**
+ * Feed the next byte of input to the lexer.
+ * After a lexeme ends, the lexer starts the next one.
+ * After kind -1 is returned, use llg_lexer_start_state() to continue.
+ * Returns 0 on success and -1 on error (use llg_lexer_get_error() to get the exact error).
+ * When 0 is returned, the result is written to *res_p.
+ */
+int32_t llg_lexer_advance(struct LlgLexer *lx, uint8_t byte, struct LlgLexerResult *res_p);
+
+/**
+ * Check if the input can end at the current position,
+ * that is if llg_lexer_finish() would return a lexeme.
+ */
+bool llg_lexer_allows_eos(struct LlgLexer *lx);
+
+/**
+ * End the input, finishing the current lexeme if any bytes were fed for it
+ * (otherwise kind 0 is returned).
+ * Returns 0 on success and -1 on error (use llg_lexer_get_error() to get the exact error).
+ * When 0 is returned, the result is written to *res_p.
+ */
+int32_t llg_lexer_finish(struct LlgLexer *lx, struct LlgLexerResult *res_p);
+
+/**
+ * Return the name of the lexeme with the given index.
+ * The output is null-terminated.
+ * Returns the number of bytes that would be written to output if output_len was large enough.
+ */
+size_t llg_lexer_lexeme_name(const struct LlgLexer *lx,
+                             uint32_t lexeme_idx,
+                             char *output,
+                             size_t output_len);
+
+/**
+ * Free the lexer
+ */
+void llg_free_lexer(struct LlgLexer *lx);
+
 #ifdef __cplusplus
 }  // extern "C"
 #endif  // __cplusplus
@@ -313,6 +313,10 @@ pub enum StopReason {
     LexerTooComplex,
     /// The parser is too complex
     ParserTooComplex,
+    /// The parser has backtracked more than max_backtracks times.
+    TooManyBacktracks,
+    /// The output has reached max_output_bytes.
+    MaxOutputBytes,
 }
 
 impl StopReason {
@@ -358,6 +362,24 @@ pub struct ParserLimits {
     /// Maximum size of the grammar (symbols in productions)
     /// Default: 500_000 (a few megabytes of JSON)
     pub max_grammar_size: usize,
+
+    /// Maximum number of times the parser can backtrack tokens
+    /// already generated by the LLM (over the whole generation).
+    /// Default: no limit (usize::MAX)
+    pub max_backtracks: usize,
+
+    /// Maximum number of bytes generated by the LLM (over the whole generation).
+    /// Once reached, the generation stops (as accepting if the grammar allows it).
+    /// Default: no limit (usize::MAX)
+    pub max_output_bytes: usize,
+
+    /// Maximum number of Earley items in a row that wait for a lexeme
+    /// (that is, possible interpretations of the input so far).
+    /// When exceeded, the shortest matches (items that started last) are pruned.
+    /// The pruned items are only scanned when none of the kept ones accepts
+    /// the next lexeme, so lexemes allowed by the mask are never rejected.
+    /// Default: no limit (usize::MAX)
+    pub max_live_interpretations: usize,
 }
 
 impl Default for ParserLimits {
@@ -368,6 +390,9 @@ impl Default for ParserLimits {
             step_lexer_fuel: 500_000,      // 500k => 10ms
             max_lexer_states: 10_000,      // ?
             max_grammar_size: 500_000,     // fhir schema => 200k
+            max_backtracks: usize::MAX,
+            max_output_bytes: usize::MAX,
+            max_live_interpretations: usize::MAX,
         }
     }
 }
@@ -1,7 +1,9 @@
-use anyhow::{ensure, Result};
+use anyhow::{anyhow, ensure, Result};
+use serde::{Deserialize, Serialize};
 use toktrie::{Splice, StepArg, StepResult, TokenId};
 
 use crate::{
+    earley::lexer::InputFilter,
     loginfo,
     output::{ParserOutput, Reporter},
     TokenParser,
@@ -17,6 +19,27 @@ pub struct Constraint {
     last_res: StepResult,
     delayed_stop: bool,
     started: bool,
+    history: SessionHistory,
+}
+
+/// Bumped whenever the snapshot format or replay semantics change.
+const SNAPSHOT_VERSION: u32 = 1;
+
+/// Calls made on the constraint so far; replayed by `Constraint::restore()`.
+#[derive(Serialize, Deserialize, Clone, Default)]
+struct SessionHistory {
+    prompt: Option<Vec<TokenId>>,
+    forced: Vec<TokenId>,
+    committed: Vec<Option<TokenId>>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SessionSnapshot {
+    version: u32,
+    grammar_fingerprint: u64,
+    vocab_size: usize,
+    history: SessionHistory,
+    mask_computed: bool,
 }
 
 #[derive(Debug, Clone, Default)]
@@ -60,6 +83,7 @@ impl Constraint {
             last_res: StepResult::noop(),
             delayed_stop: false,
             started: false,
+            history: SessionHistory::default(),
             log_json_progress: false,
             temperature: 0.0,
         }
@@ -71,6 +95,15 @@ impl Constraint {
         copy
     }
 
+    /// See `TokenParser::set_input_filter()`; must be called before the first
+    /// `process_prompt()` or `compute_mask()`.
+    /// The filter is not part of `snapshot()`, so set it on the parser
+    /// passed to `restore()`.
+    pub fn set_input_filter(&mut self, filter: Option<InputFilter>) -> Result<()> {
+        ensure!(!self.started, "input filter must be set before starting");
+        self.parser.set_input_filter(filter)
+    }
+
     fn save_progress_and_result(&mut self, res: StepResult) {
         self.last_res = res;
         if self.log_json_progress {
@@ -98,6 +131,7 @@ impl Constraint {
     pub fn process_prompt(&mut self, prompt: Vec<TokenId>) -> Vec<TokenId> {
         assert!(!self.started);
         self.started = true;
+        self.history.prompt = Some(prompt.clone());
         let r = self.parser.process_prompt(prompt);
         self.save_temperature();
         r
@@ -115,6 +149,7 @@ impl Constraint {
             tokens: tokens.to_vec(),
             sampled: None,
         });
+        self.history.forced = tokens.to_vec();
         Ok(())
     }
 
@@ -122,6 +157,15 @@ impl Constraint {
         self.delayed_stop
     }
 
+    /// Is it valid to stop generation here, that is, are both the lexer
+    /// and the grammar in an accepting state?
+    /// Tokens are applied to the parser by compute_mask()
+    /// (or by commit_token() when ff_tokens are enabled),
+    /// so a token committed otherwise is only reflected after the next compute_mask().
+    pub fn is_accepting(&mut self) -> bool {
+        self.parser.is_accepting()
+    }
+
     /// This computes token sampling mask.
     /// It typically takes up to a millisecond for a 100k tokenizer.
     /// It will return an error when the order of calls is violated.
@@ -184,6 +228,14 @@ impl Constraint {
     /// commit_token() commits the sampled token (if any), and sees if this forces any more tokens
     /// on the output (if ff_tokens are enabled in InferenceCapabilities).
     pub fn commit_token(&mut self, sampled_token: Option<TokenId>) -> Result<CommitResult> {
+        let r = self.commit_token_inner(sampled_token);
+        if r.is_ok() {
+            self.history.committed.push(sampled_token);
+        }
+        r
+    }
+
+    fn commit_token_inner(&mut self, sampled_token: Option<TokenId>) -> Result<CommitResult> {
         loginfo!(self.parser.logger, "\ncommit_token({:?})", sampled_token);
 
         ensure!(
@@ -269,6 +321,66 @@ impl Constraint {
         return self.res_commit_result();
     }
 
+    /// Serialize the session, so it can be continued with `restore()`,
+    /// possibly in a different process.
+    /// The snapshot records the calls made so far (prompt, forced and
+    /// committed tokens), rather than the parser state, and is tagged with
+    /// the format version and the grammar fingerprint.
+    pub fn snapshot(&self) -> Vec<u8> {
+        let snapshot = SessionSnapshot {
+            version: SNAPSHOT_VERSION,
+            grammar_fingerprint: self.parser.grammar_fingerprint(),
+            vocab_size: self.tok_trie().vocab_size(),
+            history: self.history.clone(),
+            mask_computed: self.has_current_step_result(),
+        };
+        serde_json::to_vec(&snapshot).unwrap()
+    }
+
+    /// Continue a session from `snapshot()`, by replaying its calls on `parser`,
+    /// which has to be a fresh parser for the same grammar and tokenizer.
+    /// The result is in the same state as the original session
+    /// (eg., if compute_mask() was called last, commit_token() is to be called next).
+    /// Logs produced while replaying are discarded.
+    pub fn restore(parser: TokenParser, snapshot: &[u8]) -> Result<Self> {
+        let snapshot: SessionSnapshot =
+            serde_json::from_slice(snapshot).map_err(|e| anyhow!("invalid snapshot: {e}"))?;
+        ensure!(
+            snapshot.version == SNAPSHOT_VERSION,
+            "snapshot version {} not supported (expecting {})",
+            snapshot.version,
+            SNAPSHOT_VERSION
+        );
+        ensure!(
+            snapshot.grammar_fingerprint == parser.grammar_fingerprint(),
+            "snapshot was taken with a different grammar"
+        );
+        ensure!(
+            snapshot.vocab_size == parser.token_env.tok_trie().vocab_size(),
+            "snapshot was taken with a different tokenizer"
+        );
+
+        let mut r = Constraint::new(parser);
+        let history = snapshot.history;
+        if let Some(prompt) = history.prompt {
+            r.process_prompt(prompt);
+        }
+        if !history.forced.is_empty() {
+            r.force_tokens(&history.forced)?;
+        }
+        for token in history.committed {
+            if !r.has_current_step_result() {
+                r.compute_mask()?;
+            }
+            r.commit_token(token)?;
+        }
+        if snapshot.mask_computed && !r.has_current_step_result() {
+            r.compute_mask()?;
+        }
+        r.flush_logs();
+        Ok(r)
+    }
+
     /// This returns parser outputs to be passed back to the user.
     /// You can use that for structured output, or set log_json_progress to true
     /// and then use flush_logs() to get a string, from which the user
@@ -0,0 +1,446 @@
+use anyhow::{anyhow, bail, Result};
+use derivre::{RegexAst, RegexBuilder};
+use serde::{Deserialize, Serialize};
+use toktrie::SimpleVob;
+
+use crate::api::ParserLimits;
+
+use super::{
+    lexer::{InputFilter, Lexer, LexerResult},
+    lexerspec::{rx_min_repeat, DiagnosticKind, LexemeIdx, LexerSpec, MatchPolicy},
+};
+
+/// A replayable lexer test case.
+/// The input is fed byte-by-byte to the lexer, restarting it after each lexeme,
+/// and the resulting lexemes (and errors) are compared with `expected`.
+/// Intermediate `LexerResult::State` results are not recorded,
+/// since state numbers are an implementation detail.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LexerFixture {
+    /// Lexemes used by `lexer_spec()`.
+    /// Can be left empty when the spec is constructed by other means.
+    #[serde(default)]
+    pub lexemes: Vec<FixtureLexeme>,
+    /// Regex for the SKIP lexeme, if any.
+    #[serde(default)]
+    pub skip: Option<String>,
+    /// Names of lexemes allowed at every step; when empty, all lexemes are allowed.
+    #[serde(default)]
+    pub allowed: Vec<String>,
+    /// Lexemes to pass to `LexerSpec::allow_after_eos()`; when not empty,
+    /// the input is lexed as trailing content, allowing only
+    /// `LexerSpec::allowed_after_eos()` (instead of `allowed`).
+    #[serde(default)]
+    pub after_eos: Vec<String>,
+    /// See `Lexer::set_input_filter()`; for filters without a byte map,
+    /// which don't apply to the streaming lexer, `expected` is not checked.
+    #[serde(default)]
+    pub input_filter: Option<InputFilter>,
+    #[serde(default)]
+    pub input: String,
+    /// Strings each matched in full by one of the allowed lexemes
+    /// (see `Lexer::is_full_match()`), independently of `input`.
+    #[serde(default)]
+    pub accepts: Vec<String>,
+    /// Strings not matched in full by any of the allowed lexemes.
+    #[serde(default)]
+    pub rejects: Vec<String>,
+    #[serde(default)]
+    pub expected: Vec<FixtureResult>,
+    /// Expected results of `Lexer::lex_views()`, with offsets in the original input.
+    #[serde(default)]
+    pub expected_views: Option<Vec<FixtureView>>,
+    /// Expected results of `Lexer::lex_views()` under `MatchPolicy::LastAccepting`,
+    /// if different from `expected`; `byte_next_row` is always false there.
+    #[serde(default)]
+    pub expected_last_accepting: Option<Vec<FixtureResult>>,
+    /// Likewise, under `MatchPolicy::MaximalMunch`.
+    #[serde(default)]
+    pub expected_maximal_munch: Option<Vec<FixtureResult>>,
+    /// Expected warnings of `Lexer::build_with_report()`, in order;
+    /// with `validate_limits`, of `LexerSpec::validate_with_limits()` instead.
+    #[serde(default)]
+    pub expected_warnings: Option<Vec<FixtureDiagnostic>>,
+    /// `check_fuel` and `max_pairs` for `LexerSpec::validate_with_limits()`.
+    #[serde(default)]
+    pub validate_limits: Option<(u64, usize)>,
+    /// If set, `lexer_spec()` returns `LexerSpec::reverse()` of the spec;
+    /// `input` and the expected results are then for input fed backwards.
+    #[serde(default)]
+    pub reverse: bool,
+    /// If set, `lexer_spec()` is expected to fail with an error containing this string,
+    /// and the input is not run.
+    #[serde(default)]
+    pub spec_error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FixtureLexeme {
+    pub name: String,
+    #[serde(default)]
+    pub rx: String,
+    /// If set, the lexeme is added with `LexerSpec::add_int_range()`,
+    /// and the fields below (and `rx`) are ignored; likewise for `float_range`.
+    #[serde(default)]
+    pub int_range: Option<(i64, i64)>,
+    #[serde(default)]
+    pub float_range: Option<(f64, f64)>,
+    /// Lazy lexemes end as soon as they match; greedy ones match as much as possible.
+    #[serde(default)]
+    pub lazy: bool,
+    /// Regex for the stop (lookahead) following the lexeme, hidden from the output.
+    #[serde(default)]
+    pub stop: Option<String>,
+    /// Minimum number of characters before the lexeme accepts.
+    #[serde(default)]
+    pub min_repeat: usize,
+    /// If set, the regex is compiled first and the lexeme refers to the compiled
+    /// expression, as lexemes of grammars with `rx_nodes` do.
+    #[serde(default)]
+    pub rx_node: bool,
+    /// Names of (earlier) lexemes shadowed by this one, see `LexerSpec::add_shadows()`.
+    #[serde(default)]
+    pub shadows: Vec<String>,
+    /// If set, the lexeme is added with `LexerSpec::add_lexeme_alias()`
+    /// of the named (earlier) lexeme, and the fields above are ignored.
+    #[serde(default)]
+    pub alias_of: Option<String>,
+    /// If not empty, the lexeme is added with `LexerSpec::add_complement_lexeme()`
+    /// of these patterns, and the regex fields are ignored.
+    #[serde(default)]
+    pub complement_of: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct FixtureDiagnostic {
+    pub lexeme: String,
+    /// The `DiagnosticKind` in snake case (eg., `matches_empty`);
+    /// `overlap` is followed by the other lexeme (eg., `overlap:ID`).
+    pub kind: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct FixtureView {
+    pub name: String,
+    pub start: usize,
+    /// The bytes of the view (lossily converted to UTF-8).
+    pub text: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FixtureResult {
+    Lexeme {
+        name: String,
+        /// Does the byte that ended the lexeme belong to the next lexeme?
+        #[serde(default)]
+        byte_next_row: bool,
+        #[serde(default)]
+        hidden_len: usize,
+    },
+    Error,
+}
+
+impl LexerFixture {
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        serde_json::from_slice(data).map_err(|e| anyhow!("invalid lexer fixture: {e}"))
+    }
+
+    pub fn lexer_spec(&self) -> Result<LexerSpec> {
+        let skip = match &self.skip {
+            Some(rx) => RegexAst::Regex(rx.clone()),
+            None => RegexAst::NoMatch,
+        };
+        let mut spec = LexerSpec::new(RegexBuilder::new(), skip)?;
+        for lex in &self.lexemes {
+            if let Some(target) = &lex.alias_of {
+                let target = lexeme_by_name(&spec, target)?;
+                spec.add_lexeme_alias(lex.name.clone(), target)?;
+                continue;
+            }
+            if !lex.complement_of.is_empty() {
+                let patterns = lex
+                    .complement_of
+                    .iter()
+                    .map(|p| p.as_str())
+                    .collect::<Vec<_>>();
+                spec.add_complement_lexeme(lex.name.clone(), &patterns)?;
+                continue;
+            }
+            if let Some((min, max)) = lex.int_range {
+                spec.add_int_range(lex.name.clone(), min, max)?;
+                continue;
+            }
+            if let Some((min, max)) = lex.float_range {
+                spec.add_float_range(lex.name.clone(), min, max)?;
+                continue;
+            }
+            let mut rx = rx_min_repeat(RegexAst::Regex(lex.rx.clone()), lex.min_repeat);
+            if lex.rx_node {
+                rx = RegexAst::ExprRef(spec.regex_builder.mk(&rx)?);
+            }
+            if let Some(stop) = &lex.stop {
+                let stop = RegexAst::Regex(stop.clone());
+                spec.add_rx_and_stop(lex.name.clone(), rx, stop, lex.lazy)?;
+            } else if lex.lazy {
+                spec.add_rx_and_stop(lex.name.clone(), rx, RegexAst::EmptyString, true)?;
+            } else {
+                spec.add_greedy_lexeme(lex.name.clone(), rx, false, None)?;
+            }
+        }
+        for lex in &self.lexemes {
+            if !lex.shadows.is_empty() {
+                let shadowed = lex
+                    .shadows
+                    .iter()
+                    .map(|name| lexeme_by_name(&spec, name))
+                    .collect::<Result<Vec<_>>>()?;
+                spec.add_shadows(lexeme_by_name(&spec, &lex.name)?, &shadowed)?;
+            }
+        }
+        for name in &self.after_eos {
+            let idx = lexeme_by_name(&spec, name)?;
+            spec.allow_after_eos(idx)?;
+        }
+        if self.reverse {
+            spec = spec.reverse()?;
+        }
+        Ok(spec)
+    }
+
+    fn lexer(&self, spec: &LexerSpec) -> Result<Lexer> {
+        let mut lexer = Lexer::from(spec, &mut ParserLimits::default())?;
+        lexer.set_input_filter(self.input_filter.clone());
+        Ok(lexer)
+    }
+}
+
+fn lexeme_by_name(spec: &LexerSpec, name: &str) -> Result<LexemeIdx> {
+    spec.lexemes
+        .iter()
+        .position(|lex| lex.name() == name)
+        .map(LexemeIdx::new)
+        .ok_or_else(|| anyhow!("unknown lexeme: {:?}", name))
+}
+
+/// Run the fixture against the spec, reporting the first mismatch.
+pub fn run_fixture(spec: &LexerSpec, fixture: &LexerFixture) -> Result<()> {
+    let mut allowed = spec.alloc_lexeme_set();
+    if !fixture.after_eos.is_empty() {
+        allowed = spec.allowed_after_eos();
+    } else if fixture.allowed.is_empty() {
+        allowed = spec.all_lexemes();
+    } else {
+        for name in &fixture.allowed {
+            allowed.set(lexeme_by_name(spec, name)?.as_usize(), true);
+        }
+    }
+
+    let input = fixture.input.as_bytes();
+    let streaming = !matches!(&fixture.input_filter, Some(f) if f.byte_map().is_none());
+    if streaming {
+        let mut lexer = fixture.lexer(spec)?;
+        let results = stream_results(&mut lexer, &allowed, input);
+        compare_results(&fixture.expected, &results)?;
+
+        if spec.single_lexeme().is_some() {
+            let mut lexer = fixture.lexer(spec)?;
+            lexer.disable_single_lexeme_path();
+            let general = stream_results(&mut lexer, &allowed, input);
+            if general != results {
+                bail!(
+                    "single lexeme fast path: {:?}, general path: {:?}",
+                    results,
+                    general
+                );
+            }
+        }
+    }
+
+    if !fixture.accepts.is_empty() || !fixture.rejects.is_empty() {
+        let mut lexer = fixture.lexer(spec)?;
+        for (strings, expected) in [(&fixture.accepts, true), (&fixture.rejects, false)] {
+            for s in strings {
+                if lexer.is_full_match(&allowed, s.as_bytes()) != expected {
+                    bail!(
+                        "{:?} should {}be matched in full",
+                        s,
+                        if expected { "" } else { "not " }
+                    );
+                }
+            }
+        }
+    }
+
+    if let Some(expected) = &fixture.expected_warnings {
+        let warnings = match fixture.validate_limits {
+            Some((check_fuel, max_pairs)) => spec.validate_with_limits(check_fuel, max_pairs)?,
+            None => {
+                let (_, report) = Lexer::build_with_report(spec, &mut ParserLimits::default())?;
+                report.warnings
+            }
+        };
+        let warnings = warnings
+            .iter()
+            .map(|d| FixtureDiagnostic {
+                lexeme: spec.lexeme_spec(d.lexeme).name().to_string(),
+                kind: match &d.kind {
+                    DiagnosticKind::EmptyLanguage => "empty_language".to_string(),
+                    DiagnosticKind::MatchesEmpty => "matches_empty".to_string(),
+                    DiagnosticKind::Overlap(idx) => {
+                        format!("overlap:{}", spec.lexeme_spec(*idx).name())
+                    }
+                    DiagnosticKind::TooComplex => "too_complex".to_string(),
+                    DiagnosticKind::NotChecked => "not_checked".to_string(),
+                    DiagnosticKind::LargeLookahead(_) => "large_lookahead".to_string(),
+                },
+            })
+            .collect::<Vec<_>>();
+        if &warnings != expected {
+            bail!("warnings: expected {:?}, got {:?}", expected, warnings);
+        }
+    }
+
+    if let Some(expected) = &fixture.expected_views {
+        let mut lexer = fixture.lexer(spec)?;
+        let views = lexer
+            .lex_views(&allowed, input)
+            .map_err(|e| anyhow!("lex_views(): {e}"))?
+            .iter()
+            .map(|v| FixtureView {
+                name: spec.lexeme_spec(v.idx).name().to_string(),
+                start: v.start,
+                text: String::from_utf8_lossy(v.bytes).to_string(),
+            })
+            .collect::<Vec<_>>();
+        if &views != expected {
+            bail!("lex_views(): expected {:?}, got {:?}", expected, views);
+        }
+    }
+
+    for (policy, expected) in [
+        (MatchPolicy::LastAccepting, &fixture.expected_last_accepting),
+        (MatchPolicy::MaximalMunch, &fixture.expected_maximal_munch),
+    ] {
+        if let Some(expected) = expected {
+            let results = lex_views_results(spec, fixture, policy, &allowed, input)?;
+            compare_results(expected, &results).map_err(|e| anyhow!("{:?}: {e}", policy))?;
+        }
+    }
+
+    Ok(())
+}
+
+// feed `input` byte by byte, as the parser does
+fn stream_results(
+    lexer: &mut Lexer,
+    allowed: &SimpleVob,
+    input: &[u8],
+) -> Vec<(usize, FixtureResult)> {
+    let mut state = lexer.start_state(allowed, None);
+    let mut pending = false;
+    let mut results = vec![];
+
+    for (pos, &b) in input.iter().enumerate() {
+        match lexer.advance(state, b, false) {
+            LexerResult::State(s, _) => {
+                state = s;
+                pending = true;
+            }
+            LexerResult::Lexeme(p) => {
+                results.push((
+                    pos,
+                    FixtureResult::Lexeme {
+                        name: lexer.lexer_spec().lexeme_spec(p.idx).name().to_string(),
+                        byte_next_row: p.byte_next_row,
+                        hidden_len: p.hidden_len,
+                    },
+                ));
+                let transition_byte = if p.byte_next_row { p.byte } else { None };
+                state = lexer.start_state(allowed, transition_byte);
+                pending = transition_byte.is_some();
+                if state.is_dead() {
+                    results.push((pos, FixtureResult::Error));
+                    break;
+                }
+            }
+            LexerResult::Error => {
+                results.push((pos, FixtureResult::Error));
+                break;
+            }
+        }
+    }
+
+    if pending && !matches!(results.last(), Some((_, FixtureResult::Error))) {
+        let r = match lexer.finish_at_eos(state, true) {
+            LexerResult::Lexeme(p) => FixtureResult::Lexeme {
+                name: lexer.lexer_spec().lexeme_spec(p.idx).name().to_string(),
+                byte_next_row: false,
+                hidden_len: p.hidden_len,
+            },
+            _ => FixtureResult::Error,
+        };
+        results.push((input.len(), r));
+    }
+    results
+}
+
+fn lex_views_results(
+    spec: &LexerSpec,
+    fixture: &LexerFixture,
+    policy: MatchPolicy,
+    allowed: &SimpleVob,
+    input: &[u8],
+) -> Result<Vec<(usize, FixtureResult)>> {
+    let mut spec = spec.clone();
+    spec.match_policy = policy;
+    let mut lexer = fixture.lexer(&spec)?;
+    let mut results = vec![];
+    match lexer.lex_views(allowed, input) {
+        Ok(views) => {
+            for v in views {
+                results.push((
+                    v.start,
+                    FixtureResult::Lexeme {
+                        name: spec.lexeme_spec(v.idx).name().to_string(),
+                        byte_next_row: false,
+                        hidden_len: v.hidden_len,
+                    },
+                ));
+            }
+        }
+        Err(_) => results.push((input.len(), FixtureResult::Error)),
+    }
+    Ok(results)
+}
+
+fn compare_results(expected: &[FixtureResult], results: &[(usize, FixtureResult)]) -> Result<()> {
+    for (idx, expected) in expected.iter().enumerate() {
+        match results.get(idx) {
+            Some((pos, actual)) if actual != expected => {
+                bail!(
+                    "mismatch at result #{} (input offset {}): expected {:?}, got {:?}",
+                    idx,
+                    pos,
+                    expected,
+                    actual
+                );
+            }
+            Some(_) => {}
+            None => bail!(
+                "missing result #{}: expected {:?}, got end of results",
+                idx,
+                expected
+            ),
+        }
+    }
+    if let Some((pos, extra)) = results.get(expected.len()) {
+        bail!(
+            "unexpected result #{} (input offset {}): {:?}",
+            expected.len(),
+            pos,
+            extra
+        );
+    }
+    Ok(())
+}
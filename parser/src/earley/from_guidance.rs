@@ -1,7 +1,11 @@
 use std::fmt::Write;
 use std::{sync::Arc, vec};
 
-use super::{grammar::SymbolProps, lexerspec::LexerSpec, CGrammar, Grammar};
+use super::{
+    grammar::SymbolProps,
+    lexerspec::{has_regex_anchors, LexerSpec},
+    CGrammar, Grammar,
+};
 use crate::api::{
     GrammarWithLexer, Node, ParserLimits, RegexId, RegexNode, RegexSpec, TopLevelGrammar,
     DEFAULT_CONTEXTUAL,
@@ -13,57 +17,87 @@ use derivre::{ExprRef, JsonQuoteOptions, RegexAst, RegexBuilder};
 use instant::Instant;
 use toktrie::TokEnv;
 
-fn resolve_rx(rx_refs: &[ExprRef], node: &RegexSpec) -> Result<RegexAst> {
+/// Compiled `rx_nodes`.
+/// A `RegexNode::Regex` with `^`/`$` anchors is not compiled here, but kept as source,
+/// so that `LexerSpec` strips the anchors when the node is used directly as a lexeme.
+/// Referencing such a node from another node is an error.
+struct RxNodes {
+    refs: Vec<ExprRef>,
+    anchored: Vec<Option<String>>,
+}
+
+fn resolve_rx(rx_nodes: &RxNodes, node: &RegexSpec) -> Result<RegexAst> {
     match node {
         RegexSpec::Regex(rx) => Ok(RegexAst::Regex(rx.clone())),
         RegexSpec::RegexId(id) => {
-            if id.0 >= rx_refs.len() {
+            if id.0 >= rx_nodes.refs.len() {
                 bail!("invalid regex id: {}", id.0)
+            } else if let Some(rx) = &rx_nodes.anchored[id.0] {
+                Ok(RegexAst::Regex(rx.clone()))
             } else {
-                Ok(RegexAst::ExprRef(rx_refs[id.0]))
+                Ok(RegexAst::ExprRef(rx_nodes.refs[id.0]))
             }
         }
     }
 }
 
-fn map_rx_ref(rx_refs: &[ExprRef], id: RegexId) -> Result<RegexAst> {
-    if id.0 >= rx_refs.len() {
+fn map_rx_ref(rx_nodes: &RxNodes, id: RegexId) -> Result<RegexAst> {
+    if id.0 >= rx_nodes.refs.len() {
         bail!("invalid regex id when building nodes: {}", id.0)
+    } else if let Some(rx) = &rx_nodes.anchored[id.0] {
+        bail!(
+            "regex node {} ({:?}) has anchors; anchors are only supported at the beginning and end of a lexeme",
+            id.0,
+            rx
+        )
     } else {
-        Ok(RegexAst::ExprRef(rx_refs[id.0]))
+        Ok(RegexAst::ExprRef(rx_nodes.refs[id.0]))
     }
 }
 
-fn map_rx_refs(rx_refs: &[ExprRef], ids: Vec<RegexId>) -> Result<Vec<RegexAst>> {
-    ids.into_iter().map(|id| map_rx_ref(rx_refs, id)).collect()
+fn map_rx_refs(rx_nodes: &RxNodes, ids: Vec<RegexId>) -> Result<Vec<RegexAst>> {
+    ids.into_iter().map(|id| map_rx_ref(rx_nodes, id)).collect()
 }
 
 fn map_rx_nodes(
     limits: &ParserLimits,
     rx_nodes: Vec<RegexNode>,
     allow_invalid_utf8: bool,
-) -> Result<(RegexBuilder, Vec<ExprRef>)> {
+) -> Result<(RegexBuilder, RxNodes)> {
     let mut builder = RegexBuilder::new();
     if allow_invalid_utf8 {
         builder.utf8(false);
         builder.unicode(false);
     }
-    let mut rx_refs = vec![];
+    let mut res = RxNodes {
+        refs: vec![],
+        anchored: vec![],
+    };
     for node in rx_nodes {
-        rx_refs.push(builder.mk(&map_node(&rx_refs, node)?)?);
+        match node {
+            RegexNode::Regex(rx) if has_regex_anchors(&rx) => {
+                res.refs.push(ExprRef::INVALID);
+                res.anchored.push(Some(rx));
+            }
+            node => {
+                let rx = builder.mk(&map_node(&res, node)?)?;
+                res.refs.push(rx);
+                res.anchored.push(None);
+            }
+        }
         ensure!(
             builder.exprset().cost() <= limits.initial_lexer_fuel,
             "initial lexer configuration (rx_nodes) too big (limit for this grammar: {})",
             limits.initial_lexer_fuel
         );
     }
-    return Ok((builder, rx_refs));
+    return Ok((builder, res));
 
-    fn map_node(rx_refs: &[ExprRef], node: RegexNode) -> Result<RegexAst> {
+    fn map_node(rx_nodes: &RxNodes, node: RegexNode) -> Result<RegexAst> {
         match node {
-            RegexNode::Not(id) => Ok(RegexAst::Not(Box::new(map_rx_ref(rx_refs, id)?))),
+            RegexNode::Not(id) => Ok(RegexAst::Not(Box::new(map_rx_ref(rx_nodes, id)?))),
             RegexNode::Repeat(id, min, max) => Ok(RegexAst::Repeat(
-                Box::new(map_rx_ref(rx_refs, id)?),
+                Box::new(map_rx_ref(rx_nodes, id)?),
                 min,
                 max.unwrap_or(u32::MAX),
             )),
@@ -74,10 +108,12 @@ fn map_rx_nodes(
             RegexNode::Byte(b) => Ok(RegexAst::Byte(b)),
             RegexNode::ByteSet(bs) => Ok(RegexAst::ByteSet(bs)),
             RegexNode::ByteLiteral(bs) => Ok(RegexAst::ByteLiteral(bs)),
-            RegexNode::And(lst) => Ok(RegexAst::And(map_rx_refs(rx_refs, lst)?)),
-            RegexNode::Concat(lst) => Ok(RegexAst::Concat(map_rx_refs(rx_refs, lst)?)),
-            RegexNode::Or(lst) => Ok(RegexAst::Or(map_rx_refs(rx_refs, lst)?)),
-            RegexNode::LookAhead(id) => Ok(RegexAst::LookAhead(Box::new(map_rx_ref(rx_refs, id)?))),
+            RegexNode::And(lst) => Ok(RegexAst::And(map_rx_refs(rx_nodes, lst)?)),
+            RegexNode::Concat(lst) => Ok(RegexAst::Concat(map_rx_refs(rx_nodes, lst)?)),
+            RegexNode::Or(lst) => Ok(RegexAst::Or(map_rx_refs(rx_nodes, lst)?)),
+            RegexNode::LookAhead(id) => {
+                Ok(RegexAst::LookAhead(Box::new(map_rx_ref(rx_nodes, id)?)))
+            }
         }
     }
 }
@@ -185,6 +221,9 @@ fn grammar_from_json(
                 let lazy = data.lazy.unwrap_or(!data.stop_rx.is_missing());
                 let stop_rx = if data.stop_rx.is_missing() {
                     RegexAst::EmptyString
+                } else if let RegexSpec::RegexId(id) = &data.stop_rx {
+                    // the stop regex is not at the top level of the lexeme
+                    map_rx_ref(&rx_nodes, *id)?
                 } else {
                     resolve_rx(&rx_nodes, &data.stop_rx)?
                 };
@@ -1,11 +1,15 @@
-use std::{fmt::Debug, hash::Hash};
+use std::{
+    fmt::Debug,
+    hash::{Hash, Hasher},
+};
 
 use anyhow::{bail, ensure, Result};
 
 use crate::api::GenGrammarOptions;
+use toktrie::SimpleVob;
 
 use super::lexerspec::{LexemeIdx, LexerSpec};
-use rustc_hash::FxHashMap;
+use rustc_hash::{FxHashMap, FxHasher};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct SymIdx(u32);
@@ -806,6 +810,101 @@ impl CGrammar {
         outp
     }
 
+    /// Compute, for every symbol, the set of lexemes that can start it.
+    fn first_sets(&self) -> Vec<SimpleVob> {
+        let mut first: Vec<SimpleVob> = self
+            .symbols
+            .iter()
+            .map(|sym| {
+                let mut v = self.lexer_spec.alloc_lexeme_set();
+                if let Some(lx) = sym.lexeme {
+                    v.set(lx.as_usize(), true);
+                }
+                v
+            })
+            .collect();
+        loop {
+            let mut changed = false;
+            for sym in &self.symbols {
+                let lhs = sym.idx.as_index();
+                for rule in &sym.rules {
+                    for &elt in self.rule_rhs(*rule).0 {
+                        let add = first[elt.as_index()].clone();
+                        if add.iter().any(|l| !first[lhs].get(l as usize)) {
+                            first[lhs].or(&add);
+                            changed = true;
+                        }
+                        if !self.sym_data(elt).is_nullable {
+                            break;
+                        }
+                    }
+                }
+            }
+            if !changed {
+                break;
+            }
+        }
+        first
+    }
+
+    /// Lexemes that can come right after lexeme `idx` in some derivation
+    /// (the FOLLOW set of the terminals for `idx`).
+    /// Nullable symbols are looked through.
+    /// The end of input is not represented in the result.
+    pub fn follow_set(&self, idx: LexemeIdx) -> SimpleVob {
+        let first = self.first_sets();
+        let mut follow = vec![self.lexer_spec.alloc_lexeme_set(); self.symbols.len()];
+        loop {
+            let mut changed = false;
+            for sym in &self.symbols {
+                for rule in &sym.rules {
+                    // lexemes that can follow the current element of the rhs
+                    let mut trailer = follow[sym.idx.as_index()].clone();
+                    for &elt in self.rule_rhs(*rule).0.iter().rev() {
+                        let e = elt.as_index();
+                        if trailer.iter().any(|l| !follow[e].get(l as usize)) {
+                            follow[e].or(&trailer);
+                            changed = true;
+                        }
+                        if self.sym_data(elt).is_nullable {
+                            trailer.or(&first[e]);
+                        } else {
+                            trailer = first[e].clone();
+                        }
+                    }
+                }
+            }
+            if !changed {
+                break;
+            }
+        }
+
+        let mut res = self.lexer_spec.alloc_lexeme_set();
+        for sym in &self.symbols {
+            if sym.is_terminal && sym.lexeme == Some(idx) {
+                res.or(&follow[sym.idx.as_index()]);
+            }
+        }
+        res
+    }
+
+    /// Hash of the symbols, rules and lexemes, for checking that two grammars
+    /// were compiled from the same source (eg., when restoring a snapshot).
+    /// It's stable across processes, but not across library versions.
+    pub fn fingerprint(&self) -> u64 {
+        let mut hasher = FxHasher::default();
+        for sym in &self.symbols {
+            sym.name.hash(&mut hasher);
+            sym.is_nullable.hash(&mut hasher);
+            sym.lexeme.hash(&mut hasher);
+            for rule in &sym.rules {
+                self.rule_to_string(*rule).hash(&mut hasher);
+            }
+        }
+        format!("{:?}", self.lexer_spec).hash(&mut hasher);
+        hasher.finish()
+    }
+
     pub fn sym_name(&self, sym: CSymIdx) -> &str {
         &self.symbols[sym.0 as usize].name
     }
@@ -1,11 +1,19 @@
-use anyhow::Result;
-use std::fmt::Debug;
+use anyhow::{bail, ensure, Result};
+use serde::{Deserialize, Serialize};
+use std::{borrow::Cow, collections::VecDeque, fmt::Debug, ops::Range, sync::Arc};
+#[cfg(feature = "async")]
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
 use toktrie::SimpleVob;
 
 use crate::api::ParserLimits;
 
 use super::{
-    lexerspec::{LexemeIdx, LexerSpec},
+    lexerspec::{
+        Diagnostic, DiagnosticKind, Lexeme, LexemeIdx, LexerError, LexerSpec, MatchPolicy, SpecDiff,
+    },
     regexvec::{NextByte, RegexVec, StateDesc},
 };
 
@@ -19,10 +27,239 @@ macro_rules! debug {
     }
 }
 
+/// The automaton driven by the lexer.
+/// `RegexVec` is the standard implementation; others (eg., precomputed tables,
+/// or hand-built automata in tests) can be plugged in with `Lexer::with_backend()`.
+pub trait DfaBackend {
+    fn initial_state(&mut self, selected: &SimpleVob) -> StateID;
+    fn transition(&mut self, state: StateID, b: u8) -> StateID;
+    fn state_desc(&self, state: StateID) -> &StateDesc;
+    fn possible_lookahead_len(&mut self, state: StateID) -> usize;
+    /// Length of the lookahead of the lowest accepting lexeme, if any.
+    fn lookahead_len(&mut self, state: StateID) -> Option<usize>;
+    fn lowest_match(&mut self, state: StateID) -> Option<(usize, usize)>;
+    fn next_byte(&mut self, state: StateID) -> NextByte;
+    fn limit_state_to(&mut self, state: StateID, allowed_lexemes: &SimpleVob) -> StateID;
+    fn has_error(&self) -> bool;
+    fn num_states(&self) -> usize;
+    /// Result of `transition()`, if it's known without changing the backend;
+    /// backends that don't cache transitions always return `None`.
+    fn cached_transition(&self, _state: StateID, _b: u8) -> Option<StateID> {
+        None
+    }
+    /// Work done so far, in the units of `RegexVec::set_fuel()`;
+    /// backends that don't track it always return 0.
+    fn total_fuel_spent(&self) -> u64 {
+        0
+    }
+}
+
+impl DfaBackend for RegexVec {
+    fn initial_state(&mut self, selected: &SimpleVob) -> StateID {
+        RegexVec::initial_state(self, selected)
+    }
+
+    #[inline(always)]
+    fn transition(&mut self, state: StateID, b: u8) -> StateID {
+        RegexVec::transition(self, state, b)
+    }
+
+    #[inline(always)]
+    fn state_desc(&self, state: StateID) -> &StateDesc {
+        RegexVec::state_desc(self, state)
+    }
+
+    fn possible_lookahead_len(&mut self, state: StateID) -> usize {
+        RegexVec::possible_lookahead_len(self, state)
+    }
+
+    fn lookahead_len(&mut self, state: StateID) -> Option<usize> {
+        RegexVec::lookahead_len_for_state(self, state)
+    }
+
+    #[inline(always)]
+    fn lowest_match(&mut self, state: StateID) -> Option<(usize, usize)> {
+        RegexVec::lowest_match(self, state)
+    }
+
+    fn next_byte(&mut self, state: StateID) -> NextByte {
+        RegexVec::next_byte(self, state)
+    }
+
+    fn limit_state_to(&mut self, state: StateID, allowed_lexemes: &SimpleVob) -> StateID {
+        RegexVec::limit_state_to(self, state, allowed_lexemes)
+    }
+
+    fn has_error(&self) -> bool {
+        RegexVec::has_error(self)
+    }
+
+    fn num_states(&self) -> usize {
+        RegexVec::num_states(self)
+    }
+
+    #[inline(always)]
+    fn cached_transition(&self, state: StateID, b: u8) -> Option<StateID> {
+        RegexVec::cached_transition(self, state, b)
+    }
+
+    fn total_fuel_spent(&self) -> u64 {
+        RegexVec::total_fuel_spent(self)
+    }
+}
+
+/// DFA backend shared between forks of a lexer, see `Lexer::fork()`.
+/// The states realized before forking are kept behind an `Arc`, and read by
+/// all forks; the first time a fork needs to change the backend (eg., to realize
+/// a new state), it gets a private copy (copy-on-write), so forks never wait for
+/// each other, and can be moved to other threads if `D` is `Send + Sync`.
+#[derive(Clone)]
+pub struct SharedDfa<D> {
+    shared: Arc<D>,
+    // private copy, once this fork changed the backend
+    own: Option<D>,
+    // initial states known to the backend, by the set of allowed lexemes
+    initial_states: Vec<(SimpleVob, StateID)>,
+}
+
+impl<D: DfaBackend + Clone> SharedDfa<D> {
+    pub fn new(dfa: D) -> Self {
+        SharedDfa {
+            shared: Arc::new(dfa),
+            own: None,
+            initial_states: vec![],
+        }
+    }
+
+    /// Did this fork copy the backend, since it was shared with other forks?
+    pub fn is_copied(&self) -> bool {
+        self.own.is_some()
+    }
+
+    /// Number of forks using the shared copy of the backend.
+    pub fn num_sharing(&self) -> usize {
+        Arc::strong_count(&self.shared)
+    }
+
+    // make the private copy (if any) the shared one
+    fn share(&mut self) {
+        if let Some(own) = self.own.take() {
+            self.shared = Arc::new(own);
+        }
+    }
+
+    #[inline(always)]
+    fn get(&self) -> &D {
+        self.own.as_ref().unwrap_or(&self.shared)
+    }
+
+    fn get_mut(&mut self) -> &mut D {
+        if self.own.is_none() && Arc::strong_count(&self.shared) == 1 {
+            // no other fork reads the shared copy
+            return Arc::get_mut(&mut self.shared).unwrap();
+        }
+        self.own.get_or_insert_with(|| (*self.shared).clone())
+    }
+}
+
+impl<D: DfaBackend + Clone> DfaBackend for SharedDfa<D> {
+    fn initial_state(&mut self, selected: &SimpleVob) -> StateID {
+        let known = self
+            .initial_states
+            .iter()
+            .find(|(set, _)| set.len() == selected.len() && set.iter().eq(selected.iter()));
+        if let Some(&(_, state)) = known {
+            return state;
+        }
+        let state = self.get_mut().initial_state(selected);
+        self.initial_states.push((selected.clone(), state));
+        state
+    }
+
+    #[inline(always)]
+    fn transition(&mut self, state: StateID, b: u8) -> StateID {
+        if let Some(own) = &mut self.own {
+            return own.transition(state, b);
+        }
+        match self.shared.cached_transition(state, b) {
+            Some(next) => next,
+            None => self.get_mut().transition(state, b),
+        }
+    }
+
+    #[inline(always)]
+    fn state_desc(&self, state: StateID) -> &StateDesc {
+        self.get().state_desc(state)
+    }
+
+    fn possible_lookahead_len(&mut self, state: StateID) -> usize {
+        self.get_mut().possible_lookahead_len(state)
+    }
+
+    fn lookahead_len(&mut self, state: StateID) -> Option<usize> {
+        self.get_mut().lookahead_len(state)
+    }
+
+    #[inline(always)]
+    fn lowest_match(&mut self, state: StateID) -> Option<(usize, usize)> {
+        match &mut self.own {
+            Some(own) => own.lowest_match(state),
+            // computed when the state is realized
+            None => self.shared.state_desc(state).lowest_match,
+        }
+    }
+
+    fn next_byte(&mut self, state: StateID) -> NextByte {
+        self.get_mut().next_byte(state)
+    }
+
+    fn limit_state_to(&mut self, state: StateID, allowed_lexemes: &SimpleVob) -> StateID {
+        self.get_mut().limit_state_to(state, allowed_lexemes)
+    }
+
+    fn has_error(&self) -> bool {
+        self.get().has_error()
+    }
+
+    fn num_states(&self) -> usize {
+        self.get().num_states()
+    }
+
+    fn cached_transition(&self, state: StateID, b: u8) -> Option<StateID> {
+        self.get().cached_transition(state, b)
+    }
+
+    fn total_fuel_spent(&self) -> u64 {
+        self.get().total_fuel_spent()
+    }
+}
+
 #[derive(Clone)]
-pub struct Lexer {
-    pub(crate) dfa: RegexVec,
+pub struct Lexer<D = RegexVec> {
+    pub(crate) dfa: D,
     spec: LexerSpec,
+    eos_lexemes: SimpleVob,
+    // set when only one lexeme can ever match (eg., for pure regex constraints)
+    single_lexeme: Option<(LexemeIdx, bool)>,
+    // start state with the single lexeme allowed, once computed
+    single_start: Option<StateID>,
+    // allows_eos() for states realized before precompute_eos(), indexed by state
+    eos_cache: Vec<bool>,
+    // DFA fuel spent before the current session; see reset_session()
+    session_start_fuel: u64,
+    // see set_lexeme_fuel_budget()
+    lexeme_fuel_budget: Option<u64>,
+    // fuel charged to each lexeme, allocated on first use
+    lexeme_fuel: Vec<u64>,
+    over_budget: Option<LexemeIdx>,
+    // start state with all lexemes allowed; see set_boundary_check()
+    boundary_state: Option<StateID>,
+    input_filter: Option<InputFilter>,
+    // byte map of `input_filter`, applied to every transition
+    byte_map: Option<[u8; 256]>,
+    // false when a byte map or a lexeme fuel budget applies to transitions,
+    // so that otherwise advance() checks a single flag
+    plain_transitions: bool,
 }
 
 pub type StateID = derivre::StateID;
@@ -36,6 +273,186 @@ pub struct PreLexeme {
     pub byte_next_row: bool,
     /// Length in bytes of the hidden part of the lexeme.
     pub hidden_len: usize,
+    /// Set when `Lexer::set_boundary_check()` detects a likely problem.
+    pub diagnostic: Option<LexemeDiagnostic>,
+}
+
+/// Problem noticed when a lexeme ended; see `Lexer::set_boundary_check()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LexemeDiagnostic {
+    /// The byte that ended the (greedy) lexeme cannot start any lexeme,
+    /// so lexing will fail right after it.
+    NoLexemeStartsWith(u8),
+}
+
+impl std::fmt::Display for LexemeDiagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LexemeDiagnostic::NoLexemeStartsWith(b) => write!(
+                f,
+                "byte {:?} ends the lexeme, but no lexeme can start with it; \
+                 the grammar is likely missing a lexeme (or SKIP) for it",
+                *b as char
+            ),
+        }
+    }
+}
+
+/// How much of the DFA `Lexer::warmup()` computes ahead of time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WarmupMode {
+    /// Only the states visited by the sample inputs, so that the remaining
+    /// states are computed on demand, a few per `Lexer::advance()` call.
+    Lazy,
+    /// Also all states reachable (with all lexemes allowed) by any bytes,
+    /// until `max_states` states are realized; keep it below
+    /// `ParserLimits::max_lexer_states`, which puts the lexer in error.
+    Eager { max_states: usize },
+}
+
+/// Non-fatal findings of `Lexer::build_with_report()`.
+#[derive(Debug, Clone)]
+pub struct BuildReport {
+    /// Problems found by `LexerSpec::validate()`, and lexemes with
+    /// a large lookahead (`DiagnosticKind::LargeLookahead`).
+    pub warnings: Vec<Diagnostic>,
+    /// Size of the regex expressions (`LexerSpec::cost()`);
+    /// the automaton tends to grow with it.
+    pub estimated_dfa_size: u64,
+    /// Number of DFA states realized during construction.
+    pub num_states: usize,
+}
+
+/// What the lexer is currently matching, eg. for displaying progress.
+#[derive(Debug, Clone)]
+pub struct LexemeHint {
+    /// Lexemes that can still match.
+    pub possible: SimpleVob,
+    /// Lexemes that would match if the lexeme ended here.
+    pub accepting: SimpleVob,
+    pub is_accepting: bool,
+}
+
+/// A lexeme borrowing its bytes from the input, as returned by `Lexer::lex_views()`.
+#[derive(Debug, Clone, Copy)]
+pub struct LexemeView<'a> {
+    pub idx: LexemeIdx,
+    /// Offset of the lexeme in the input.
+    pub start: usize,
+    /// All bytes of the lexeme, including the hidden ones.
+    pub bytes: &'a [u8],
+    /// Length in bytes of the hidden part of the lexeme (at the end of `bytes`).
+    pub hidden_len: usize,
+}
+
+impl<'a> LexemeView<'a> {
+    pub fn visible_bytes(&self) -> &'a [u8] {
+        &self.bytes[..self.bytes.len() - self.hidden_len]
+    }
+
+    /// The visible bytes, after the lexeme transform of `spec`, if any
+    /// (see `LexerSpec::set_lexeme_transform()`).
+    pub fn value(&self, spec: &LexerSpec) -> Cow<'a, [u8]> {
+        match spec.lexeme_spec(self.idx).transform(self.visible_bytes()) {
+            Some(value) => Cow::Owned(value),
+            None => Cow::Borrowed(self.visible_bytes()),
+        }
+    }
+}
+
+/// Normalization of the input applied by the lexer before matching,
+/// see `Lexer::set_input_filter()`.
+/// Offsets in the results (and errors) always refer to the original input.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InputFilter {
+    Identity,
+    /// Map `A-Z` to `a-z`; other bytes (including non-ASCII) are left alone.
+    AsciiLowercase,
+    /// Unicode lowercase (see `char::to_lowercase()`), which can change
+    /// the length of the input (eg., `İ` becomes `i̇`); invalid UTF-8 is left alone.
+    Lowercase,
+}
+
+impl InputFilter {
+    /// The byte each input byte is mapped to, for filters that work byte by byte;
+    /// only these can be applied to the streaming interface (`Lexer::advance()` etc.).
+    pub fn byte_map(&self) -> Option<[u8; 256]> {
+        let mut map = [0u8; 256];
+        for (b, m) in map.iter_mut().enumerate() {
+            *m = b as u8;
+        }
+        match self {
+            InputFilter::Identity => {}
+            InputFilter::AsciiLowercase => map.make_ascii_lowercase(),
+            InputFilter::Lowercase => return None,
+        }
+        Some(map)
+    }
+
+    /// Returns the filtered bytes, and for each position in them
+    /// (including the end) the corresponding offset in `input`.
+    /// The offsets are non-decreasing; all the bytes a character is mapped to
+    /// get the offset of that character.
+    pub fn apply(&self, input: &[u8]) -> (Vec<u8>, Vec<usize>) {
+        if let Some(map) = self.byte_map() {
+            let bytes = input.iter().map(|&b| map[b as usize]).collect();
+            return (bytes, (0..=input.len()).collect());
+        }
+        let mut bytes = Vec::with_capacity(input.len());
+        let mut offsets = Vec::with_capacity(input.len() + 1);
+        let mut pos = 0;
+        while pos < input.len() {
+            let rest = &input[pos..];
+            let valid = match std::str::from_utf8(rest) {
+                Ok(s) => s,
+                Err(e) => std::str::from_utf8(&rest[..e.valid_up_to()]).unwrap(),
+            };
+            for (off, c) in valid.char_indices() {
+                let len0 = bytes.len();
+                for lc in c.to_lowercase() {
+                    bytes.extend_from_slice(lc.encode_utf8(&mut [0; 4]).as_bytes());
+                }
+                offsets.resize(offsets.len() + bytes.len() - len0, pos + off);
+            }
+            pos += valid.len();
+            if pos < input.len() {
+                bytes.push(input[pos]);
+                offsets.push(pos);
+                pos += 1;
+            }
+        }
+        offsets.push(input.len());
+        (bytes, offsets)
+    }
+}
+
+/// A lexeme with the skipped (SKIP) bytes before it, as returned by
+/// `Lexer::lex_views_with_trivia()`.
+#[derive(Debug, Clone)]
+pub struct LexemeWithTrivia<'a> {
+    pub lexeme: LexemeView<'a>,
+    /// Input range of the SKIP lexemes right before `lexeme`
+    /// (empty, at `lexeme.start`, if there are none).
+    pub leading_trivia: Range<usize>,
+}
+
+/// Result of `Lexer::step_options()`.
+#[derive(Debug, Clone)]
+pub struct StepOptions {
+    pub allowed_bytes: SimpleVob,
+    pub eos_allowed: bool,
+}
+
+/// Result of `Lexer::parse_prefix()`.
+#[derive(Debug, Clone, Copy)]
+pub struct PrefixResult {
+    /// Number of bytes lexed before the failure (the whole input if there was none).
+    pub valid_len: usize,
+    /// Lexer state after the last valid byte.
+    pub state: StateID,
+    /// The byte that could not be lexed, if any.
+    pub failing_byte: Option<u8>,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -46,22 +463,247 @@ pub enum LexerResult {
 }
 
 impl Lexer {
-    pub fn from(spec: &LexerSpec, limits: &mut ParserLimits) -> Result<Self> {
-        let dfa = spec.to_regex_vec(limits)?;
+    pub fn from(spec: &LexerSpec, limits: &mut ParserLimits) -> Result<Self, LexerError> {
+        let mut dfa = spec.to_regex_vec(limits)?;
+        dfa.set_max_states(limits.max_lexer_states);
 
         debug!("lexer: {:?}\n  ==> dfa: {:?}", spec, dfa);
+        debug_assert!(
+            spec.verify_invariants().is_ok(),
+            "{}",
+            spec.verify_invariants().unwrap_err()
+        );
+
+        let lexer = Self::with_backend(spec, dfa);
+        lexer.check_error()?;
+        Ok(lexer)
+    }
+
+    /// Report the error, if lexer fuel or state limit (`ParserLimits::max_lexer_states`)
+    /// was exceeded, either at construction or while realizing states.
+    /// Once in error, the lexer only returns dead states.
+    pub fn check_error(&self) -> Result<(), LexerError> {
+        if let Some(idx) = self.over_budget {
+            return Err(LexerError::LexemeBudgetExceeded {
+                lexeme: self.spec.lexeme_spec(idx).name().to_string(),
+                budget: self.lexeme_fuel_budget.unwrap_or(0),
+            });
+        }
+        match self.dfa.get_error() {
+            None => Ok(()),
+            Some(msg) if self.dfa.has_too_many_states() => Err(LexerError::DfaTooLarge(msg)),
+            Some(msg) => Err(LexerError::BudgetExceeded(msg)),
+        }
+    }
+
+    /// Like `Lexer::from()`, but also check the spec for likely problems
+    /// that don't prevent building the lexer, see `BuildReport`.
+    /// Lookahead is considered large above 64 bytes.
+    pub fn build_with_report(
+        spec: &LexerSpec,
+        limits: &mut ParserLimits,
+    ) -> Result<(Self, BuildReport), LexerError> {
+        const LARGE_LOOKAHEAD: usize = 64;
+
+        let mut warnings = spec
+            .validate()
+            .map_err(|e| LexerError::InvalidSpec(e.to_string()))?;
+        for lex in &spec.lexemes {
+            let len = lex.max_lookahead_len();
+            if len.is_none_or(|len| len > LARGE_LOOKAHEAD) {
+                warnings.push(Diagnostic {
+                    lexeme: lex.idx,
+                    kind: DiagnosticKind::LargeLookahead(len),
+                    message: match len {
+                        Some(len) => {
+                            format!("lexeme {:?}: lookahead of up to {} bytes", lex.name(), len)
+                        }
+                        None => format!("lexeme {:?}: unbounded lookahead", lex.name()),
+                    },
+                });
+            }
+        }
+        let lexer = Self::from(spec, limits)?;
+        let report = BuildReport {
+            warnings,
+            estimated_dfa_size: spec.cost(),
+            num_states: lexer.dfa.num_states(),
+        };
+        Ok((lexer, report))
+    }
+
+    /// Build a lexer for the spec of `prev` modified by `diff`.
+    /// Compiled regexes of unchanged lexemes are reused, but the automaton
+    /// is built from scratch, since its states combine all lexemes.
+    /// Returns the lexer and the number of reused lexemes.
+    pub fn from_previous(
+        prev: &Lexer,
+        diff: &SpecDiff,
+        limits: &mut ParserLimits,
+    ) -> Result<(Self, usize), LexerError> {
+        let mut spec = prev.spec.clone();
+        let num_reused = diff.apply(&mut spec)?;
+        Ok((Self::from(&spec, limits)?, num_reused))
+    }
+
+    /// Build a lexer for the intersection of the languages of the two lexers,
+    /// see `LexerSpec::intersect()`; fails if the intersection is empty.
+    /// Returns the lexer and, for each of its lexemes, the pair of lexemes
+    /// of `self` and `other` it comes from.
+    pub fn intersect(
+        &self,
+        other: &Lexer,
+        limits: &mut ParserLimits,
+    ) -> Result<(Self, Vec<(LexemeIdx, LexemeIdx)>), LexerError> {
+        let (spec, mapping) = self.spec.intersect(&other.spec)?;
+        Ok((Self::from(&spec, limits)?, mapping))
+    }
+
+    /// Set the lexemes that can end at EOS (instead of the defaults from the spec),
+    /// and compute `allows_eos()` for all states realized so far, so that
+    /// later calls for these states are lookups.
+    /// States realized later are computed on demand.
+    pub fn precompute_eos(&mut self, allowed_eos: &SimpleVob) {
+        self.eos_lexemes = allowed_eos.clone();
+        self.single_lexeme = self
+            .single_lexeme
+            .map(|(idx, _)| (idx, allowed_eos.get(idx.as_usize())));
+        self.eos_cache.clear();
+        let states: Vec<StateID> = self.dfa.state_ids().collect();
+        let cache_len = states.iter().map(|s| s.as_usize() + 1).max().unwrap_or(0);
+        let mut cache = vec![false; cache_len];
+        for state in states {
+            cache[state.as_usize()] = self.allows_eos(state);
+        }
+        self.eos_cache = cache;
+    }
+
+    /// Prepare the lexer for a new session (eg., the next request in a server),
+    /// keeping the DFA states realized so far.
+    /// This restores EOS lexemes from the spec (undoing `precompute_eos()`),
+    /// removes any fuel limit and resets `session_fuel_spent()`
+    /// and the fuel charged to lexemes (see `set_lexeme_fuel_budget()`).
+    /// If the DFA itself is in error state (see `check_error()`), it stays in it.
+    pub fn reset_session(&mut self) {
+        self.eos_lexemes = self.spec.eos_ending_lexemes();
+        self.single_lexeme = self
+            .spec
+            .single_lexeme()
+            .map(|idx| (idx, self.eos_lexemes.get(idx.as_usize())));
+        self.eos_cache.clear();
+        self.dfa.set_fuel(u64::MAX);
+        self.session_start_fuel = self.dfa.total_fuel_spent();
+        self.lexeme_fuel.clear();
+        self.over_budget = None;
+    }
+
+    /// Lexer fuel spent since construction or the last `reset_session()`.
+    pub fn session_fuel_spent(&self) -> u64 {
+        self.dfa.total_fuel_spent() - self.session_start_fuel
+    }
+
+    /// See `RegexVec::set_on_state_realized()`.
+    pub fn set_on_state_realized(&mut self, f: Option<Arc<dyn Fn(StateID) + Send + Sync>>) {
+        self.dfa.set_on_state_realized(f);
+    }
+
+    pub fn subsume_possible(&mut self, state: StateID) -> bool {
+        self.dfa.subsume_possible(state)
+    }
+
+    pub fn check_subsume(&mut self, state: StateID, extra_idx: usize, budget: u64) -> Result<bool> {
+        self.dfa
+            .check_subsume(state, self.spec.extra_lexeme(extra_idx).as_usize(), budget)
+    }
+}
+
+impl<D: DfaBackend + Clone> Lexer<D> {
+    /// Put the DFA behind an `Arc`, so that the lexer can be forked, see `fork()`.
+    pub fn into_shared(self) -> Lexer<SharedDfa<D>> {
+        Lexer {
+            dfa: SharedDfa::new(self.dfa),
+            spec: self.spec,
+            eos_lexemes: self.eos_lexemes,
+            single_lexeme: self.single_lexeme,
+            single_start: self.single_start,
+            eos_cache: self.eos_cache,
+            session_start_fuel: self.session_start_fuel,
+            lexeme_fuel_budget: self.lexeme_fuel_budget,
+            lexeme_fuel: self.lexeme_fuel,
+            over_budget: self.over_budget,
+            boundary_state: self.boundary_state,
+            input_filter: self.input_filter,
+            byte_map: self.byte_map,
+            plain_transitions: self.plain_transitions,
+        }
+    }
+}
+
+impl<D: DfaBackend + Clone> Lexer<SharedDfa<D>> {
+    /// Create a lexer for another session over the same spec, sharing the DFA
+    /// states realized so far (by this lexer, and the lexers it was forked from),
+    /// instead of re-computing them.
+    /// States realized later are private to the lexer that realized them:
+    /// on the first one, it copies the DFA (see `SharedDfa`).
+    /// Forks don't share any mutable data, so they can be used from different
+    /// threads without locking; to continue sharing the states realized by
+    /// a fork, fork it again.
+    pub fn fork(&mut self) -> Self {
+        self.dfa.share();
+        self.clone()
+    }
+}
 
-        let lex = Lexer {
+impl<D: DfaBackend> Lexer<D> {
+    pub fn with_backend(spec: &LexerSpec, dfa: D) -> Self {
+        let eos_lexemes = spec.eos_ending_lexemes();
+        let single_lexeme = spec
+            .single_lexeme()
+            .map(|idx| (idx, eos_lexemes.get(idx.as_usize())));
+
+        Lexer {
             dfa,
             spec: spec.clone(), // TODO check perf of Rc<> ?
-        };
+            eos_lexemes,
+            single_lexeme,
+            single_start: None,
+            eos_cache: vec![],
+            session_start_fuel: 0,
+            lexeme_fuel_budget: None,
+            lexeme_fuel: vec![],
+            over_budget: None,
+            boundary_state: None,
+            input_filter: None,
+            byte_map: None,
+            plain_transitions: true,
+        }
+    }
+
+    pub fn lexer_spec(&self) -> &LexerSpec {
+        &self.spec
+    }
 
-        Ok(lex)
+    /// The DFA backend, eg. for its statistics.
+    pub fn dfa(&self) -> &D {
+        &self.dfa
     }
 
     pub fn start_state(&mut self, allowed_lexemes: &SimpleVob, first_byte: Option<u8>) -> StateID {
-        let s = self.dfa.initial_state(allowed_lexemes);
-        first_byte.map(|b| self.dfa.transition(s, b)).unwrap_or(s)
+        let s = match (self.single_lexeme, self.single_start) {
+            // skip looking up the allowed set in the DFA
+            (Some((idx, _)), Some(s))
+                if allowed_lexemes.get(idx.as_usize()) && !self.dfa.has_error() =>
+            {
+                s
+            }
+            (Some((idx, _)), None) if allowed_lexemes.get(idx.as_usize()) => {
+                let s = self.dfa.initial_state(allowed_lexemes);
+                self.single_start = Some(s);
+                s
+            }
+            _ => self.dfa.initial_state(allowed_lexemes),
+        };
+        first_byte.map(|b| self.transition(s, b)).unwrap_or(s)
     }
 
     pub fn a_dead_state(&self) -> StateID {
@@ -77,7 +719,13 @@ impl Lexer {
     }
 
     pub fn allows_eos(&mut self, state: StateID) -> bool {
-        let mut l = self.spec.eos_ending_lexemes();
+        if let Some(&r) = self.eos_cache.get(state.as_usize()) {
+            return r;
+        }
+        if let Some((idx, ends_at_eos)) = self.single_lexeme {
+            return ends_at_eos && self.state_info(state).accepting.get(idx.as_usize());
+        }
+        let mut l = self.eos_lexemes.clone();
         l.and(&self.state_info(state).accepting);
         !l.is_zero()
     }
@@ -86,10 +734,20 @@ impl Lexer {
         self.dfa.limit_state_to(state, allowed_lexemes)
     }
 
+    /// Lexemes that can still match in `state`; iteration is in ascending index order.
     pub fn possible_lexemes(&self, state: StateID) -> &SimpleVob {
         &self.state_info(state).possible
     }
 
+    pub fn current_lexeme_hint(&self, state: StateID) -> LexemeHint {
+        let info = self.state_info(state);
+        LexemeHint {
+            possible: info.possible.clone(),
+            accepting: info.accepting.clone(),
+            is_accepting: info.is_accepting(),
+        }
+    }
+
     pub fn force_lexeme_end(&self, prev: StateID) -> LexerResult {
         let info = self.state_info(prev);
         match info.possible.first_bit_set() {
@@ -98,6 +756,7 @@ impl Lexer {
                 byte: None,
                 byte_next_row: false,
                 hidden_len: 0,
+                diagnostic: None,
             }),
             None => LexerResult::Error,
         }
@@ -110,12 +769,57 @@ impl Lexer {
                 byte: None,
                 byte_next_row: false,
                 hidden_len: 0,
+                diagnostic: None,
             })
         } else {
             LexerResult::Error
         }
     }
 
+    /// Resolve the pending lexeme when no more bytes will arrive.
+    /// A lexeme whose lookahead (or stop) has been seen in full is returned with
+    /// the lookahead as `hidden_len`.
+    /// Otherwise, the lowest accepting lexeme is returned, but only if it can end
+    /// at EOS, or `allowed_eos` is set (which also accepts lazy lexemes
+    /// still waiting for their stop).
+    /// If the required lookahead never arrived, the lexeme is not accepting
+    /// and the result is an error.
+    pub fn finish_at_eos(&mut self, state: StateID, allowed_eos: bool) -> LexerResult {
+        if let Some((idx, hidden_len)) = self.dfa.lowest_match(state) {
+            return LexerResult::Lexeme(PreLexeme {
+                idx: LexemeIdx::new(idx),
+                byte: None,
+                byte_next_row: false,
+                hidden_len,
+                diagnostic: None,
+            });
+        }
+        let idx = if allowed_eos {
+            self.state_info(state).lowest_accepting
+        } else {
+            let mut l = self.eos_lexemes.clone();
+            l.and(&self.state_info(state).accepting);
+            l.first_bit_set()
+        };
+        match idx {
+            Some(idx) => {
+                let hidden_len = if Some(idx) == self.state_info(state).lowest_accepting {
+                    self.dfa.lookahead_len(state).unwrap_or(0)
+                } else {
+                    0
+                };
+                LexerResult::Lexeme(PreLexeme {
+                    idx: LexemeIdx::new(idx),
+                    byte: None,
+                    byte_next_row: false,
+                    hidden_len,
+                    diagnostic: None,
+                })
+            }
+            None => LexerResult::Error,
+        }
+    }
+
     pub fn check_for_single_byte_lexeme(&mut self, state: StateID, b: u8) -> Option<PreLexeme> {
         if self.dfa.next_byte(state) == NextByte::ForcedEOI {
             let info = self.state_info(state);
@@ -125,24 +829,526 @@ impl Lexer {
                 byte: Some(b),
                 byte_next_row: false,
                 hidden_len: 0,
+                diagnostic: None,
             })
         } else {
             None
         }
     }
 
-    pub fn subsume_possible(&mut self, state: StateID) -> bool {
-        self.dfa.subsume_possible(state)
+    /// Check if the whole input is matched by one of the allowed lexemes.
+    /// Unlike when lexing, trailing input is not allowed.
+    pub fn is_full_match(&mut self, allowed_lexemes: &SimpleVob, input: &[u8]) -> bool {
+        let mut state = self.dfa.initial_state(allowed_lexemes);
+        for &b in input {
+            state = self.transition(state, b);
+            if state.is_dead() {
+                return false;
+            }
+        }
+        self.state_info(state).is_accepting()
     }
 
-    pub fn check_subsume(&mut self, state: StateID, extra_idx: usize, budget: u64) -> Result<bool> {
-        self.dfa
-            .check_subsume(state, self.spec.extra_lexeme(extra_idx).as_usize(), budget)
+    /// Bytes that keep the lexer alive when consumed in `state`.
+    pub fn allowed_bytes(&mut self, state: StateID) -> SimpleVob {
+        let mut r = SimpleVob::alloc(256);
+        for b in 0..=255u8 {
+            if self.is_byte_allowed(state, b) {
+                r.set(b as usize, true);
+            }
+        }
+        r
+    }
+
+    /// Bytes that can start one of the `allowed` lexemes.
+    pub fn initial_allowed_bytes(&mut self, allowed: &SimpleVob) -> SimpleVob {
+        let state = self.start_state(allowed, None);
+        self.allowed_bytes(state)
+    }
+
+    /// Same as `allowed_bytes(state).get(byte)`, without allocating the set.
+    #[inline(always)]
+    pub fn is_byte_allowed(&mut self, state: StateID, byte: u8) -> bool {
+        !self.transition(state, byte).is_dead()
+    }
+
+    /// Compute the allowed bytes and whether EOS is allowed in one go,
+    /// after limiting `state` to `allowed` lexemes.
+    /// EOS is allowed if any of `allowed_eos` lexemes is accepting in the state.
+    pub fn step_options(
+        &mut self,
+        state: StateID,
+        allowed: &SimpleVob,
+        allowed_eos: &SimpleVob,
+    ) -> StepOptions {
+        let state = self.limit_state_to(state, allowed);
+        let mut eos = allowed_eos.clone();
+        eos.and(&self.state_info(state).accepting);
+        StepOptions {
+            allowed_bytes: self.allowed_bytes(state),
+            eos_allowed: !eos.is_zero(),
+        }
+    }
+
+    /// Compute which tokens of `vocab` can be consumed in `state`.
+    /// A token is allowed iff its bytes keep the lexer alive or complete a lexeme
+    /// (bytes after the lexeme end are not checked).
+    /// Tokens are grouped by their first byte, and groups starting with a byte
+    /// not in `allowed_bytes()` are discarded at once.
+    pub fn compute_mask_for_vocab(&mut self, state: StateID, vocab: &[&[u8]]) -> SimpleVob {
+        let first_bytes = self.allowed_bytes(state);
+        let mut groups = vec![vec![]; 256];
+        for (idx, tok) in vocab.iter().enumerate() {
+            if let Some(&b) = tok.first() {
+                if first_bytes.get(b as usize) {
+                    groups[b as usize].push(idx);
+                }
+            }
+        }
+
+        let mut mask = SimpleVob::alloc(vocab.len());
+        for (b, group) in groups.iter().enumerate() {
+            if group.is_empty() {
+                continue;
+            }
+            let next = self.transition(state, b as u8);
+            for &idx in group {
+                if self.walk_is_alive(next, &vocab[idx][1..]) {
+                    mask.set(idx, true);
+                }
+            }
+        }
+        mask
+    }
+
+    /// Compute which tokens of `vocab` would complete the current lexeme in `state`,
+    /// that is leave the lexer in an accepting state, or end a lazy lexeme
+    /// somewhere inside the token.
+    /// Tokens that merely continue the lexeme are not included,
+    /// so the result is a subset of `compute_mask_for_vocab()`.
+    /// For example, with `[0-9]{3}` after two digits, only one-digit tokens complete it.
+    pub fn completing_tokens(&mut self, state: StateID, vocab: &[&[u8]]) -> SimpleVob {
+        let first_bytes = self.allowed_bytes(state);
+        let mut mask = SimpleVob::alloc(vocab.len());
+        for (idx, tok) in vocab.iter().enumerate() {
+            if let Some(&b) = tok.first() {
+                if first_bytes.get(b as usize) && self.walk_completes(state, tok) {
+                    mask.set(idx, true);
+                }
+            }
+        }
+        mask
+    }
+
+    fn walk_completes(&mut self, mut state: StateID, bytes: &[u8]) -> bool {
+        for &b in bytes {
+            if state.has_lowest_match() {
+                return true;
+            }
+            state = self.transition(state, b);
+            if state.is_dead() {
+                return false;
+            }
+        }
+        state.has_lowest_match() || self.state_info(state).is_accepting()
+    }
+
+    fn walk_is_alive(&mut self, mut state: StateID, bytes: &[u8]) -> bool {
+        for &b in bytes {
+            if state.has_lowest_match() {
+                return true;
+            }
+            state = self.transition(state, b);
+            if state.is_dead() {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Lex as much of `bytes` as possible, restarting after each lexeme
+    /// with the same set of allowed lexemes, and report where lexing failed.
+    pub fn parse_prefix(&mut self, allowed: &SimpleVob, bytes: &[u8]) -> PrefixResult {
+        let mut state = self.start_state(allowed, None);
+        for (pos, &b) in bytes.iter().enumerate() {
+            let next = match self.advance(state, b, false) {
+                LexerResult::State(s, _) => s,
+                LexerResult::Lexeme(p) => {
+                    let transition_byte = if p.byte_next_row { p.byte } else { None };
+                    self.start_state(allowed, transition_byte)
+                }
+                LexerResult::Error => StateID::DEAD,
+            };
+            if next.is_dead() {
+                return PrefixResult {
+                    valid_len: pos,
+                    state,
+                    failing_byte: Some(b),
+                };
+            }
+            state = next;
+        }
+        PrefixResult {
+            valid_len: bytes.len(),
+            state,
+            failing_byte: None,
+        }
+    }
+
+    /// Set the filter applied to the input before matching.
+    /// Filters with a byte map (see `InputFilter::byte_map()`) apply to every
+    /// transition, including `advance()` and the mask computations, and so also
+    /// to the parser (see `TokenParser::set_input_filter()`); bytes reported
+    /// by the lexer (eg., `PreLexeme::byte`) are the original ones.
+    /// Other filters only apply to `lex_views()` and `lex_all_collecting_errors()`,
+    /// which map the offsets back to the original input.
+    pub fn set_input_filter(&mut self, filter: Option<InputFilter>) {
+        self.byte_map = filter.as_ref().and_then(|f| f.byte_map());
+        self.input_filter = filter;
+        self.update_plain_transitions();
+    }
+
+    fn update_plain_transitions(&mut self) {
+        self.plain_transitions = self.byte_map.is_none() && self.lexeme_fuel_budget.is_none();
+    }
+
+    pub fn input_filter(&self) -> Option<&InputFilter> {
+        self.input_filter.as_ref()
+    }
+
+    #[inline(always)]
+    fn transition(&mut self, state: StateID, byte: u8) -> StateID {
+        let byte = match &self.byte_map {
+            Some(map) => map[byte as usize],
+            None => byte,
+        };
+        self.dfa.transition(state, byte)
+    }
+
+    // the input of lex_views() after filters that don't apply to transitions
+    fn filter_input(&self, input: &[u8]) -> Option<(Vec<u8>, Vec<usize>)> {
+        match &self.input_filter {
+            Some(f) if self.byte_map.is_none() => Some(f.apply(input)),
+            _ => None,
+        }
+    }
+
+    // end offset, lexeme and hidden length, if `state` accepts and we track it
+    fn accept_point(&mut self, state: StateID, end: usize) -> Option<(usize, LexemeIdx, usize)> {
+        if self.spec.match_policy == MatchPolicy::Default {
+            return None;
+        }
+        let idx = self.state_info(state).lowest_accepting?;
+        let hidden_len = self.dfa.lookahead_len(state).unwrap_or(0);
+        Some((end, LexemeIdx::new(idx), hidden_len))
+    }
+
+    /// Split `input` into lexemes, restarting after each lexeme with the same
+    /// set of allowed lexemes; the lexemes borrow from `input`, nothing is copied.
+    /// Dead states are handled according to `LexerSpec::match_policy`,
+    /// and the input is first passed through `set_input_filter()`, if any.
+    pub fn lex_views<'a>(
+        &mut self,
+        allowed: &SimpleVob,
+        input: &'a [u8],
+    ) -> Result<Vec<LexemeView<'a>>> {
+        let filtered = self.filter_input(input);
+        let bytes = filtered.as_ref().map_or(input, |(b, _)| b.as_slice());
+        let offsets = filtered.as_ref().map(|(_, o)| o.as_slice());
+
+        let mut ranges = vec![];
+        match self.lex_ranges(allowed, bytes, 0, &mut ranges) {
+            Ok(()) => Ok(to_views(input, offsets, ranges)),
+            Err((_, pos)) if pos == bytes.len() => bail!("unfinished lexeme at end of input"),
+            Err((_, pos)) => bail!("lexer error at offset {}", orig_offset(offsets, pos)),
+        }
+    }
+
+    /// Like `lex_views()`, but instead of stopping at the first error,
+    /// skip the offending bytes, up to the next byte that can start
+    /// one of the `allowed` lexemes, and continue.
+    /// All errors are returned, in input order, as `LexerError::InvalidInput`.
+    pub fn lex_all_collecting_errors<'a>(
+        &mut self,
+        allowed: &SimpleVob,
+        input: &'a [u8],
+    ) -> (Vec<LexemeView<'a>>, Vec<LexerError>) {
+        let filtered = self.filter_input(input);
+        let bytes = filtered.as_ref().map_or(input, |(b, _)| b.as_slice());
+        let offsets = filtered.as_ref().map(|(_, o)| o.as_slice());
+
+        let first_bytes = self.initial_allowed_bytes(allowed);
+        let mut ranges = vec![];
+        let mut errors = vec![];
+        let mut start = 0;
+        while let Err((lexeme_start, pos)) = self.lex_ranges(allowed, bytes, start, &mut ranges) {
+            // always make progress, even if the lexeme failed on its first byte
+            let mut resume = std::cmp::max(pos, lexeme_start + 1);
+            while resume < bytes.len() && !first_bytes.get(bytes[resume] as usize) {
+                resume += 1;
+            }
+            errors.push(LexerError::InvalidInput {
+                start: orig_offset(offsets, lexeme_start),
+                end: orig_offset(offsets, resume),
+            });
+            start = resume;
+        }
+        (to_views(input, offsets, ranges), errors)
+    }
+
+    // lex `bytes[start..]`, appending (lexeme, start, end, hidden_len) to `out`;
+    // on failure, returns the start of the failed lexeme and the offset of the error
+    // (`bytes.len()` for an unfinished lexeme at the end)
+    fn lex_ranges(
+        &mut self,
+        allowed: &SimpleVob,
+        bytes: &[u8],
+        mut start: usize,
+        out: &mut Vec<(LexemeIdx, usize, usize, usize)>,
+    ) -> Result<(), (usize, usize)> {
+        let mut state = self.start_state(allowed, None);
+        let mut pos = start;
+        let mut last_accept = None;
+        loop {
+            let r = if pos < bytes.len() {
+                self.advance(state, bytes[pos], false)
+            } else if start < bytes.len() {
+                self.finish_at_eos(state, true)
+            } else {
+                break;
+            };
+            match r {
+                LexerResult::State(s, _) => {
+                    state = s;
+                    pos += 1;
+                    if let Some(acc) = self.accept_point(s, pos) {
+                        last_accept = Some(acc);
+                    }
+                }
+                LexerResult::Lexeme(p) => {
+                    let end = if p.byte_next_row || pos == bytes.len() {
+                        pos
+                    } else {
+                        pos + 1
+                    };
+                    out.push((p.idx, start, end, p.hidden_len));
+                    start = end;
+                    if pos == bytes.len() {
+                        break;
+                    }
+                    pos += 1;
+                    let transition_byte = if p.byte_next_row { p.byte } else { None };
+                    state = self.start_state(allowed, transition_byte);
+                    if state.is_dead() {
+                        return Err((pos - 1, pos - 1));
+                    }
+                    last_accept = if transition_byte.is_some() {
+                        self.accept_point(state, pos)
+                    } else {
+                        None
+                    };
+                }
+                LexerResult::Error => match last_accept.take() {
+                    Some((end, idx, hidden_len)) => {
+                        out.push((idx, start, end, hidden_len));
+                        start = end;
+                        pos = end;
+                        state = self.start_state(allowed, None);
+                    }
+                    None => return Err((start, pos)),
+                },
+            }
+        }
+        Ok(())
+    }
+
+    /// Like `lex_views()`, but SKIP lexemes are not returned; instead,
+    /// they are recorded as leading trivia of the following lexeme,
+    /// for lossless reconstruction of the input.
+    /// Trailing trivia, if any, starts at the end of the last lexeme.
+    pub fn lex_views_with_trivia<'a>(
+        &mut self,
+        allowed: &SimpleVob,
+        input: &'a [u8],
+    ) -> Result<Vec<LexemeWithTrivia<'a>>> {
+        let mut res = vec![];
+        let mut trivia_start = None;
+        for lexeme in self.lex_views(allowed, input)? {
+            if lexeme.idx == LexemeIdx::SKIP {
+                trivia_start.get_or_insert(lexeme.start);
+                continue;
+            }
+            let start = trivia_start.take().unwrap_or(lexeme.start);
+            res.push(LexemeWithTrivia {
+                leading_trivia: start..lexeme.start,
+                lexeme,
+            });
+        }
+        Ok(res)
+    }
+
+    /// Start lexing input that arrives in chunks (eg., model output read
+    /// from a socket or an async channel), see `LexemeStream`.
+    /// Unlike `lex_views()`, input is never re-read, so dead states
+    /// are always handled as in `MatchPolicy::Default`.
+    pub fn lex_stream(&mut self, allowed: &SimpleVob) -> LexemeStream<'_, D> {
+        let state = self.start_state(allowed, None);
+        LexemeStream {
+            lexer: self,
+            allowed: allowed.clone(),
+            state,
+            bytes: vec![],
+            offset: 0,
+            failed: false,
+        }
+    }
+
+    /// Lex bytes from an async source (eg., model output read from a channel),
+    /// yielding lexemes as soon as they end, and the pending lexeme, if any,
+    /// once the source is exhausted; the stream ends after the first error.
+    /// This only drives `lex_stream()`, so it works with any async runtime.
+    #[cfg(feature = "async")]
+    pub fn lex_async<S>(&mut self, allowed: &SimpleVob, source: S) -> AsyncLexemeStream<'_, S, D>
+    where
+        S: futures_core::Stream<Item = u8> + Unpin,
+    {
+        AsyncLexemeStream {
+            inner: Some(self.lex_stream(allowed)),
+            source,
+            ready: VecDeque::new(),
+        }
+    }
+
+    /// Lexer states are computed lazily, as bytes are consumed.
+    /// This runs the sample inputs through the lexer (allowing all lexemes)
+    /// so that the states on hot paths are computed ahead of time;
+    /// with `WarmupMode::Eager`, the states reachable from the initial
+    /// state are then computed as well (see there).
+    /// Returns the number of states realized so far.
+    pub fn warmup(&mut self, sample_inputs: &[&[u8]], mode: WarmupMode) -> usize {
+        let allowed = self.spec.all_lexemes();
+        for input in sample_inputs {
+            let mut state = self.start_state(&allowed, None);
+            for &b in input.iter() {
+                if self.dfa.has_error() {
+                    return self.dfa.num_states();
+                }
+                state = match self.advance(state, b, false) {
+                    LexerResult::State(s, _) => s,
+                    LexerResult::Lexeme(p) => {
+                        let transition_byte = if p.byte_next_row { p.byte } else { None };
+                        self.start_state(&allowed, transition_byte)
+                    }
+                    LexerResult::Error => self.start_state(&allowed, None),
+                };
+            }
+        }
+        if let WarmupMode::Eager { max_states } = mode {
+            // breadth-first, so that states closer to the start come first
+            let initial = self.dfa.initial_state(&allowed);
+            let mut seen = vec![];
+            let mut queue = VecDeque::from([initial]);
+            while let Some(state) = queue.pop_front() {
+                for b in 0..=255 {
+                    if self.dfa.has_error() || self.dfa.num_states() >= max_states {
+                        return self.dfa.num_states();
+                    }
+                    let next = self.dfa.transition(state, b);
+                    let idx = next.as_usize();
+                    if idx >= seen.len() {
+                        seen.resize(idx + 1, false);
+                    }
+                    if !next.is_dead() && !seen[idx] {
+                        seen[idx] = true;
+                        queue.push_back(next);
+                    }
+                }
+            }
+        }
+        self.dfa.num_states()
+    }
+
+    /// When enabled, a greedy lexeme ended by a byte that cannot start any lexeme
+    /// gets a diagnostic (`PreLexeme::diagnostic`), pointing at the likely grammar
+    /// gap, rather than only failing on the next lexeme.
+    /// This costs an extra transition per such lexeme.
+    pub fn set_boundary_check(&mut self, enabled: bool) {
+        self.boundary_state = if enabled {
+            let all = self.spec.all_lexemes();
+            Some(self.dfa.initial_state(&all))
+        } else {
+            None
+        };
+    }
+
+    fn check_boundary_byte(&mut self, byte: u8) -> Option<LexemeDiagnostic> {
+        let state = self.boundary_state?;
+        if self.transition(state, byte).is_dead() {
+            Some(LexemeDiagnostic::NoLexemeStartsWith(byte))
+        } else {
+            None
+        }
+    }
+
+    /// Use the general path even if the spec has a single lexeme,
+    /// to check it against the fast one; `reset_session()` restores the fast path.
+    pub(crate) fn disable_single_lexeme_path(&mut self) {
+        self.single_lexeme = None;
+        self.single_start = None;
+    }
+
+    /// Limit the fuel (see `RegexVec::set_fuel()`) spent on computing
+    /// transitions for any single lexeme, to guard against regexes with
+    /// pathological derivatives (eg., nested quantifiers).
+    /// The fuel spent on a transition is charged to all lexemes still possible
+    /// before it; cached transitions are free.
+    /// Once a lexeme exceeds the budget, `advance()` returns errors and
+    /// `check_error()` names the lexeme (the one charged most, if several).
+    pub fn set_lexeme_fuel_budget(&mut self, budget: Option<u64>) {
+        self.lexeme_fuel_budget = budget;
+        self.update_plain_transitions();
+    }
+
+    fn transition_with_budget(&mut self, prev: StateID, byte: u8, budget: u64) -> Option<StateID> {
+        if self.over_budget.is_some() {
+            return None;
+        }
+        let fuel0 = self.dfa.total_fuel_spent();
+        let state = self.transition(prev, byte);
+        let cost = self.dfa.total_fuel_spent() - fuel0;
+        if cost > 0 {
+            if self.lexeme_fuel.is_empty() {
+                self.lexeme_fuel = vec![0; self.spec.lexemes.len()];
+            }
+            let mut worst: Option<(u64, usize)> = None;
+            for idx in self.dfa.state_desc(prev).possible.iter() {
+                let fuel = &mut self.lexeme_fuel[idx as usize];
+                *fuel += cost;
+                if *fuel > budget && worst.is_none_or(|(w, _)| *fuel > w) {
+                    worst = Some((*fuel, idx as usize));
+                }
+            }
+            if let Some((_, idx)) = worst {
+                self.over_budget = Some(LexemeIdx::new(idx));
+                return None;
+            }
+        }
+        Some(state)
     }
 
     #[inline(always)]
     pub fn advance(&mut self, prev: StateID, byte: u8, enable_logging: bool) -> LexerResult {
-        let state = self.dfa.transition(prev, byte);
+        let state = if self.plain_transitions {
+            self.dfa.transition(prev, byte)
+        } else {
+            match self.lexeme_fuel_budget {
+                Some(budget) => match self.transition_with_budget(prev, byte, budget) {
+                    Some(state) => state,
+                    None => return LexerResult::Error,
+                },
+                None => self.transition(prev, byte),
+            }
+        };
 
         if enable_logging {
             let info = self.state_info(state);
@@ -153,15 +1359,15 @@ impl Lexer {
         }
 
         if state.is_dead() {
-            let info = self.dfa.state_desc(prev);
             // we take the first token that matched
             // (eg., "while" will match both keyword and identifier, but keyword is first)
-            if let Some(idx) = info.lowest_accepting {
+            if let Some(idx) = self.dfa.state_desc(prev).lowest_accepting {
                 LexerResult::Lexeme(PreLexeme {
                     idx: LexemeIdx::new(idx),
                     byte: Some(byte),
                     byte_next_row: true,
                     hidden_len: 0,
+                    diagnostic: self.check_boundary_byte(byte),
                 })
             } else {
                 LexerResult::Error
@@ -173,6 +1379,7 @@ impl Lexer {
                     byte: Some(byte),
                     byte_next_row: false,
                     hidden_len,
+                    diagnostic: None,
                 })
             } else {
                 unreachable!()
@@ -183,9 +1390,148 @@ impl Lexer {
     }
 }
 
+/// Incremental lexer driven by the caller as input arrives:
+/// `push()` every chunk, and `finish()` when the source is exhausted
+/// (`Lexer::lex_async()` does this for an async byte source).
+/// Lexemes are returned as soon as they end, with transforms applied
+/// (see `LexerSpec::set_lexeme_transform()`).
+pub struct LexemeStream<'a, D: DfaBackend = RegexVec> {
+    lexer: &'a mut Lexer<D>,
+    allowed: SimpleVob,
+    state: StateID,
+    // bytes of the current lexeme so far
+    bytes: Vec<u8>,
+    offset: usize,
+    failed: bool,
+}
+
+impl<D: DfaBackend> LexemeStream<'_, D> {
+    /// Feed the next chunk of input, returning the lexemes it completes.
+    /// After an error, the stream cannot be used anymore.
+    pub fn push(&mut self, chunk: &[u8]) -> Result<Vec<Lexeme>> {
+        ensure!(!self.failed, "lexer stream already failed");
+        let mut res = vec![];
+        for &b in chunk {
+            match self.lexer.advance(self.state, b, false) {
+                LexerResult::State(s, _) => {
+                    self.state = s;
+                    self.bytes.push(b);
+                }
+                LexerResult::Lexeme(p) => {
+                    if !p.byte_next_row {
+                        self.bytes.push(b);
+                    }
+                    let bytes = std::mem::take(&mut self.bytes);
+                    let lexeme = Lexeme::new(p.idx, bytes, p.hidden_len);
+                    res.push(self.lexer.lexer_spec().transform_lexeme(lexeme));
+                    let transition_byte = if p.byte_next_row { p.byte } else { None };
+                    self.state = self.lexer.start_state(&self.allowed, transition_byte);
+                    if self.state.is_dead() {
+                        self.failed = true;
+                        bail!("lexer error at offset {}", self.offset);
+                    }
+                    if transition_byte.is_some() {
+                        self.bytes.push(b);
+                    }
+                }
+                LexerResult::Error => {
+                    self.failed = true;
+                    bail!("lexer error at offset {}", self.offset);
+                }
+            }
+            self.offset += 1;
+        }
+        Ok(res)
+    }
+
+    /// Signal the end of input, returning the pending lexeme, if any.
+    pub fn finish(self) -> Result<Option<Lexeme>> {
+        ensure!(!self.failed, "lexer stream already failed");
+        if self.bytes.is_empty() {
+            return Ok(None);
+        }
+        match self.lexer.finish_at_eos(self.state, true) {
+            LexerResult::Lexeme(p) => {
+                let lexeme = Lexeme::new(p.idx, self.bytes, p.hidden_len);
+                Ok(Some(self.lexer.lexer_spec().transform_lexeme(lexeme)))
+            }
+            _ => bail!("unfinished lexeme at end of input"),
+        }
+    }
+}
+
+/// Stream of lexemes over an async byte source, see `Lexer::lex_async()`.
+#[cfg(feature = "async")]
+pub struct AsyncLexemeStream<'a, S, D: DfaBackend = RegexVec> {
+    // None once the source is exhausted, or after an error
+    inner: Option<LexemeStream<'a, D>>,
+    source: S,
+    // lexemes completed by the last byte, not yet yielded
+    ready: VecDeque<Lexeme>,
+}
+
+#[cfg(feature = "async")]
+impl<S, D> futures_core::Stream for AsyncLexemeStream<'_, S, D>
+where
+    S: futures_core::Stream<Item = u8> + Unpin,
+    D: DfaBackend,
+{
+    type Item = Result<Lexeme>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            if let Some(lexeme) = this.ready.pop_front() {
+                return Poll::Ready(Some(Ok(lexeme)));
+            }
+            let Some(inner) = this.inner.as_mut() else {
+                return Poll::Ready(None);
+            };
+            match Pin::new(&mut this.source).poll_next(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Some(b)) => match inner.push(&[b]) {
+                    Ok(lexemes) => this.ready.extend(lexemes),
+                    Err(e) => {
+                        this.inner = None;
+                        return Poll::Ready(Some(Err(e)));
+                    }
+                },
+                Poll::Ready(None) => {
+                    let inner = this.inner.take().unwrap();
+                    return Poll::Ready(inner.finish().transpose());
+                }
+            }
+        }
+    }
+}
+
 impl LexerResult {
     #[inline(always)]
     pub fn is_error(&self) -> bool {
         matches!(self, LexerResult::Error)
     }
 }
+
+// offset in the original input of position `pos` in the filtered input
+fn orig_offset(offsets: Option<&[usize]>, pos: usize) -> usize {
+    offsets.map_or(pos, |offsets| offsets[pos])
+}
+
+fn to_views<'a>(
+    input: &'a [u8],
+    offsets: Option<&[usize]>,
+    ranges: Vec<(LexemeIdx, usize, usize, usize)>,
+) -> Vec<LexemeView<'a>> {
+    ranges
+        .into_iter()
+        .map(|(idx, start, end, hidden_len)| {
+            let (orig_start, orig_end) = (orig_offset(offsets, start), orig_offset(offsets, end));
+            LexemeView {
+                idx,
+                start: orig_start,
+                bytes: &input[orig_start..orig_end],
+                hidden_len: orig_end - orig_offset(offsets, end - hidden_len),
+            }
+        })
+        .collect()
+}
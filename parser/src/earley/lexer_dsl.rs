@@ -0,0 +1,162 @@
+// A compact format for defining lexers, one lexeme per line:
+//
+//     # comment
+//     SKIP: /[ \t]+/
+//     KW_IF: "if"
+//     ID: /[a-z_][a-z0-9_]*/i
+//     STMT: /[^;]*;/ lazy
+//
+// Literals use JSON string syntax. Regexes are delimited by '/', with '\/'
+// standing for '/'; they can be followed by regex flags (i, m, s, x, U).
+// Lexeme flags are separated by whitespace: 'lazy' and 'contextual'.
+// The special SKIP lexeme defines the whitespace between lexemes.
+// Two lines defining the same regex are an error, since the spec would
+// merge them into a single lexeme.
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use derivre::{RegexAst, RegexBuilder};
+
+use super::lexerspec::{LexemeIdx, LexerSpec};
+
+struct DslLexeme {
+    name: String,
+    rx: RegexAst,
+    lazy: bool,
+    contextual: bool,
+}
+
+pub(crate) fn parse_dsl(src: &str) -> Result<LexerSpec> {
+    let mut skip = RegexAst::NoMatch;
+    let mut skip_line = None;
+    let mut lexemes = vec![];
+    for (line_no, line) in src.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let lex = parse_line(line).with_context(|| format!("line {}", line_no + 1))?;
+        if lex.name == "SKIP" {
+            ensure!(
+                !lex.lazy && !lex.contextual,
+                "line {}: SKIP cannot have flags",
+                line_no + 1
+            );
+            ensure!(
+                skip_line.is_none(),
+                "line {}: SKIP defined twice",
+                line_no + 1
+            );
+            skip = lex.rx;
+            skip_line = Some(line_no + 1);
+        } else {
+            lexemes.push((line_no + 1, lex));
+        }
+    }
+
+    // only an invalid SKIP regex can fail here
+    let mut spec = LexerSpec::new(RegexBuilder::new(), skip)
+        .with_context(|| format!("line {}", skip_line.unwrap_or(0)))?;
+    // the line defining each lexeme, by index
+    let mut defined_at = vec![skip_line];
+    for (line_no, lex) in lexemes {
+        let r = if lex.lazy {
+            spec.add_rx_and_stop(lex.name, lex.rx, RegexAst::EmptyString, true)
+        } else {
+            spec.add_greedy_lexeme(lex.name, lex.rx, lex.contextual, None)
+        };
+        let idx = r.with_context(|| format!("line {}", line_no))?.as_usize();
+        if idx < defined_at.len() {
+            let prev = spec.lexeme_spec(LexemeIdx::new(idx)).name();
+            match defined_at[idx] {
+                Some(prev_line) => bail!(
+                    "line {}: same lexeme as {} on line {}",
+                    line_no,
+                    prev,
+                    prev_line
+                ),
+                None => bail!("line {}: same lexeme as {}", line_no, prev),
+            }
+        }
+        defined_at.push(Some(line_no));
+    }
+    Ok(spec)
+}
+
+fn parse_line(line: &str) -> Result<DslLexeme> {
+    let (name, rest) = line
+        .split_once(':')
+        .ok_or_else(|| anyhow!("expecting 'NAME: definition'"))?;
+    let name = name.trim();
+    ensure!(
+        !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'),
+        "invalid lexeme name {:?}",
+        name
+    );
+    let rest = rest.trim_start();
+
+    let (rx, flags) = if rest.starts_with('"') {
+        let mut de = serde_json::Deserializer::from_str(rest).into_iter::<String>();
+        let literal = match de.next() {
+            Some(Ok(s)) => s,
+            _ => bail!("invalid string literal"),
+        };
+        (RegexAst::Literal(literal), &rest[de.byte_offset()..])
+    } else if let Some(body) = rest.strip_prefix('/') {
+        let mut rx = String::new();
+        let mut end = None;
+        let mut chars = body.char_indices();
+        while let Some((idx, c)) = chars.next() {
+            match c {
+                '/' => {
+                    end = Some(idx + 1);
+                    break;
+                }
+                '\\' => match chars.next() {
+                    Some((_, '/')) => rx.push('/'),
+                    Some((_, c)) => {
+                        rx.push('\\');
+                        rx.push(c);
+                    }
+                    None => bail!("unterminated regex"),
+                },
+                _ => rx.push(c),
+            }
+        }
+        let end = end.ok_or_else(|| anyhow!("unterminated regex"))?;
+        let rest = &body[end..];
+        let num_flags = rest
+            .find(|c: char| !c.is_ascii_alphabetic())
+            .unwrap_or(rest.len());
+        let rx_flags = &rest[..num_flags];
+        if !rx_flags.is_empty() {
+            ensure!(
+                rx_flags.chars().all(|c| "imsxU".contains(c)),
+                "invalid regex flags {:?}",
+                rx_flags
+            );
+            rx = format!("(?{}:{})", rx_flags, rx);
+        }
+        (RegexAst::Regex(rx), &rest[num_flags..])
+    } else {
+        bail!("expecting a string literal or a /regex/");
+    };
+
+    let mut lex = DslLexeme {
+        name: name.to_string(),
+        rx,
+        lazy: false,
+        contextual: false,
+    };
+    for flag in flags.split_whitespace() {
+        match flag {
+            "lazy" => lex.lazy = true,
+            "contextual" => lex.contextual = true,
+            _ => bail!("unknown flag {:?}", flag),
+        }
+    }
+    ensure!(
+        !(lex.lazy && lex.contextual),
+        "lazy lexemes cannot be contextual"
+    );
+    Ok(lex)
+}
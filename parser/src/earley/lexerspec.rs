@@ -1,11 +1,18 @@
-use anyhow::Result;
-use derivre::{ExprRef, JsonQuoteOptions, RegexAst, RegexBuilder};
-use std::{fmt::Debug, hash::Hash};
+use anyhow::{bail, ensure, Result};
+use derivre::{raw::RelevanceCache, ExprRef, JsonQuoteOptions, RegexAst, RegexBuilder};
+use regex_syntax::ast::{self, AssertionKind, Ast, Span};
+use rustc_hash::FxHashMap;
+use std::{fmt::Debug, hash::Hash, sync::Arc};
 use toktrie::{bytes::limit_str, SimpleVob, TokTrie};
 
 use crate::api::ParserLimits;
 
-use super::regexvec::RegexVec;
+use super::{
+    lexer_dsl::parse_dsl,
+    numeric::{rx_float_range, rx_int_range},
+    regexvec::RegexVec,
+    reverse::Reverser,
+};
 
 #[derive(Clone)]
 pub struct LexerSpec {
@@ -14,6 +21,79 @@ pub struct LexerSpec {
     pub no_forcing: bool,
     pub allow_initial_skip: bool,
     pub num_extra_lexemes: usize,
+    pub match_policy: MatchPolicy,
+    /// Restrict all lexemes (except for special tokens) to valid UTF-8.
+    /// For lexemes with a stop, only the body is restricted.
+    pub enforce_utf8: bool,
+    /// Lexemes allowed only a limited number of times in the whole input.
+    pub max_occurrences: Vec<(LexemeIdx, usize)>,
+    /// Lexemes allowed as trailing content once the grammar is complete.
+    pub after_eos: Vec<LexemeIdx>,
+    /// Factor common prefixes of alternatives of literals (eg., "apple"|"apply"),
+    /// when adding lexemes; has to be set before adding them.
+    pub factor_prefixes: bool,
+    /// Maximum number of hidden (lookahead) bytes a lexeme may require;
+    /// lexemes needing more, or an unbounded number, are rejected when added.
+    pub max_lookahead: usize,
+}
+
+/// Restrict `rx` to matches of at least `min_repeat` characters.
+pub fn rx_min_repeat(rx: RegexAst, min_repeat: usize) -> RegexAst {
+    if min_repeat == 0 {
+        return rx;
+    }
+    RegexAst::And(vec![
+        rx,
+        RegexAst::Regex(format!("(?s:.{{{},}})", min_repeat)),
+    ])
+}
+
+/// Upper bound on the length in bytes of strings matching `rx`,
+/// or `None` if unbounded (or not known).
+fn max_match_len(rx: &RegexAst) -> Option<usize> {
+    match rx {
+        RegexAst::EmptyString => Some(0),
+        RegexAst::Byte(_) => Some(1),
+        RegexAst::Literal(s) => Some(s.len()),
+        RegexAst::Regex(rx) => regex_syntax::Parser::new()
+            .parse(rx)
+            .ok()?
+            .properties()
+            .maximum_len(),
+        RegexAst::Concat(args) => args
+            .iter()
+            .try_fold(0usize, |acc, a| acc.checked_add(max_match_len(a)?)),
+        RegexAst::Or(args) => args
+            .iter()
+            .try_fold(0usize, |acc, a| Some(acc.max(max_match_len(a)?))),
+        _ => None,
+    }
+}
+
+/// How the lexer decides where a lexeme ends.
+/// The streaming lexer used by the parser (`Lexer::advance()`) never re-reads input,
+/// so backtracking to the last accepting state only happens in `Lexer::lex_views()`
+/// (and functions based on it); when streaming, `LastAccepting` is the same
+/// as `Default`, and `MaximalMunch` only makes lazy lexemes greedy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MatchPolicy {
+    /// Greedy lexemes are extended as long as possible,
+    /// while lazy lexemes end as soon as they match.
+    /// When the automaton dies, the lexeme accepted by the previous state
+    /// (if any, the first one) is committed; otherwise it's an error
+    /// (eg., lexemes `a` and `abc` on input `abd`).
+    #[default]
+    Default,
+    /// Like `Default`, but when the automaton dies, the last lexeme accepted
+    /// on the way is committed and the bytes after it are lexed again
+    /// (as in the usual backtracking tokenizers).
+    LastAccepting,
+    /// Longest overall match: like `LastAccepting`, but lazy lexemes without
+    /// a stop regex are also extended as long as possible, so that the longest
+    /// accepting prefix wins, instead of the first one.
+    /// Lexemes with a stop regex are still lazy, since the stop
+    /// (hidden) part has to be recognized as soon as it occurs.
+    MaximalMunch,
 }
 
 #[derive(Clone)]
@@ -26,6 +106,13 @@ pub struct LexemeSpec {
     lazy: bool,
     contextual: bool,
     json_options: Option<JsonQuoteOptions>,
+    /// Lexemes that never match a string starting with a match of this lexeme
+    /// (not only the strings matched by it), see `LexerSpec::add_shadows()`.
+    shadows: Vec<LexemeIdx>,
+    /// For lexemes added with `LexerSpec::add_string_set()`, index of each string.
+    string_set: Option<Arc<FxHashMap<Vec<u8>, usize>>>,
+    /// See `LexerSpec::set_lexeme_transform()`.
+    transform: Option<fn(&[u8]) -> Vec<u8>>,
 }
 
 /// LexemeIdx is an index into the lexeme table.
@@ -54,7 +141,190 @@ impl LexemeIdx {
     }
 }
 
+/// A lexeme regex with `{}` placeholders, which are replaced
+/// with (escaped) literal strings by `LexerSpec::instantiate()`.
+#[derive(Debug, Clone)]
+pub struct LexemeTemplate {
+    /// Instantiated lexemes are called `name_param`.
+    pub name: String,
+    pub rx: String,
+    pub contextual: bool,
+}
+
+impl LexemeTemplate {
+    pub fn new(name: &str, rx: &str) -> Self {
+        LexemeTemplate {
+            name: name.to_string(),
+            rx: rx.to_string(),
+            contextual: false,
+        }
+    }
+
+    pub fn expand(&self, param: &str) -> String {
+        self.rx.replace("{}", &regex_syntax::escape(param))
+    }
+}
+
+/// Error when adding a lexeme or constructing the lexer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LexerError {
+    /// The regex of the lexeme is invalid or uses unsupported features.
+    InvalidRegex { lexeme: String, message: String },
+    /// Lexer construction ran out of fuel (see `ParserLimits::initial_lexer_fuel`).
+    BudgetExceeded(String),
+    /// The automaton has more than `ParserLimits::max_lexer_states` states.
+    DfaTooLarge(String),
+    /// Advancing the lexer spent more fuel on the lexeme than allowed
+    /// by `Lexer::set_lexeme_fuel_budget()`.
+    LexemeBudgetExceeded { lexeme: String, budget: u64 },
+    /// The spec is inconsistent, or was used with invalid arguments
+    /// (eg., an unknown lexeme index).
+    InvalidSpec(String),
+    /// Input bytes `start..end` could not be lexed;
+    /// see `Lexer::lex_all_collecting_errors()`.
+    InvalidInput { start: usize, end: usize },
+}
+
+impl std::fmt::Display for LexerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LexerError::InvalidRegex { lexeme, message } => {
+                write!(f, "lexeme {:?}: {}", lexeme, message)
+            }
+            LexerError::BudgetExceeded(message)
+            | LexerError::DfaTooLarge(message)
+            | LexerError::InvalidSpec(message) => {
+                write!(f, "{}", message)
+            }
+            LexerError::LexemeBudgetExceeded { lexeme, budget } => {
+                write!(f, "lexeme {:?}: fuel budget of {} exceeded", lexeme, budget)
+            }
+            LexerError::InvalidInput { start, end } => {
+                write!(f, "invalid input at bytes {}..{}", start, end)
+            }
+        }
+    }
+}
+
+impl std::error::Error for LexerError {}
+
+/// Changes to a lexer spec, applied with `SpecDiff::apply()` or `Lexer::from_previous()`.
+#[derive(Debug, Clone, Default)]
+pub struct SpecDiff {
+    /// Lexemes whose regex changed.
+    pub changed: Vec<(LexemeIdx, RegexAst)>,
+    /// New greedy lexemes, added at the end.
+    pub added: Vec<(String, RegexAst)>,
+}
+
+impl SpecDiff {
+    /// Apply the changes, compiling only changed and added lexemes.
+    /// Returns the number of existing lexemes whose compiled regex was kept;
+    /// this includes changed lexemes that compile to the same expression.
+    /// Lexemes cannot be added to a spec with extra lexemes, since these have to come last.
+    pub fn apply(&self, spec: &mut LexerSpec) -> Result<usize, LexerError> {
+        if spec.num_extra_lexemes != 0 && !self.added.is_empty() {
+            return Err(LexerError::InvalidSpec(
+                "cannot add lexemes to a spec with extra lexemes".to_string(),
+            ));
+        }
+        let prev_rx: Vec<ExprRef> = spec.lexemes.iter().map(|lex| lex.compiled_rx).collect();
+        for (idx, rx) in &self.changed {
+            spec.set_lexeme_rx(*idx, rx.clone())?;
+        }
+        for (name, rx) in &self.added {
+            spec.add_greedy_lexeme(name.clone(), rx.clone(), false, None)?;
+        }
+        let num_reused = prev_rx
+            .iter()
+            .zip(spec.lexemes.iter())
+            .filter(|(rx, lex)| **rx == lex.compiled_rx)
+            .count();
+        Ok(num_reused)
+    }
+}
+
+/// A problem with a lexeme, as found by `LexerSpec::validate()`.
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub lexeme: LexemeIdx,
+    pub kind: DiagnosticKind,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DiagnosticKind {
+    /// The lexeme doesn't match any string.
+    EmptyLanguage,
+    /// The lexeme matches the empty string.
+    MatchesEmpty,
+    /// The lexeme matches some string also matched by another lexeme.
+    /// When both are allowed, the one with the lower index wins.
+    Overlap(LexemeIdx),
+    /// The check was too expensive and was abandoned.
+    TooComplex,
+    /// Overlaps of this lexeme with later lexemes (and of later lexemes
+    /// among themselves) were not checked, since the number of pairs
+    /// to check is limited (see `LexerSpec::validate_with_limits()`).
+    NotChecked,
+    /// The lexeme may need many bytes of lookahead (`None` if unbounded);
+    /// reported by `Lexer::build_with_report()`.
+    LargeLookahead(Option<usize>),
+}
+
+impl std::fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
 impl LexemeSpec {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The lexeme regex intersected with valid UTF-8 (see `LexerSpec::enforce_utf8`).
+    fn utf8_only_rx(&self) -> RegexAst {
+        let utf8 = RegexAst::Regex("(?s:.)*".to_string());
+        match &self.rx {
+            RegexAst::Concat(args) => match args.split_last() {
+                Some((RegexAst::LookAhead(stop), body)) => RegexAst::Concat(vec![
+                    RegexAst::And(vec![RegexAst::Concat(body.to_vec()), utf8]),
+                    RegexAst::LookAhead(stop.clone()),
+                ]),
+                Some(_)
+                    if matches!(args[0], RegexAst::Byte(TokTrie::SPECIAL_TOKEN_PREFIX_BYTE)) =>
+                {
+                    RegexAst::ExprRef(self.compiled_rx)
+                }
+                _ => RegexAst::And(vec![RegexAst::ExprRef(self.compiled_rx), utf8]),
+            },
+            _ => RegexAst::And(vec![RegexAst::ExprRef(self.compiled_rx), utf8]),
+        }
+    }
+
+    /// Canonical value of the lexeme with the given visible bytes,
+    /// if the lexeme has a transform (see `LexerSpec::set_lexeme_transform()`).
+    pub fn transform(&self, visible_bytes: &[u8]) -> Option<Vec<u8>> {
+        self.transform.map(|f| f(visible_bytes))
+    }
+
+    /// Upper bound on the number of hidden (lookahead) bytes of the lexeme,
+    /// 0 if it has no stop, `None` if unbounded.
+    pub fn max_lookahead_len(&self) -> Option<usize> {
+        match &self.rx {
+            RegexAst::Concat(args) => match args.last() {
+                Some(RegexAst::LookAhead(stop)) => max_match_len(stop),
+                _ => Some(0),
+            },
+            _ => Some(0),
+        }
+    }
+
+    fn has_stop(&self) -> bool {
+        matches!(&self.rx, RegexAst::Concat(args) if matches!(args.last(), Some(RegexAst::LookAhead(_))))
+    }
+
     /// Check if the lexeme always matches bytes, and has at least one more byte to spare.
     pub fn has_forced_bytes(&self, bytes: &[u8]) -> bool {
         match &self.rx {
@@ -73,18 +343,27 @@ impl Debug for LexemeSpec {
         if self.contextual {
             write!(f, " contextual")?;
         }
+        if self.transform.is_some() {
+            write!(f, " transform")?;
+        }
         Ok(())
     }
 }
 
 impl LexerSpec {
-    pub fn new(regex_builder: RegexBuilder, skip: RegexAst) -> Result<Self> {
+    pub fn new(regex_builder: RegexBuilder, skip: RegexAst) -> Result<Self, LexerError> {
         let mut r = LexerSpec {
             lexemes: Vec::new(),
             regex_builder,
             no_forcing: false,
             allow_initial_skip: false,
             num_extra_lexemes: 0,
+            match_policy: MatchPolicy::Default,
+            enforce_utf8: false,
+            max_occurrences: vec![],
+            after_eos: vec![],
+            factor_prefixes: false,
+            max_lookahead: usize::MAX,
         };
         let skip = r.add_lexeme_spec(LexemeSpec {
             name: "SKIP".to_string(),
@@ -95,10 +374,255 @@ impl LexerSpec {
         Ok(r)
     }
 
+    /// Parse a lexer definition in the compact format described in `lexer_dsl.rs`,
+    /// eg., `ID: /[a-z]+/` or `KW_IF: "if"`, one lexeme per line.
+    pub fn parse_dsl(src: &str) -> Result<LexerSpec, LexerError> {
+        parse_dsl(src).map_err(to_lexer_error)
+    }
+
+    /// Build a spec matching the byte-wise reversals of the lexemes,
+    /// for checking suffixes by feeding the input backwards.
+    /// Lexeme indices and names are preserved.
+    /// Lexemes are reversed from their compiled expressions, so the result
+    /// uses (a copy of) the same regex builder, and lexemes referring to
+    /// compiled expressions (eg., from grammar `rx_nodes`) are supported.
+    /// Lexemes with a stop (lookahead) or JSON quoting cannot be reversed.
+    /// UTF-8 is not enforced, since reversed UTF-8 is generally invalid.
+    pub fn reverse(&self) -> Result<LexerSpec, LexerError> {
+        let cannot_reverse = |lex: &LexemeSpec, message: String| LexerError::InvalidRegex {
+            lexeme: lex.name.clone(),
+            message: format!("cannot reverse: {}", message),
+        };
+        let mut reverser = Reverser::new(self.regex_builder.exprset());
+        let mut reverse_rx = |builder: &mut RegexBuilder, lex: &LexemeSpec| {
+            reverser
+                .reverse(builder, lex.compiled_rx)
+                .map(RegexAst::ExprRef)
+                .map_err(|e| cannot_reverse(lex, e.to_string()))
+        };
+        let mut builder = self.regex_builder.clone();
+        let skip = reverse_rx(&mut builder, &self.lexemes[0])?;
+        let mut r = LexerSpec::new(builder, skip)?;
+        r.no_forcing = self.no_forcing;
+        r.allow_initial_skip = self.allow_initial_skip;
+        r.num_extra_lexemes = self.num_extra_lexemes;
+        r.match_policy = self.match_policy;
+        r.max_occurrences = self.max_occurrences.clone();
+        r.after_eos = self.after_eos.clone();
+        r.max_lookahead = self.max_lookahead;
+        for lex in &self.lexemes[1..] {
+            if lex.json_options.is_some() {
+                return Err(cannot_reverse(lex, "JSON-quoted lexeme".to_string()));
+            }
+            let rx = reverse_rx(&mut r.regex_builder, lex)?;
+            let idx = r.add_lexeme_spec(LexemeSpec {
+                name: lex.name.clone(),
+                rx,
+                lazy: lex.lazy,
+                contextual: lex.contextual,
+                ends_at_eos: lex.ends_at_eos,
+                shadows: lex.shadows.clone(),
+                ..r.empty_spec()
+            })?;
+            if idx != lex.idx {
+                return Err(LexerError::InvalidSpec(format!(
+                    "reversed lexeme {} is the same as {}",
+                    lex.name, r.lexemes[idx.0].name
+                )));
+            }
+        }
+        Ok(r)
+    }
+
+    /// Build a spec whose lexemes match the strings matched by both a lexeme
+    /// of `self` and a lexeme of `other`, one for each such (non-empty) pair,
+    /// named `A&B`; SKIP is the intersection of the two SKIP lexemes.
+    /// Also returns, for each lexeme of the result, the pair it comes from.
+    /// The result is lazy if either lexeme is lazy.
+    /// Regexes of `self` may refer to its compiled expressions, as the
+    /// regex builder is shared, but those of `other` may not.
+    /// Lexemes with a stop (lookahead) or JSON quoting are not supported.
+    pub fn intersect(
+        &self,
+        other: &LexerSpec,
+    ) -> Result<(LexerSpec, Vec<(LexemeIdx, LexemeIdx)>), LexerError> {
+        // limit for a single emptiness check; see RegexVec::set_fuel() for units
+        const CHECK_FUEL: u64 = 10_000;
+
+        let unsupported = |lex: &LexemeSpec, message: &str| LexerError::InvalidRegex {
+            lexeme: lex.name.clone(),
+            message: format!("cannot intersect: {}", message),
+        };
+        for lex in self.lexemes.iter().chain(other.lexemes.iter()) {
+            if lex.json_options.is_some() || lex.has_stop() {
+                return Err(unsupported(lex, "lexeme with a stop or JSON quoting"));
+            }
+        }
+        for lex in &other.lexemes {
+            if has_expr_ref(&lex.rx) {
+                return Err(unsupported(
+                    lex,
+                    "refers to compiled expressions of its spec",
+                ));
+            }
+        }
+
+        let both = |a: &LexemeSpec, b: &LexemeSpec| RegexAst::And(vec![a.rx.clone(), b.rx.clone()]);
+
+        // find non-empty pairs in a scratch builder
+        let mut scratch = self.regex_builder.clone();
+        let mut candidates = vec![];
+        for a in &self.lexemes[1..] {
+            for b in &other.lexemes[1..] {
+                let compiled = scratch
+                    .mk(&both(a, b))
+                    .map_err(|e| LexerError::InvalidRegex {
+                        lexeme: format!("{}&{}", a.name, b.name),
+                        message: e.to_string(),
+                    })?;
+                candidates.push((a, b, compiled));
+            }
+        }
+        let mut exprs = scratch.exprset().clone();
+        let mut relevance = RelevanceCache::new();
+
+        let skip = both(&self.lexemes[0], &other.lexemes[0]);
+        let mut r = LexerSpec::new(self.regex_builder.clone(), skip)?;
+        r.no_forcing = self.no_forcing;
+        r.allow_initial_skip = self.allow_initial_skip;
+        r.match_policy = self.match_policy;
+        r.enforce_utf8 = self.enforce_utf8;
+        r.max_lookahead = self.max_lookahead;
+        let mut mapping = vec![(LexemeIdx::SKIP, LexemeIdx::SKIP)];
+        for (a, b, compiled) in candidates {
+            if compiled == ExprRef::NO_MATCH {
+                continue;
+            }
+            // keep the pair if the check runs out of fuel
+            if let Ok(false) = relevance.is_non_empty_limited(&mut exprs, compiled, CHECK_FUEL) {
+                continue;
+            }
+            let idx = r.add_lexeme_spec(LexemeSpec {
+                name: format!("{}&{}", a.name, b.name),
+                rx: both(a, b),
+                lazy: a.lazy || b.lazy,
+                ends_at_eos: !(a.lazy || b.lazy),
+                contextual: a.contextual && b.contextual,
+                ..r.empty_spec()
+            })?;
+            if idx.0 == mapping.len() {
+                mapping.push((a.idx, b.idx));
+            }
+        }
+        if mapping.len() == 1 {
+            return Err(LexerError::InvalidSpec(
+                "intersection of the lexers is empty".to_string(),
+            ));
+        }
+        Ok((r, mapping))
+    }
+
+    /// Check internal consistency of the spec: lexeme indices match positions,
+    /// all lexemes are compiled, SKIP comes first, and the lexemes referred to
+    /// (by shadows, `after_eos`, `max_occurrences` and extra lexemes) exist.
+    pub fn verify_invariants(&self) -> Result<()> {
+        ensure!(!self.lexemes.is_empty(), "missing SKIP lexeme");
+        ensure!(
+            self.lexemes[0].idx == LexemeIdx::SKIP,
+            "first lexeme is not SKIP"
+        );
+        ensure!(
+            self.num_extra_lexemes < self.lexemes.len(),
+            "too many extra lexemes: {} of {}",
+            self.num_extra_lexemes,
+            self.lexemes.len()
+        );
+        let num_lexemes = self.lexemes.len();
+        for (idx, lex) in self.lexemes.iter().enumerate() {
+            ensure!(
+                lex.idx.as_usize() == idx,
+                "lexeme {:?} at position {} has index {}",
+                lex.name,
+                idx,
+                lex.idx.as_usize()
+            );
+            ensure!(
+                lex.compiled_rx != ExprRef::INVALID,
+                "lexeme {:?} is not compiled",
+                lex.name
+            );
+            for s in &lex.shadows {
+                ensure!(
+                    s.as_usize() < num_lexemes,
+                    "lexeme {:?} shadows unknown lexeme {}",
+                    lex.name,
+                    s.as_usize()
+                );
+            }
+        }
+        for idx in &self.after_eos {
+            ensure!(
+                idx.as_usize() < num_lexemes,
+                "unknown lexeme {} allowed after EOS",
+                idx.as_usize()
+            );
+        }
+        for (idx, _) in &self.max_occurrences {
+            ensure!(
+                idx.as_usize() < num_lexemes,
+                "unknown lexeme {} with max_occurrences",
+                idx.as_usize()
+            );
+        }
+        Ok(())
+    }
+
+    /// Spec for the common "match regex, then stop at a string" pattern.
+    /// The "BODY" lexeme ends as soon as `stop` is seen; the stop is its
+    /// hidden part, so the body never consumes (any prefix of) the stop.
+    /// The "STOP" lexeme matches the stop alone, for when the body is empty.
+    /// Either lexeme ending means the stop was generated.
+    pub fn regex_with_stop(body: &str, stop: &str) -> Result<LexerSpec, LexerError> {
+        if stop.is_empty() {
+            return Err(LexerError::InvalidSpec(
+                "stop string must not be empty".to_string(),
+            ));
+        }
+        let mut spec = LexerSpec::new(RegexBuilder::new(), RegexAst::NoMatch)?;
+        spec.add_rx_and_stop(
+            "BODY".to_string(),
+            RegexAst::Regex(body.to_string()),
+            RegexAst::Literal(stop.to_string()),
+            true,
+        )?;
+        spec.add_rx_and_stop(
+            "STOP".to_string(),
+            RegexAst::EmptyString,
+            RegexAst::Literal(stop.to_string()),
+            true,
+        )?;
+        Ok(spec)
+    }
+
     pub fn alloc_lexeme_set(&self) -> SimpleVob {
         SimpleVob::alloc(self.lexemes.len())
     }
 
+    /// Lexemes that can still follow when the grammar is complete:
+    /// the SKIP lexeme (if any), and the ones added with `allow_after_eos()`.
+    /// The grammar can be still continued after a SKIP lexeme,
+    /// but not after the other ones.
+    pub fn allowed_after_eos(&self) -> SimpleVob {
+        let mut v = self.alloc_lexeme_set();
+        if self.lexemes[LexemeIdx::SKIP.as_usize()].compiled_rx != ExprRef::NO_MATCH {
+            v.set(LexemeIdx::SKIP.as_usize(), true);
+        }
+        for idx in &self.after_eos {
+            v.set(idx.as_usize(), true);
+        }
+        v
+    }
+
     pub fn all_lexemes(&self) -> SimpleVob {
         let mut v = self.alloc_lexeme_set();
         self.lexemes[0..self.lexemes.len() - self.num_extra_lexemes]
@@ -108,38 +632,212 @@ impl LexerSpec {
         v
     }
 
+    /// Under MatchPolicy::MaximalMunch, lazy lexemes without stop regex are greedy.
+    fn is_munched(&self, lex: &LexemeSpec) -> bool {
+        self.match_policy == MatchPolicy::MaximalMunch && lex.lazy && !lex.has_stop()
+    }
+
     pub fn lazy_lexemes(&self) -> SimpleVob {
         let mut v = self.alloc_lexeme_set();
         for (idx, lex) in self.lexemes.iter().enumerate() {
-            if lex.lazy {
+            if lex.lazy && !self.is_munched(lex) {
                 v.set(idx, true);
             }
         }
         v
     }
 
+    /// Like other lexeme sets, this is indexed by lexeme, and thus iterated in ascending order.
     pub fn eos_ending_lexemes(&self) -> SimpleVob {
         let mut v = self.alloc_lexeme_set();
         for (idx, lex) in self.lexemes.iter().enumerate() {
-            if lex.ends_at_eos {
+            if lex.ends_at_eos || self.is_munched(lex) {
                 v.set(idx, true);
             }
         }
         v
     }
 
+    /// Return the lexeme if it is the only one that can ever match.
+    pub fn single_lexeme(&self) -> Option<LexemeIdx> {
+        let mut live = self
+            .lexemes
+            .iter()
+            .filter(|lex| lex.compiled_rx != ExprRef::NO_MATCH);
+        match (live.next(), live.next()) {
+            (Some(lex), None) => Some(lex.idx),
+            _ => None,
+        }
+    }
+
     pub fn is_nullable(&self, idx: LexemeIdx) -> bool {
         self.regex_builder
             .is_nullable(self.lexemes[idx.0].compiled_rx)
     }
 
-    pub fn to_regex_vec(&self, limits: &mut ParserLimits) -> Result<RegexVec> {
+    /// Check lexemes for likely mistakes, without building the lexer automaton.
+    /// Regex syntax errors are reported (with lexeme names) already
+    /// when lexemes are added; here we look for lexemes that never match,
+    /// lexemes that match the empty string, and pairs of overlapping lexemes.
+    /// Checks use the default limits of `validate_with_limits()`.
+    pub fn validate(&self) -> Result<Vec<Diagnostic>> {
+        self.validate_with_limits(10_000, 10_000)
+    }
+
+    /// Like `validate()`, with `check_fuel` for every check (see `RegexVec::set_fuel()`
+    /// for units), and at most `max_pairs` pairs of lexemes checked for overlaps,
+    /// since there are quadratically many.
+    pub fn validate_with_limits(
+        &self,
+        check_fuel: u64,
+        max_pairs: usize,
+    ) -> Result<Vec<Diagnostic>> {
+        let mut exprs = self.regex_builder.exprset().clone();
+        let mut relevance = RelevanceCache::new();
+        let mut res = vec![];
+        let mut live = vec![];
+
+        let mut diag = |lex: &LexemeSpec, kind: DiagnosticKind, msg: String| {
+            res.push(Diagnostic {
+                lexeme: lex.idx,
+                kind,
+                message: format!("lexeme {:?}: {}", lex.name, msg),
+            });
+        };
+
+        for lex in &self.lexemes {
+            if lex.compiled_rx == ExprRef::NO_MATCH {
+                // this is how a missing SKIP lexeme is represented
+                if lex.idx != LexemeIdx::SKIP {
+                    diag(
+                        lex,
+                        DiagnosticKind::EmptyLanguage,
+                        "never matches".to_string(),
+                    );
+                }
+                continue;
+            }
+            match relevance.is_non_empty_limited(&mut exprs, lex.compiled_rx, check_fuel) {
+                Ok(true) => live.push(lex),
+                Ok(false) => {
+                    diag(
+                        lex,
+                        DiagnosticKind::EmptyLanguage,
+                        "never matches".to_string(),
+                    );
+                    continue;
+                }
+                Err(_) => {
+                    diag(
+                        lex,
+                        DiagnosticKind::TooComplex,
+                        "too complex to check".to_string(),
+                    );
+                    continue;
+                }
+            }
+            if exprs.is_nullable(lex.compiled_rx) {
+                diag(
+                    lex,
+                    DiagnosticKind::MatchesEmpty,
+                    "matches the empty string".to_string(),
+                );
+            }
+        }
+
+        let mut num_pairs = 0;
+        'pairs: for (i, a) in live.iter().enumerate() {
+            for b in &live[i + 1..] {
+                if a.compiled_rx == b.compiled_rx {
+                    // aliases
+                    continue;
+                }
+                if num_pairs >= max_pairs {
+                    diag(
+                        a,
+                        DiagnosticKind::NotChecked,
+                        format!(
+                            "overlaps with later lexemes not checked (limit of {} pairs)",
+                            max_pairs
+                        ),
+                    );
+                    break 'pairs;
+                }
+                num_pairs += 1;
+                let both = exprs.mk_and(vec![a.compiled_rx, b.compiled_rx]);
+                match relevance.is_non_empty_limited(&mut exprs, both, check_fuel) {
+                    Ok(true) => diag(
+                        b,
+                        DiagnosticKind::Overlap(a.idx),
+                        format!("overlaps with {:?}", a.name),
+                    ),
+                    Ok(false) => {}
+                    Err(_) => diag(
+                        b,
+                        DiagnosticKind::TooComplex,
+                        format!("too complex to check for overlaps with {:?}", a.name),
+                    ),
+                }
+            }
+        }
+
+        Ok(res)
+    }
+
+    /// Build the automaton for the spec.
+    /// Failures to compile the regexes of `enforce_utf8` or shadowing are reported
+    /// as `LexerError::InvalidRegex`, running out of `ParserLimits::initial_lexer_fuel`
+    /// as `LexerError::BudgetExceeded`.
+    pub fn to_regex_vec(&self, limits: &mut ParserLimits) -> Result<RegexVec, LexerError> {
         // TODO
         // Find all non-contextual lexemes that are literals (we call them 'keywords')
         // This assumes that this is the only possible conflict in the lexer that we want to catch.
         // For every non literals lexeme, find all keywords that match it.
         // Replace the regex R for the lexeme with (R & ~(K1|K2|...)) where K1...
         // are the conflicting keywords.
+        let err = |lex: &LexemeSpec, e: anyhow::Error| LexerError::InvalidRegex {
+            lexeme: lex.name.clone(),
+            message: e.to_string(),
+        };
+        let budget_err = |e: anyhow::Error| LexerError::BudgetExceeded(e.to_string());
+        let has_shadows = self.lexemes.iter().any(|lex| !lex.shadows.is_empty());
+        if self.enforce_utf8 || has_shadows {
+            let mut builder = self.regex_builder.clone();
+            let mut rx_list = vec![];
+            for lex in &self.lexemes {
+                rx_list.push(if self.enforce_utf8 {
+                    builder.mk(&lex.utf8_only_rx()).map_err(|e| err(lex, e))?
+                } else {
+                    lex.compiled_rx
+                });
+            }
+            for lex in &self.lexemes {
+                if lex.shadows.is_empty() {
+                    continue;
+                }
+                // strings starting with a match of the shadowing lexeme
+                let shadow = RegexAst::Concat(vec![
+                    RegexAst::ExprRef(lex.compiled_rx),
+                    RegexAst::Regex("(?s:.*)".to_string()),
+                ]);
+                for shadowed in &lex.shadows {
+                    let idx = shadowed.as_usize();
+                    rx_list[idx] = builder
+                        .mk(&RegexAst::And(vec![
+                            RegexAst::ExprRef(rx_list[idx]),
+                            RegexAst::Not(Box::new(shadow.clone())),
+                        ]))
+                        .map_err(|e| err(&self.lexemes[idx], e))?;
+                }
+            }
+            return RegexVec::new_with_exprset(
+                builder.exprset(),
+                &rx_list,
+                Some(self.lazy_lexemes()),
+                limits,
+            )
+            .map_err(budget_err);
+        }
         let rx_list: Vec<_> = self.lexemes.iter().map(|lex| lex.compiled_rx).collect();
         RegexVec::new_with_exprset(
             self.regex_builder.exprset(),
@@ -147,15 +845,60 @@ impl LexerSpec {
             Some(self.lazy_lexemes()),
             limits,
         )
+        .map_err(budget_err)
     }
 
-    fn add_lexeme_spec(&mut self, mut spec: LexemeSpec) -> Result<LexemeIdx> {
-        let compiled = self.regex_builder.mk(&spec.rx)?;
-        let compiled = if let Some(ref opts) = spec.json_options {
-            self.regex_builder.json_quote(compiled, opts)?
+    fn compile_lexeme(&mut self, spec: &mut LexemeSpec) -> Result<ExprRef, LexerError> {
+        let err = |name: &str, e: anyhow::Error| LexerError::InvalidRegex {
+            lexeme: name.to_string(),
+            message: e.to_string(),
+        };
+        // this is the only place where anchors are stripped
+        if let Some(rx) = lexeme_body_regex(&mut spec.rx) {
+            *rx = strip_regex_anchors(rx).map_err(|e| err(&spec.name, e))?;
+        }
+        self.check_lookahead(spec)?;
+        if self.factor_prefixes {
+            if let Some(factored) = factor_literal_alternatives(&spec.rx) {
+                spec.rx = factored;
+            }
+        }
+        let compiled = self
+            .regex_builder
+            .mk(&spec.rx)
+            .map_err(|e| err(&spec.name, e))?;
+        if let Some(ref opts) = spec.json_options {
+            self.regex_builder
+                .json_quote(compiled, opts)
+                .map_err(|e| err(&spec.name, e))
         } else {
-            compiled
+            Ok(compiled)
+        }
+    }
+
+    fn check_lookahead(&self, spec: &LexemeSpec) -> Result<(), LexerError> {
+        if self.max_lookahead == usize::MAX {
+            return Ok(());
+        }
+        let message = match spec.max_lookahead_len() {
+            Some(len) if len <= self.max_lookahead => return Ok(()),
+            Some(len) => format!(
+                "lookahead needs up to {} bytes, above the limit of {}",
+                len, self.max_lookahead
+            ),
+            None => format!(
+                "lookahead length is unbounded, above the limit of {}",
+                self.max_lookahead
+            ),
         };
+        Err(LexerError::InvalidRegex {
+            lexeme: spec.name.clone(),
+            message,
+        })
+    }
+
+    fn add_lexeme_spec(&mut self, mut spec: LexemeSpec) -> Result<LexemeIdx, LexerError> {
+        let compiled = self.compile_lexeme(&mut spec)?;
         if let Some(idx) = self
             .lexemes
             .iter()
@@ -170,6 +913,21 @@ impl LexerSpec {
         Ok(idx)
     }
 
+    /// Replace the regex of an existing lexeme, keeping its index and other properties.
+    /// Only the new regex is compiled; the regex builder is shared, so
+    /// sub-expressions of other lexemes are reused.
+    pub fn set_lexeme_rx(&mut self, idx: LexemeIdx, rx: RegexAst) -> Result<(), LexerError> {
+        self.check_idx(idx)?;
+        let mut spec = LexemeSpec {
+            rx,
+            string_set: None,
+            ..self.lexemes[idx.0].clone()
+        };
+        spec.compiled_rx = self.compile_lexeme(&mut spec)?;
+        self.lexemes[idx.0] = spec;
+        Ok(())
+    }
+
     fn empty_spec(&self) -> LexemeSpec {
         LexemeSpec {
             idx: LexemeIdx(0),
@@ -180,6 +938,9 @@ impl LexerSpec {
             contextual: false,
             ends_at_eos: false,
             json_options: None,
+            shadows: vec![],
+            string_set: None,
+            transform: None,
         }
     }
 
@@ -189,7 +950,7 @@ impl LexerSpec {
         body_rx: RegexAst,
         stop_rx: RegexAst,
         lazy: bool,
-    ) -> Result<LexemeIdx> {
+    ) -> Result<LexemeIdx, LexerError> {
         let rx = if !matches!(stop_rx, RegexAst::EmptyString) {
             RegexAst::Concat(vec![body_rx, RegexAst::LookAhead(Box::new(stop_rx))])
         } else {
@@ -209,7 +970,7 @@ impl LexerSpec {
         name: String,
         literal: &str,
         contextual: bool,
-    ) -> Result<LexemeIdx> {
+    ) -> Result<LexemeIdx, LexerError> {
         self.add_lexeme_spec(LexemeSpec {
             name,
             rx: RegexAst::Literal(literal.to_string()),
@@ -218,7 +979,7 @@ impl LexerSpec {
         })
     }
 
-    pub fn add_special_token(&mut self, name: String) -> Result<LexemeIdx> {
+    pub fn add_special_token(&mut self, name: String) -> Result<LexemeIdx, LexerError> {
         let rx = RegexAst::Concat(vec![
             RegexAst::Byte(TokTrie::SPECIAL_TOKEN_PREFIX_BYTE),
             RegexAst::Literal(name.clone()),
@@ -236,7 +997,7 @@ impl LexerSpec {
         rx: RegexAst,
         contextual: bool,
         json_options: Option<JsonQuoteOptions>,
-    ) -> Result<LexemeIdx> {
+    ) -> Result<LexemeIdx, LexerError> {
         self.add_lexeme_spec(LexemeSpec {
             name,
             rx,
@@ -246,6 +1007,255 @@ impl LexerSpec {
         })
     }
 
+    /// Add a greedy lexeme which only accepts after at least `min_repeat` characters.
+    /// Shorter matches are not accepting, also at EOS.
+    pub fn add_min_repeat_lexeme(
+        &mut self,
+        name: String,
+        rx: RegexAst,
+        min_repeat: usize,
+    ) -> Result<LexemeIdx, LexerError> {
+        self.add_greedy_lexeme(name, rx_min_repeat(rx, min_repeat), false, None)
+    }
+
+    /// Add one lexeme per parameter, by expanding the template.
+    /// Common parts of the regexes are shared in the regex builder.
+    pub fn instantiate(
+        &mut self,
+        template: &LexemeTemplate,
+        params: &[&str],
+    ) -> Result<Vec<LexemeIdx>, LexerError> {
+        params
+            .iter()
+            .map(|param| {
+                self.add_greedy_lexeme(
+                    format!("{}_{}", template.name, param),
+                    RegexAst::Regex(template.expand(param)),
+                    template.contextual,
+                    None,
+                )
+            })
+            .collect()
+    }
+
+    /// Add a lexeme matching integers in `[min, max]`.
+    /// Only canonical decimal representations are matched
+    /// (no leading zeros, no '+' sign, no "-0").
+    pub fn add_int_range(
+        &mut self,
+        name: String,
+        min: i64,
+        max: i64,
+    ) -> Result<LexemeIdx, LexerError> {
+        match rx_int_range(min, max) {
+            Ok(rx) => self.add_greedy_lexeme(name, rx, false, None),
+            Err(e) => Err(LexerError::InvalidRegex {
+                lexeme: name,
+                message: e.to_string(),
+            }),
+        }
+    }
+
+    /// Add a lexeme matching decimal numbers in `[min, max]`.
+    /// The fractional part is optional, and exponent notation is not matched.
+    pub fn add_float_range(
+        &mut self,
+        name: String,
+        min: f64,
+        max: f64,
+    ) -> Result<LexemeIdx, LexerError> {
+        match rx_float_range(min, max) {
+            Ok(rx) => self.add_greedy_lexeme(name, rx, false, None),
+            Err(e) => Err(LexerError::InvalidRegex {
+                lexeme: name,
+                message: e.to_string(),
+            }),
+        }
+    }
+
+    /// Add a lexeme matching exactly one of `strings` (eg., a dictionary).
+    /// The strings are arranged in a trie (common prefixes are shared),
+    /// which is much cheaper to compile and lex with than a plain alternative
+    /// of thousands of literals.
+    /// Use `string_set_index()` to find which of the strings was matched.
+    pub fn add_string_set(
+        &mut self,
+        name: String,
+        strings: &[&str],
+    ) -> Result<LexemeIdx, LexerError> {
+        let message = if strings.is_empty() {
+            Some("empty string set")
+        } else if strings.iter().any(|s| s.is_empty()) {
+            Some("string set contains an empty string")
+        } else {
+            None
+        };
+        if let Some(message) = message {
+            return Err(LexerError::InvalidRegex {
+                lexeme: name,
+                message: message.to_string(),
+            });
+        }
+        let mut index = FxHashMap::default();
+        for (i, s) in strings.iter().enumerate() {
+            index.entry(s.as_bytes().to_vec()).or_insert(i);
+        }
+        let idx = self.add_lexeme_spec(LexemeSpec {
+            name,
+            rx: factor_prefixes(strings),
+            ..self.empty_spec()
+        })?;
+        let lex = &mut self.lexemes[idx.0];
+        if lex.string_set.is_none() {
+            lex.string_set = Some(Arc::new(index));
+        }
+        Ok(idx)
+    }
+
+    /// For a lexeme added with `add_string_set()`, the index of the string
+    /// equal to `bytes` (the first one, if there are duplicates).
+    pub fn string_set_index(&self, idx: LexemeIdx, bytes: &[u8]) -> Option<usize> {
+        self.lexemes[idx.0]
+            .string_set
+            .as_ref()
+            .and_then(|set| set.get(bytes).copied())
+    }
+
+    /// Set a function computing the canonical value of the lexeme from its
+    /// visible bytes (eg., unescaping strings, or normalizing numbers).
+    /// It is applied when the lexeme is emitted, see `Lexeme::value()`;
+    /// matching is not affected, and the original bytes are kept.
+    pub fn set_lexeme_transform(
+        &mut self,
+        idx: LexemeIdx,
+        transform: Option<fn(&[u8]) -> Vec<u8>>,
+    ) -> Result<(), LexerError> {
+        self.check_idx(idx)?;
+        self.lexemes[idx.0].transform = transform;
+        Ok(())
+    }
+
+    /// Compute the value of `lexeme`, if its lexeme has a transform.
+    pub fn transform_lexeme(&self, mut lexeme: Lexeme) -> Lexeme {
+        lexeme.value = self.lexemes[lexeme.idx.0].transform(lexeme.visible_bytes());
+        lexeme
+    }
+
+    /// Allow the lexeme at most `max` times; once the cap is reached,
+    /// the parser removes it from the allowed lexemes.
+    /// The skip lexeme cannot be capped.
+    pub fn set_max_occurrences(&mut self, idx: LexemeIdx, max: usize) -> Result<(), LexerError> {
+        self.check_idx(idx)?;
+        if idx == LexemeIdx::SKIP {
+            return Err(LexerError::InvalidSpec(format!(
+                "lexeme {} cannot have max_occurrences",
+                self.lexemes[idx.0].name
+            )));
+        }
+        self.max_occurrences.retain(|(lx, _)| *lx != idx);
+        self.max_occurrences.push((idx, max));
+        Ok(())
+    }
+
+    /// Allow the lexeme as trailing content once the grammar is complete
+    /// (eg., a footer or trailing newlines).
+    /// Lexemes with a stop regex are rejected, since the parser matches
+    /// their hidden stop against what the grammar allows next.
+    pub fn allow_after_eos(&mut self, idx: LexemeIdx) -> Result<(), LexerError> {
+        self.check_idx(idx)?;
+        let lex = &self.lexemes[idx.0];
+        if idx == LexemeIdx::SKIP || lex.has_stop() {
+            return Err(LexerError::InvalidSpec(format!(
+                "lexeme {} cannot be allowed after EOS",
+                lex.name
+            )));
+        }
+        if !self.after_eos.contains(&idx) {
+            self.after_eos.push(idx);
+        }
+        Ok(())
+    }
+
+    /// Add a lexeme matching non-empty UTF-8 strings not matched by any of the `patterns`
+    /// (regexes), eg., identifiers other than keywords.
+    pub fn add_complement_lexeme(
+        &mut self,
+        name: String,
+        patterns: &[&str],
+    ) -> Result<LexemeIdx, LexerError> {
+        let excluded = RegexAst::Or(
+            patterns
+                .iter()
+                .map(|p| RegexAst::Regex(p.to_string()))
+                .collect(),
+        );
+        let rx = RegexAst::And(vec![
+            RegexAst::Regex("(?s:.)+".to_string()),
+            RegexAst::Not(Box::new(excluded)),
+        ]);
+        self.add_greedy_lexeme(name, rx, false, None)
+    }
+
+    /// Make `lexeme` shadow the `shadowed` lexemes: once `lexeme` matches,
+    /// the shadowed ones are out, regardless of lexeme order and of length;
+    /// that is, they never match a string that starts with a match of `lexeme`
+    /// (eg., with keyword `if` shadowing identifiers, `iffy` lexes as `if` and `fy`).
+    /// This is static, and so applies whether or not `lexeme` is allowed.
+    /// The shadowing lexeme cannot have a stop regex.
+    pub fn add_shadows(
+        &mut self,
+        lexeme: LexemeIdx,
+        shadowed: &[LexemeIdx],
+    ) -> Result<(), LexerError> {
+        self.check_idx(lexeme)?;
+        if self.lexemes[lexeme.0].has_stop() {
+            return Err(LexerError::InvalidSpec(format!(
+                "lexeme {} has a stop regex and cannot shadow other lexemes",
+                self.lexemes[lexeme.0].name
+            )));
+        }
+        for &idx in shadowed {
+            self.check_idx(idx)?;
+            if idx == lexeme {
+                return Err(LexerError::InvalidSpec(format!(
+                    "lexeme {} cannot shadow itself",
+                    self.lexemes[idx.0].name
+                )));
+            }
+        }
+        self.lexemes[lexeme.as_usize()]
+            .shadows
+            .extend_from_slice(shadowed);
+        Ok(())
+    }
+
+    /// Add a lexeme with the same regex as `target` but with a distinct index.
+    /// Identical regexes are normally collapsed into a single lexeme;
+    /// an alias shares the automaton with `target`, and which of the two
+    /// indices is reported depends on the set of allowed lexemes
+    /// (if both are allowed, the lower index wins).
+    pub fn add_lexeme_alias(
+        &mut self,
+        name: String,
+        target: LexemeIdx,
+    ) -> Result<LexemeIdx, LexerError> {
+        self.check_idx(target)?;
+        if self.num_extra_lexemes != 0 {
+            return Err(LexerError::InvalidSpec(format!(
+                "alias {} added after extra lexemes",
+                name
+            )));
+        }
+        let idx = LexemeIdx(self.lexemes.len());
+        let spec = LexemeSpec {
+            idx,
+            name,
+            ..self.lexemes[target.0].clone()
+        };
+        self.lexemes.push(spec);
+        Ok(idx)
+    }
+
     pub fn add_extra_lexemes(&mut self, extra_lexemes: &Vec<String>) {
         assert!(self.num_extra_lexemes == 0);
         self.num_extra_lexemes = extra_lexemes.len();
@@ -294,6 +1304,17 @@ impl LexerSpec {
         &self.lexemes[idx.0]
     }
 
+    fn check_idx(&self, idx: LexemeIdx) -> Result<(), LexerError> {
+        if idx.0 < self.lexemes.len() {
+            Ok(())
+        } else {
+            Err(LexerError::InvalidSpec(format!(
+                "lexeme index {} out of range",
+                idx.0
+            )))
+        }
+    }
+
     pub fn cost(&self) -> u64 {
         self.regex_builder.exprset().cost()
     }
@@ -309,11 +1330,229 @@ impl Debug for LexerSpec {
     }
 }
 
+// keep LexerErrors raised by the spec (also under a context), and wrap others,
+// with their context
+fn to_lexer_error(e: anyhow::Error) -> LexerError {
+    e.downcast::<LexerError>()
+        .unwrap_or_else(|e| LexerError::InvalidSpec(format!("{:#}", e)))
+}
+
+fn has_expr_ref(rx: &RegexAst) -> bool {
+    match rx {
+        RegexAst::ExprRef(_) => true,
+        RegexAst::And(args) | RegexAst::Or(args) | RegexAst::Concat(args) => {
+            args.iter().any(has_expr_ref)
+        }
+        RegexAst::Not(inner) | RegexAst::LookAhead(inner) | RegexAst::Repeat(inner, _, _) => {
+            has_expr_ref(inner)
+        }
+        _ => false,
+    }
+}
+
+fn is_anchor(kind: &AssertionKind) -> bool {
+    matches!(
+        kind,
+        AssertionKind::StartLine
+            | AssertionKind::StartText
+            | AssertionKind::EndLine
+            | AssertionKind::EndText
+    )
+}
+
+/// If `rx` is an alternative of literals, turn it into an equivalent regex
+/// where literals with common prefixes share them.
+fn factor_literal_alternatives(rx: &RegexAst) -> Option<RegexAst> {
+    let args = match rx {
+        RegexAst::Or(args) if args.len() > 1 => args,
+        _ => return None,
+    };
+    let literals = args
+        .iter()
+        .map(|a| match a {
+            RegexAst::Literal(s) => Some(s.as_str()),
+            _ => None,
+        })
+        .collect::<Option<Vec<_>>>()?;
+    Some(factor_prefixes(&literals))
+}
+
+fn factor_prefixes(literals: &[&str]) -> RegexAst {
+    let mut has_empty = false;
+    // group by the first character, preserving order
+    let mut groups: Vec<(char, Vec<&str>)> = vec![];
+    for &lit in literals {
+        match lit.chars().next() {
+            None => has_empty = true,
+            Some(c) => match groups.iter_mut().find(|(c2, _)| *c2 == c) {
+                Some((_, group)) => group.push(lit),
+                None => groups.push((c, vec![lit])),
+            },
+        }
+    }
+
+    let mut alternatives = vec![];
+    for (_, group) in groups {
+        if group.len() == 1 {
+            alternatives.push(RegexAst::Literal(group[0].to_string()));
+            continue;
+        }
+        let mut prefix_len = group[0].len();
+        for lit in &group[1..] {
+            prefix_len = group[0]
+                .char_indices()
+                .zip(lit.chars())
+                .take_while(|((_, a), b)| a == b)
+                .map(|((i, a), _)| i + a.len_utf8())
+                .last()
+                .unwrap_or(0)
+                .min(prefix_len);
+        }
+        let rest = group
+            .iter()
+            .map(|lit| &lit[prefix_len..])
+            .collect::<Vec<_>>();
+        alternatives.push(RegexAst::Concat(vec![
+            RegexAst::Literal(group[0][..prefix_len].to_string()),
+            factor_prefixes(&rest),
+        ]));
+    }
+    if has_empty {
+        alternatives.push(RegexAst::EmptyString);
+    }
+
+    if alternatives.len() == 1 {
+        alternatives.pop().unwrap()
+    } else {
+        RegexAst::Or(alternatives)
+    }
+}
+
+/// The regex source of the lexeme body, if it's given as a string,
+/// either directly or followed by the stop lookahead (see `add_rx_and_stop()`).
+fn lexeme_body_regex(rx: &mut RegexAst) -> Option<&mut String> {
+    match rx {
+        RegexAst::Regex(rx) => Some(rx),
+        RegexAst::Concat(parts)
+            if parts.len() == 2 && matches!(parts[1], RegexAst::LookAhead(_)) =>
+        {
+            match &mut parts[0] {
+                RegexAst::Regex(rx) => Some(rx),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+fn may_have_anchors(rx: &str) -> bool {
+    rx.contains(|c: char| c == '^' || c == '$' || c == '\\')
+}
+
+struct AnchorCheck<'a> {
+    rx: &'a str,
+    redundant: &'a [Span],
+}
+
+impl ast::Visitor for AnchorCheck<'_> {
+    type Output = ();
+    type Err = anyhow::Error;
+
+    fn finish(self) -> Result<()> {
+        Ok(())
+    }
+
+    fn visit_pre(&mut self, ast: &Ast) -> Result<()> {
+        if let Ast::Assertion(a) = ast {
+            if is_anchor(&a.kind) && !self.redundant.contains(&a.span) {
+                bail!(
+                    "anchors are only supported at the beginning and end of regex: {:?} (at offset {})",
+                    self.rx,
+                    a.span.start.offset
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Check if the regex has any `^`/`$` anchors (or their `\A`/`\z` forms).
+pub fn has_regex_anchors(rx: &str) -> bool {
+    if !may_have_anchors(rx) {
+        return false;
+    }
+    match ast::parse::Parser::new().parse(rx) {
+        Ok(parsed) => ast::visit(&parsed, AnchorCheck { rx, redundant: &[] }).is_err(),
+        Err(_) => false,
+    }
+}
+
+/// Lexemes are always matched against the whole input, so `^` at the beginning
+/// and `$` at the end of a regex are redundant and are removed.
+/// Anchors anywhere else cannot be supported and result in an error.
+pub fn strip_regex_anchors(rx: &str) -> Result<String> {
+    if !may_have_anchors(rx) {
+        return Ok(rx.to_string());
+    }
+    let parsed = match ast::parse::Parser::new().parse(rx) {
+        Ok(parsed) => parsed,
+        // leave error reporting to the regex compiler
+        Err(_) => return Ok(rx.to_string()),
+    };
+    let elements: Vec<&Ast> = match &parsed {
+        Ast::Concat(c) => c.asts.iter().collect(),
+        a => vec![a],
+    };
+
+    let mut redundant: Vec<Span> = vec![];
+    for a in elements.iter() {
+        match a {
+            Ast::Flags(_) => {}
+            Ast::Assertion(a)
+                if matches!(a.kind, AssertionKind::StartLine | AssertionKind::StartText) =>
+            {
+                redundant.push(a.span)
+            }
+            _ => break,
+        }
+    }
+    for a in elements.iter().rev() {
+        match a {
+            Ast::Assertion(a)
+                if matches!(a.kind, AssertionKind::EndLine | AssertionKind::EndText) =>
+            {
+                redundant.push(a.span)
+            }
+            _ => break,
+        }
+    }
+
+    ast::visit(
+        &parsed,
+        AnchorCheck {
+            rx,
+            redundant: &redundant,
+        },
+    )?;
+
+    redundant.sort_by_key(|s| s.start.offset);
+    let mut res = String::new();
+    let mut pos = 0;
+    for span in &redundant {
+        res.push_str(&rx[pos..span.start.offset]);
+        pos = span.end.offset;
+    }
+    res.push_str(&rx[pos..]);
+    Ok(res)
+}
+
 #[derive(Clone)]
 pub struct Lexeme {
     pub idx: LexemeIdx,
     bytes: Vec<u8>,
     hidden_len: usize,
+    // set by LexerSpec::transform_lexeme()
+    value: Option<Vec<u8>>,
 }
 
 impl Lexeme {
@@ -322,6 +1561,7 @@ impl Lexeme {
             idx,
             bytes,
             hidden_len,
+            value: None,
         }
     }
 
@@ -330,6 +1570,7 @@ impl Lexeme {
             idx,
             hidden_len: 0,
             bytes: Vec::new(),
+            value: None,
         }
     }
 
@@ -357,4 +1598,12 @@ impl Lexeme {
     pub fn hidden_bytes(&self) -> &[u8] {
         &self.bytes[self.num_visible_bytes()..]
     }
+
+    /// The visible bytes, after the lexeme transform, if any
+    /// (see `LexerSpec::set_lexeme_transform()`).
+    pub fn value(&self) -> &[u8] {
+        self.value
+            .as_deref()
+            .unwrap_or_else(|| self.visible_bytes())
+    }
 }
@@ -1,8 +1,12 @@
 mod from_guidance;
 mod grammar;
-pub(crate) mod lexer;
+pub mod lexer;
+mod lexer_dsl;
+mod numeric;
 mod parser;
+mod reverse;
 
+pub mod fixture;
 pub mod lexerspec;
 pub mod regexvec;
 
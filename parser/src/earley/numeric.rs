@@ -0,0 +1,196 @@
+// Regexes matching decimal representations of numbers in a given range.
+//
+// Only canonical representations are matched: no leading zeros
+// (except for the single "0" integer part), no '+' sign, and no "-0".
+// Floats are matched without exponent, and with an optional fractional part.
+
+use anyhow::{ensure, Result};
+use derivre::RegexAst;
+
+const FRACTION: &str = r"(\.[0-9]+)?";
+
+fn digit_range(lo: u8, hi: u8) -> String {
+    if lo == hi {
+        format!("{}", lo as char)
+    } else {
+        format!("[{}-{}]", lo as char, hi as char)
+    }
+}
+
+fn alternatives(alts: Vec<String>) -> String {
+    format!("({})", alts.join("|"))
+}
+
+/// Canonical non-negative integers greater than `s` (or equal, if `or_equal`).
+fn int_above(s: &str, or_equal: bool) -> Vec<String> {
+    let d = s.as_bytes();
+    let n = d.len();
+    let mut alts = vec![format!("[1-9][0-9]{{{},}}", n)];
+    for i in 0..n {
+        if d[i] < b'9' {
+            alts.push(format!(
+                "{}{}[0-9]{{{}}}",
+                &s[..i],
+                digit_range(d[i] + 1, b'9'),
+                n - i - 1
+            ));
+        }
+    }
+    if or_equal {
+        alts.push(s.to_string());
+    }
+    alts
+}
+
+/// Canonical non-negative integers less than `s` (or equal, if `or_equal`).
+/// The result is empty for `s == "0"` and `!or_equal`.
+fn int_below(s: &str, or_equal: bool) -> Vec<String> {
+    let d = s.as_bytes();
+    let n = d.len();
+    let mut alts = vec![];
+    if n > 1 {
+        alts.push(format!("0|[1-9][0-9]{{0,{}}}", n - 2));
+    }
+    for i in 0..n {
+        let lo = if i == 0 && n > 1 { b'1' } else { b'0' };
+        if d[i] > lo {
+            alts.push(format!(
+                "{}{}[0-9]{{{}}}",
+                &s[..i],
+                digit_range(lo, d[i] - 1),
+                n - i - 1
+            ));
+        }
+    }
+    if or_equal {
+        alts.push(s.to_string());
+    }
+    alts
+}
+
+fn int_between(lo: u64, hi: u64) -> RegexAst {
+    RegexAst::And(vec![
+        RegexAst::Regex(alternatives(int_above(&lo.to_string(), true))),
+        RegexAst::Regex(alternatives(int_below(&hi.to_string(), true))),
+    ])
+}
+
+/// Regex matching integers in `[min, max]`.
+pub fn rx_int_range(min: i64, max: i64) -> Result<RegexAst> {
+    ensure!(min <= max, "invalid integer range: [{}, {}]", min, max);
+    let mut options = vec![];
+    if min < 0 {
+        let mag_lo = if max < 0 { max.unsigned_abs() } else { 1 };
+        options.push(RegexAst::Concat(vec![
+            RegexAst::Literal("-".to_string()),
+            int_between(mag_lo, min.unsigned_abs()),
+        ]));
+    }
+    if max >= 0 {
+        options.push(int_between(std::cmp::max(min, 0) as u64, max as u64));
+    }
+    Ok(RegexAst::Or(options))
+}
+
+/// Split the (shortest) decimal representation of a non-negative float
+/// into integer and fractional part.
+/// The fractional part has no trailing zeros.
+fn split_decimal(v: f64) -> (String, String) {
+    // Display for f64 never uses the exponent notation
+    let s = v.to_string();
+    match s.split_once('.') {
+        Some((int, frac)) => (int.to_string(), frac.trim_end_matches('0').to_string()),
+        None => (s, String::new()),
+    }
+}
+
+/// Canonical non-negative decimals greater than or equal to `v`.
+fn float_at_least(v: f64) -> String {
+    let (int, frac) = split_decimal(v);
+    let frac_rx = if frac.is_empty() {
+        FRACTION.to_string()
+    } else {
+        let f = frac.as_bytes();
+        let mut alts = vec![];
+        for i in 0..f.len() {
+            if f[i] < b'9' {
+                alts.push(format!(
+                    "{}{}[0-9]*",
+                    &frac[..i],
+                    digit_range(f[i] + 1, b'9')
+                ));
+            }
+        }
+        alts.push(format!("{}[0-9]*", frac));
+        format!(r"\.{}", alternatives(alts))
+    };
+    format!(
+        "{}{}|{}{}",
+        alternatives(int_above(&int, false)),
+        FRACTION,
+        int,
+        frac_rx
+    )
+}
+
+/// Canonical non-negative decimals less than or equal to `v`.
+fn float_at_most(v: f64) -> String {
+    let (int, frac) = split_decimal(v);
+    let frac_rx = if frac.is_empty() {
+        r"(\.0+)?".to_string()
+    } else {
+        let f = frac.as_bytes();
+        let mut alts = vec![];
+        for i in 0..f.len() {
+            if f[i] > b'0' {
+                alts.push(format!(
+                    "{}{}[0-9]*",
+                    &frac[..i],
+                    digit_range(b'0', f[i] - 1)
+                ));
+            }
+            if i > 0 {
+                // shorter fraction, which is smaller since the bound has no trailing zeros
+                alts.push(frac[..i].to_string());
+            }
+        }
+        alts.push(format!("{}0*", frac));
+        format!(r"(\.{})?", alternatives(alts))
+    };
+    let below = int_below(&int, false);
+    if below.is_empty() {
+        format!("{}{}", int, frac_rx)
+    } else {
+        format!("{}{}|{}{}", alternatives(below), FRACTION, int, frac_rx)
+    }
+}
+
+/// Regex matching decimal numbers in `[min, max]` (no exponent notation).
+pub fn rx_float_range(min: f64, max: f64) -> Result<RegexAst> {
+    ensure!(
+        min.is_finite() && max.is_finite() && min <= max,
+        "invalid float range: [{}, {}]",
+        min,
+        max
+    );
+    let mut options = vec![];
+    if min < 0.0 {
+        let magnitude = if max < 0.0 {
+            RegexAst::Regex(float_at_least(-max))
+        } else {
+            // exclude "-0", "-0.0", etc.
+            RegexAst::Not(Box::new(RegexAst::Regex(r"0(\.0+)?".to_string())))
+        };
+        options.push(RegexAst::Concat(vec![
+            RegexAst::Literal("-".to_string()),
+            RegexAst::And(vec![magnitude, RegexAst::Regex(float_at_most(-min))]),
+        ]));
+    }
+    if max >= 0.0 {
+        options.push(RegexAst::And(vec![
+            RegexAst::Regex(float_at_least(if min > 0.0 { min } else { 0.0 })),
+            RegexAst::Regex(float_at_most(max)),
+        ]));
+    }
+    Ok(RegexAst::Or(options))
+}
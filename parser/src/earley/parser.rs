@@ -25,7 +25,7 @@ use crate::{
 
 use super::{
     grammar::{CGrammar, CSymIdx, CSymbol, RuleIdx},
-    lexer::{LexerResult, PreLexeme},
+    lexer::{InputFilter, LexerResult, PreLexeme, WarmupMode},
     lexerspec::{Lexeme, LexemeIdx, LexerSpec},
 };
 
@@ -61,6 +61,8 @@ pub struct ParserStats {
     pub num_lex_errors: usize,
     pub num_lexemes: usize,
     pub all_items: usize,
+    // see ParserLimits::max_live_interpretations
+    pub pruned_items: usize,
 
     pub lexer_cost: u64,
 }
@@ -74,6 +76,7 @@ impl ParserStats {
             num_lexemes: self.num_lexemes - previous.num_lexemes,
             num_lex_errors: self.num_lex_errors - previous.num_lex_errors,
             all_items: self.all_items - previous.all_items,
+            pruned_items: self.pruned_items - previous.pruned_items,
 
             lexer_cost: self.lexer_cost - previous.lexer_cost,
         }
@@ -93,6 +96,15 @@ struct Row {
     // will accept in the next row.  They are all and only those lexemes
     // which can lead to a successful parse.
     allowed_lexemes: SimpleVob,
+
+    // Number of occurrences of lexemes with LexerSpec::max_occurrences
+    // so far, indexed like max_occurrences (so usually empty).
+    occurrences: Vec<u32>,
+
+    // Number of items at the end of the row that were pruned
+    // (see ParserLimits::max_live_interpretations); they are only
+    // scanned if none of the other items accepts the lexeme.
+    num_pruned: usize,
 }
 
 impl Row {
@@ -223,6 +235,9 @@ struct ParserState {
     trie_gen_grammar: Option<CSymIdx>,
     trie_gen_grammar_accepting: bool,
     limits: ParserLimits,
+    lexeme_coverage: SimpleVob,
+    // LexerSpec::allowed_after_eos() without SKIP
+    trailing_lexemes: SimpleVob,
 }
 
 #[derive(Clone)]
@@ -261,11 +276,18 @@ impl Scratch {
 
     // Add a new row to the Earley table.  It will be the
     // current, working, row.
-    fn work_row(&self, allowed_lexemes: SimpleVob) -> Row {
+    fn work_row(
+        &self,
+        allowed_lexemes: SimpleVob,
+        occurrences: Vec<u32>,
+        num_pruned: usize,
+    ) -> Row {
         Row {
             first_item: self.row_start,
             last_item: self.row_end,
             allowed_lexemes,
+            occurrences,
+            num_pruned,
         }
     }
 
@@ -346,6 +368,9 @@ impl ParserState {
         let mut lexer = Lexer::from(grammar.lexer_spec(), &mut limits)?;
         let scratch = Scratch::new(Arc::clone(&grammar));
         let lexer_state = lexer.a_dead_state(); // placeholder
+        let lexeme_coverage = grammar.lexer_spec().alloc_lexeme_set();
+        let mut trailing_lexemes = grammar.lexer_spec().allowed_after_eos();
+        trailing_lexemes.set(LexemeIdx::SKIP.as_usize(), false);
         let mut r = ParserState {
             grammar,
             trie_lexer_stack: usize::MAX,
@@ -361,6 +386,8 @@ impl ParserState {
             trie_gen_grammar: None,
             trie_gen_grammar_accepting: false,
             limits,
+            lexeme_coverage,
+            trailing_lexemes,
             lexer_stack: vec![LexerState {
                 row_idx: 0,
                 lexer_state,
@@ -452,6 +479,10 @@ impl ParserState {
     }
 
     fn can_advance_inner(&self) -> bool {
+        // trailing lexemes can follow once the grammar is complete
+        if !self.trailing_lexemes.is_zero() && self.row_is_accepting() {
+            return true;
+        }
         for data in self.after_dots_symdata() {
             if data.lexeme == Some(LexemeIdx::SKIP) || data.idx == CSymIdx::NULL {
                 continue;
@@ -776,6 +807,8 @@ impl ParserState {
 
         for idx in 0..self.num_rows() {
             let range = self.rows[idx].item_indices();
+            let pruned_start = range.end - self.rows[idx].num_pruned;
+            let mut num_pruned = 0;
             self.rows[idx].first_item = dst;
             for i in range {
                 let item = self.scratch.items[i];
@@ -793,10 +826,14 @@ impl ParserState {
                         continue;
                     }
                 }
+                if i >= pruned_start {
+                    num_pruned += 1;
+                }
                 self.scratch.items[dst] = item;
                 dst += 1;
             }
             self.rows[idx].last_item = dst;
+            self.rows[idx].num_pruned = num_pruned;
         }
 
         self.row_infos.pop();
@@ -1062,8 +1099,23 @@ impl ParserState {
 
     // this just copies current row
     fn scan_skip_lexeme(&mut self, lexeme: &Lexeme) -> bool {
-        let src = self.curr_row().item_indices();
         let allowed_lexemes = self.curr_row().allowed_lexemes.clone();
+        self.copy_row(lexeme, allowed_lexemes)
+    }
+
+    fn is_trailing_lexeme(&self, lexeme: &Lexeme) -> bool {
+        self.trailing_lexemes.get(lexeme.idx.as_usize()) && self.row_is_accepting()
+    }
+
+    // like SKIP, but after it only trailing lexemes are allowed
+    fn scan_trailing_lexeme(&mut self, lexeme: &Lexeme) -> bool {
+        let mut allowed_lexemes = self.trailing_lexemes.clone();
+        allowed_lexemes.set(LexemeIdx::SKIP.as_usize(), true);
+        self.copy_row(lexeme, allowed_lexemes)
+    }
+
+    fn copy_row(&mut self, lexeme: &Lexeme, allowed_lexemes: SimpleVob) -> bool {
+        let src = self.curr_row().item_indices();
         let n = src.len();
         if n == 0 {
             return false;
@@ -1102,9 +1154,10 @@ impl ParserState {
     // and debugging (lexeme.idx used always)
     fn scan(&mut self, lexeme: &Lexeme) -> bool {
         let row_idx = self.num_rows() - 1;
+        let first = self.rows[row_idx].first_item;
         let last = self.rows[row_idx].last_item;
-        let mut i = self.rows[row_idx].first_item;
-        let n = last - i;
+        let pruned_start = last - self.rows[row_idx].num_pruned;
+        let n = last - first;
         self.scratch.ensure_items(last + n + 100);
         self.scratch.new_row(last);
 
@@ -1121,20 +1174,86 @@ impl ParserState {
         // (slide 21 of Kallmeyer 2018).  It is an
         // initialization inference rule, performed "just
         // in time" at the beginning of the creation of
-        // each row
-        while i < last {
-            let item = self.scratch.items[i];
-            let sym = self.grammar.sym_data_dot(item.rule_idx());
-            if sym.lexeme == Some(lexeme.idx) {
-                self.scratch.just_add(item.advance_dot(), i, "scan");
+        // each row.
+        // The pruned items are only tried when none of the others matched.
+        for range in [first..pruned_start, pruned_start..last] {
+            if self.scratch.row_len() > 0 {
+                break;
+            }
+            for i in range {
+                let item = self.scratch.items[i];
+                let sym = self.grammar.sym_data_dot(item.rule_idx());
+                if sym.lexeme == Some(lexeme.idx) {
+                    self.scratch.just_add(item.advance_dot(), i, "scan");
+                }
             }
-            i += 1;
         }
 
         // Perform the other inference rules on this Earley set.
         self.push_row(self.num_rows(), self.scratch.row_start, lexeme)
     }
 
+    // Update occurrence counts with the lexeme leading to row 'curr_idx',
+    // and disallow lexemes that reached their max_occurrences.
+    fn count_occurrences(
+        &self,
+        curr_idx: usize,
+        lexeme: &Lexeme,
+        allowed_lexemes: &mut SimpleVob,
+    ) -> Vec<u32> {
+        let caps = &self.lexer_spec().max_occurrences;
+        if caps.is_empty() {
+            return vec![];
+        }
+        let mut occurrences = if curr_idx > 0 {
+            self.rows[curr_idx - 1].occurrences.clone()
+        } else {
+            vec![0; caps.len()]
+        };
+        for (i, &(lx, max)) in caps.iter().enumerate() {
+            if curr_idx > 0 && lexeme.idx == lx {
+                occurrences[i] += 1;
+            }
+            if occurrences[i] as usize >= max {
+                allowed_lexemes.set(lx.as_usize(), false);
+            }
+        }
+        occurrences
+    }
+
+    // Move the items of the working row waiting for a lexeme, beyond
+    // ParserLimits::max_live_interpretations, to the end of the row,
+    // and return their number.
+    // The shortest matches (items that started last) are pruned first;
+    // among items that started at the same row, the ones with the most
+    // symbols left in their rule.
+    fn prune_interpretations(&mut self) -> usize {
+        let max = self.limits.max_live_interpretations.max(1);
+        if max == usize::MAX || self.scratch.row_len() <= max {
+            return 0;
+        }
+        let grammar = &self.grammar;
+        let row = self.scratch.row_start..self.scratch.row_end;
+        let (mut waiting, mut items): (Vec<Item>, Vec<Item>) = self.scratch.items[row.clone()]
+            .iter()
+            .partition(|item| grammar.sym_data_dot(item.rule_idx()).lexeme.is_some());
+        if waiting.len() <= max {
+            return 0;
+        }
+        waiting.sort_by_key(|item| {
+            let (rhs, dot) = grammar.rule_rhs(item.rule_idx());
+            (item.start_pos(), rhs.len() - dot)
+        });
+        let num_pruned = waiting.len() - max;
+        items.extend_from_slice(&waiting);
+        self.scratch.items[row].copy_from_slice(&items);
+        if self.scratch.definitive {
+            debug!("  pruned {} interpretations", num_pruned);
+        }
+        self.stats.pruned_items += num_pruned;
+        num_pruned
+    }
+
     // push_row() does the agenda processing.  There is an agenda for
     // each Earley set (aka row).
 
@@ -1146,6 +1265,7 @@ impl ParserState {
     fn push_row(&mut self, curr_idx: usize, mut agenda_ptr: usize, lexeme: &Lexeme) -> bool {
         let mut allowed_lexemes = self.lexer_spec().alloc_lexeme_set();
         let mut max_tokens = vec![];
+        let mut accepting = false;
 
         // Agenda retrieval is a simplification of Kallmeyer 2018.
         // There is no separate data structure for the agenda --
@@ -1169,6 +1289,7 @@ impl ParserState {
             if after_dot == CSymIdx::NULL {
                 let flags = self.grammar.sym_flags_lhs(rule);
                 let lhs = self.grammar.sym_idx_lhs(rule);
+                accepting |= lhs == self.grammar.start();
 
                 if self.scratch.definitive && flags.stop_capture() {
                     let var_name = self
@@ -1265,6 +1386,13 @@ impl ParserState {
 
             // Always accept a SKIP lexeme
             allowed_lexemes.set(LexemeIdx::SKIP.as_usize(), true);
+            // Trailing lexemes are accepted once the grammar is complete
+            if accepting {
+                allowed_lexemes.or(&self.trailing_lexemes);
+            }
+
+            let occurrences = self.count_occurrences(curr_idx, lexeme, &mut allowed_lexemes);
+            let num_pruned = self.prune_interpretations();
 
             if self.scratch.definitive {
                 debug!(
@@ -1275,7 +1403,9 @@ impl ParserState {
 
             // Add the working row to the parser state
             let idx = self.num_rows();
-            let row = self.scratch.work_row(allowed_lexemes);
+            let row = self
+                .scratch
+                .work_row(allowed_lexemes, occurrences, num_pruned);
             if self.rows.len() == 0 || self.rows.len() == idx {
                 self.rows.push(row);
             } else {
@@ -1368,7 +1498,8 @@ impl ParserState {
             bytes.push(byte.unwrap());
         }
 
-        Lexeme::new(pre_lexeme.idx, bytes, pre_lexeme.hidden_len)
+        let lexeme = Lexeme::new(pre_lexeme.idx, bytes, pre_lexeme.hidden_len);
+        self.lexer_spec().transform_lexeme(lexeme)
     }
 
     fn has_forced_bytes(&self, allowed_lexemes: &SimpleVob, bytes: &[u8]) -> bool {
@@ -1530,11 +1661,17 @@ impl ParserState {
             self.scan_skip_lexeme(&lexeme)
         } else {
             // For all but the SKIP lexeme, process this lexeme
-            // with the parser
+            // with the parser; if the grammar is complete and doesn't
+            // need it, it can still be a trailing lexeme
             self.scan(&lexeme)
+                || (self.is_trailing_lexeme(&lexeme) && self.scan_trailing_lexeme(&lexeme))
         };
 
         if scan_res {
+            if self.scratch.definitive {
+                self.lexeme_coverage.set(lexeme_idx.as_usize(), true);
+            }
+
             let mut no_hidden = self.lexer_state_for_added_row(shared, lexeme, transition_byte);
 
             if pre_lexeme.hidden_len > 0 {
@@ -1740,6 +1877,12 @@ impl Parser {
         &self.state.stats
     }
 
+    /// Lexemes (including SKIP) emitted so far; compare with
+    /// `LexerSpec::all_lexemes()` to find lexemes not exercised by the input.
+    pub fn lexeme_coverage(&self) -> &SimpleVob {
+        &self.state.lexeme_coverage
+    }
+
     pub(crate) fn take_global_state_from(&mut self, other: &mut Parser) {
         self.state.stats = other.state.stats.clone();
         self.state.captures = std::mem::take(&mut other.state.captures);
@@ -1759,6 +1902,27 @@ impl Parser {
         shared.lexer.dfa.stats()
     }
 
+    /// See `Lexer::warmup()`.
+    pub fn warmup_lexer(&mut self, sample_inputs: &[&[u8]], mode: WarmupMode) -> usize {
+        let mut shared = self.shared.lock().unwrap();
+        shared.lexer.warmup(sample_inputs, mode)
+    }
+
+    /// See `Lexer::set_input_filter()`; the lexer sees the bytes of tokens,
+    /// so only filters with a byte map can be used.
+    pub fn set_input_filter(&mut self, filter: Option<InputFilter>) -> Result<()> {
+        if let Some(f) = &filter {
+            ensure!(
+                f.byte_map().is_some(),
+                "input filter {:?} changes the length of the input; it can't be used by the parser",
+                f
+            );
+        }
+        let mut shared = self.shared.lock().unwrap();
+        shared.lexer.set_input_filter(filter);
+        Ok(())
+    }
+
     pub fn lexer_error(&self) -> Option<String> {
         let shared = self.shared.lock().unwrap();
         shared.lexer.dfa.get_error()
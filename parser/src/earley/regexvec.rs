@@ -1,6 +1,6 @@
 use anyhow::{bail, Result};
 use derivre::raw::{DerivCache, ExprSet, NextByteCache, RelevanceCache, VecHashCons};
-use std::{fmt::Debug, u64};
+use std::{fmt::Debug, sync::Arc, u64};
 use toktrie::SimpleVob;
 
 pub use derivre::{AlphabetInfo, ExprRef, NextByte, StateID};
@@ -23,8 +23,12 @@ pub struct RegexVec {
     num_ast_nodes: usize,
     max_states: usize,
     fuel: u64,
+    on_state_realized: Option<Arc<dyn Fn(StateID) + Send + Sync>>,
 }
 
+/// Lexeme sets are bit vectors indexed by lexeme, so iterating them
+/// (eg., with `iter()` or `first_bit_set()`) always gives ascending indices,
+/// independent of the platform or the order in which states were computed.
 #[derive(Clone, Debug)]
 pub struct StateDesc {
     pub state: StateID,
@@ -39,6 +43,27 @@ pub struct StateDesc {
 }
 
 impl StateDesc {
+    /// Create a state description; other fields are computed lazily by RegexVec,
+    /// and are not used by other DfaBackend implementations.
+    pub fn new(
+        state: StateID,
+        lowest_accepting: Option<usize>,
+        accepting: SimpleVob,
+        possible: SimpleVob,
+        lowest_match: Option<(usize, usize)>,
+    ) -> Self {
+        StateDesc {
+            state,
+            lowest_accepting,
+            accepting,
+            possible,
+            lowest_match,
+            possible_lookahead_len: None,
+            lookahead_len: None,
+            next_byte: None,
+        }
+    }
+
     pub fn is_accepting(&self) -> bool {
         self.lowest_accepting.is_some()
     }
@@ -120,6 +145,17 @@ impl RegexVec {
         }
     }
 
+    /// Like `transition()`, but only if the transition was already computed.
+    #[inline(always)]
+    pub fn cached_transition(&self, state: StateID, b: u8) -> Option<StateID> {
+        let new_state = self.state_table[self.alpha.map_state(state, b)];
+        if new_state != StateID::MISSING {
+            Some(new_state)
+        } else {
+            None
+        }
+    }
+
     pub fn subsume_possible(&mut self, state: StateID) -> bool {
         if state.is_dead() || self.has_error() {
             return false;
@@ -266,6 +302,11 @@ impl RegexVec {
         self.exprs.cost()
     }
 
+    /// Set a hook called whenever a new state is realized (eg., for profiling).
+    pub fn set_on_state_realized(&mut self, f: Option<Arc<dyn Fn(StateID) + Send + Sync>>) {
+        self.on_state_realized = f;
+    }
+
     pub fn set_max_states(&mut self, max_states: usize) {
         if !self.has_error() {
             self.max_states = max_states;
@@ -284,6 +325,27 @@ impl RegexVec {
         self.alpha.has_error()
     }
 
+    /// Ids of all states realized so far, including DEAD;
+    /// the placeholder for MISSING (a copy of DEAD) is skipped.
+    pub fn state_ids(&self) -> impl Iterator<Item = StateID> + '_ {
+        self.state_descs
+            .iter()
+            .enumerate()
+            .filter(|(idx, _)| *idx != StateID::MISSING.as_usize())
+            .map(|(_, desc)| desc.state)
+    }
+
+    /// Number of states realized so far (states are computed lazily),
+    /// including DEAD, but not the MISSING placeholder.
+    pub fn num_states(&self) -> usize {
+        self.state_descs.len() - 1
+    }
+
+    /// Check if the error (if any) is due to exceeding `max_states`.
+    pub fn has_too_many_states(&self) -> bool {
+        self.has_error() && self.fuel != 0 && self.state_descs.len() >= self.max_states
+    }
+
     pub fn get_error(&self) -> Option<String> {
         if self.has_error() {
             if self.fuel == 0 {
@@ -384,6 +446,7 @@ impl RegexVec {
             num_ast_nodes,
             fuel: u64::MAX,
             max_states: usize::MAX,
+            on_state_realized: None,
         };
 
         assert!(r.lazy.len() == r.rx_list.len());
@@ -413,16 +476,23 @@ impl RegexVec {
         // }
         assert!(lst.len() % 2 == 0);
         let id = StateID::new(self.rx_sets.insert(&lst));
-        if id.as_usize() >= self.state_descs.len() {
+        let is_new = id.as_usize() >= self.state_descs.len();
+        if is_new {
             let mut state_desc = self.compute_state_desc(id);
             state_desc.lowest_match = self.lowest_match_inner(id);
             self.append_state(state_desc);
         }
-        if self.state_desc(id).lowest_match.is_some() {
+        let id = if self.state_desc(id).lowest_match.is_some() {
             id._set_lowest_match()
         } else {
             id
+        };
+        if is_new {
+            if let Some(f) = &self.on_state_realized {
+                f(id);
+            }
         }
+        id
     }
 
     fn compute_state_desc(&self, state: StateID) -> StateDesc {
@@ -0,0 +1,77 @@
+// Reversal of regexes, used to match lexemes against input fed backwards.
+//
+// Lexemes are reversed from their compiled expressions, so that regexes given
+// as strings are parsed once, with the settings of the regex builder of the spec,
+// and expressions compiled beforehand (as for `rx_nodes` of a grammar) are
+// handled like any other regex. Expressions are over bytes, and so is the reversal.
+// Lookaheads have no reversed counterpart and are rejected.
+
+use anyhow::{bail, Result};
+use derivre::{
+    raw::{Expr, ExprSet},
+    ExprRef, RegexAst, RegexBuilder,
+};
+use rustc_hash::FxHashMap;
+
+/// Reverses expressions of an `ExprSet` into a regex builder.
+/// Results are cached, since expressions share sub-expressions.
+pub(crate) struct Reverser<'a> {
+    exprs: &'a ExprSet,
+    cache: FxHashMap<ExprRef, ExprRef>,
+}
+
+impl<'a> Reverser<'a> {
+    pub fn new(exprs: &'a ExprSet) -> Self {
+        Reverser {
+            exprs,
+            cache: FxHashMap::default(),
+        }
+    }
+
+    /// Expression in `builder` matching the byte-wise reversals of strings matched by `e`.
+    pub fn reverse(&mut self, builder: &mut RegexBuilder, e: ExprRef) -> Result<ExprRef> {
+        if let Some(r) = self.cache.get(&e) {
+            return Ok(*r);
+        }
+        let exprs = self.exprs;
+        let rx = match exprs.get(e) {
+            Expr::EmptyString => RegexAst::EmptyString,
+            Expr::NoMatch => RegexAst::NoMatch,
+            Expr::Byte(b) => RegexAst::Byte(b),
+            Expr::ByteSet(bits) => RegexAst::ByteSet(bits.to_vec()),
+            Expr::ByteConcat(_, bytes, tail) => RegexAst::Concat(vec![
+                self.reverse_ref(builder, tail)?,
+                RegexAst::ByteLiteral(bytes.iter().rev().copied().collect()),
+            ]),
+            // reversal is a bijection on strings, so it commutes with complement
+            Expr::Not(_, inner) => RegexAst::Not(Box::new(self.reverse_ref(builder, inner)?)),
+            Expr::Repeat(_, inner, min, max) => {
+                RegexAst::Repeat(Box::new(self.reverse_ref(builder, inner)?), min, max)
+            }
+            Expr::Concat(_, args) => RegexAst::Concat(
+                args.iter()
+                    .rev()
+                    .map(|&a| self.reverse_ref(builder, a))
+                    .collect::<Result<_>>()?,
+            ),
+            Expr::Or(_, args) => RegexAst::Or(
+                args.iter()
+                    .map(|&a| self.reverse_ref(builder, a))
+                    .collect::<Result<_>>()?,
+            ),
+            Expr::And(_, args) => RegexAst::And(
+                args.iter()
+                    .map(|&a| self.reverse_ref(builder, a))
+                    .collect::<Result<_>>()?,
+            ),
+            _ => bail!("lookahead cannot be reversed"),
+        };
+        let r = builder.mk(&rx)?;
+        self.cache.insert(e, r);
+        Ok(r)
+    }
+
+    fn reverse_ref(&mut self, builder: &mut RegexBuilder, e: ExprRef) -> Result<RegexAst> {
+        Ok(RegexAst::ExprRef(self.reverse(builder, e)?))
+    }
+}
@@ -4,10 +4,15 @@ use std::{
 };
 
 use anyhow::{bail, ensure, Result};
-use toktrie::{InferenceCapabilities, TokEnv, TokRxInfo, TokTrie, TokenizerEnv};
+use toktrie::{InferenceCapabilities, SimpleVob, TokEnv, TokRxInfo, TokTrie, TokenizerEnv};
 
 use crate::{
     api::{ParserLimits, RegexNode, TopLevelGrammar},
+    earley::{
+        lexer::{Lexer, LexerResult, PreLexeme, StateID},
+        lexerspec::{LexemeIdx, LexerSpec},
+    },
+    gbnf::parse_gbnf,
     lark::{lark_to_llguidance, parse_lark},
     CommitResult, Constraint, JsonCompileOptions, Logger, TokenParser,
 };
@@ -283,6 +288,14 @@ fn new_constraint_lark(init: &LlgConstraintInit, lark: *const c_char) -> Result<
     new_constraint_core(init, grammar)
 }
 
+fn new_constraint_gbnf(init: &LlgConstraintInit, gbnf: *const c_char) -> Result<Constraint> {
+    let gbnf = unsafe { CStr::from_ptr(gbnf) }
+        .to_str()
+        .map_err(|_| anyhow::anyhow!("Invalid UTF-8 in gbnf"))?;
+    let grammar = parse_gbnf(gbnf)?;
+    new_constraint_core(init, grammar)
+}
+
 fn new_constraint_json(init: &LlgConstraintInit, json_schema: *const c_char) -> Result<Constraint> {
     let json_schema = unsafe { CStr::from_ptr(json_schema) }
         .to_str()
@@ -317,6 +330,7 @@ fn new_constraint_any(
         "regex" => new_constraint_regex(init, data),
         "json" | "json_schema" => new_constraint_json(init, data),
         "lark" => new_constraint_lark(init, data),
+        "gbnf" => new_constraint_gbnf(init, data),
         "llguidance" | "guidance" => new_constraint_lark(init, data),
         _ => bail!("unknown constraint type: {tp}"),
     }
@@ -438,7 +452,7 @@ pub extern "C" fn llg_new_constraint_lark(
 }
 
 /// Create a new constraint with specified type
-/// Type can be one of "regex", "json_schema" (or "json"), "lark", "llguidance" (or "guidance")
+/// Type can be one of "regex", "json_schema" (or "json"), "lark", "gbnf", "llguidance" (or "guidance")
 /// Always returns a non-null value. Call llg_get_error() on the result to check for errors.
 #[no_mangle]
 pub extern "C" fn llg_new_constraint_any(
@@ -629,3 +643,237 @@ pub extern "C" fn llg_flush_logs(cc: &mut LlgConstraint) -> *const c_char {
     }
     cc.last_logs.as_ptr() as *const c_char
 }
+
+pub struct LlgLexer {
+    local_error: Option<String>,
+    cursor: Option<LexerCursor>,
+}
+
+struct LexerCursor {
+    lexer: Lexer,
+    allowed: SimpleVob,
+    state: StateID,
+    // were any bytes fed since the last lexeme?
+    pending: bool,
+}
+
+/// Represents result from llg_lexer_advance() and llg_lexer_finish()
+#[repr(C)]
+pub struct LlgLexerResult {
+    /// 0 - the byte was consumed, and no lexeme ended yet
+    /// 1 - a lexeme ended (described by the fields below)
+    /// -1 - the byte (or end of input) is not allowed here
+    pub kind: i32,
+    /// The lexeme that ended; see llg_lexer_lexeme_name()
+    pub lexeme_idx: u32,
+    /// The number of bytes at the end of the lexeme matched by its stop (lookahead)
+    pub hidden_len: u32,
+    /// Does the byte passed to llg_lexer_advance() belong to the next lexeme?
+    /// If so, it was already fed to the next lexeme.
+    pub byte_next_row: bool,
+}
+
+impl LlgLexerResult {
+    fn new(kind: i32) -> Self {
+        LlgLexerResult {
+            kind,
+            lexeme_idx: 0,
+            hidden_len: 0,
+            byte_next_row: false,
+        }
+    }
+
+    fn from_lexeme(p: &PreLexeme) -> Self {
+        LlgLexerResult {
+            kind: 1,
+            lexeme_idx: p.idx.as_usize() as u32,
+            hidden_len: p.hidden_len as u32,
+            byte_next_row: p.byte_next_row,
+        }
+    }
+}
+
+fn new_lexer_cursor(spec: *const c_char) -> Result<LexerCursor> {
+    let spec = unsafe { CStr::from_ptr(spec) }
+        .to_str()
+        .map_err(|_| anyhow::anyhow!("Invalid UTF-8 in lexer spec"))?;
+    let spec = LexerSpec::parse_dsl(spec)?;
+    let mut lexer = Lexer::from(&spec, &mut ParserLimits::default())?;
+    let allowed = spec.all_lexemes();
+    // llg_lexer_finish() ends any accepting lexeme, so allows_eos() should agree
+    lexer.precompute_eos(&allowed);
+    let state = lexer.start_state(&allowed, None);
+    Ok(LexerCursor {
+        lexer,
+        allowed,
+        state,
+        pending: false,
+    })
+}
+
+impl LlgLexer {
+    fn get_error_code(&self) -> i32 {
+        if self.local_error.is_some() {
+            -1
+        } else {
+            0
+        }
+    }
+
+    fn set_error(&mut self, e: &str) {
+        self.cursor = None;
+        self.local_error = Some(format!("{e}\0"));
+    }
+
+    fn write_result(&mut self, r: LexerResult, res_p: *mut LlgLexerResult) -> i32 {
+        if let Some(c) = &mut self.cursor {
+            let res = match r {
+                LexerResult::State(s, _) => {
+                    c.state = s;
+                    c.pending = true;
+                    LlgLexerResult::new(0)
+                }
+                LexerResult::Lexeme(p) => {
+                    let transition_byte = if p.byte_next_row { p.byte } else { None };
+                    c.state = c.lexer.start_state(&c.allowed, transition_byte);
+                    c.pending = transition_byte.is_some();
+                    LlgLexerResult::from_lexeme(&p)
+                }
+                LexerResult::Error => {
+                    if let Err(e) = c.lexer.check_error() {
+                        self.set_error(&e.to_string());
+                        return self.get_error_code();
+                    }
+                    LlgLexerResult::new(-1)
+                }
+            };
+            unsafe { *res_p = res };
+        }
+        self.get_error_code()
+    }
+}
+
+/// Create a new lexer from a spec in the lexer DSL format.
+/// Each line defines a lexeme, as in `ID: /[a-z]+/` or `KW_IF: "if"`;
+/// `SKIP: /[ \t]+/` defines the whitespace to skip.
+/// All lexemes are allowed everywhere.
+/// Always returns a non-null value. Call llg_lexer_get_error() on the result to check for errors.
+#[no_mangle]
+pub extern "C" fn llg_new_lexer(spec: *const c_char) -> *mut LlgLexer {
+    let mut res = LlgLexer {
+        local_error: None,
+        cursor: None,
+    };
+    match new_lexer_cursor(spec) {
+        Ok(cursor) => res.cursor = Some(cursor),
+        Err(e) => res.set_error(&e.to_string()),
+    }
+    Box::into_raw(Box::new(res))
+}
+
+/// Get the error message from the lexer or null if there is no error.
+/// After it returns a non-null value, it will always return it until the lexer is freed
+/// using llg_free_lexer() (at which point the pointer will be invalid).
+#[no_mangle]
+pub extern "C" fn llg_lexer_get_error(lx: &LlgLexer) -> *const c_char {
+    match &lx.local_error {
+        Some(e) => e.as_ptr() as *const c_char,
+        None => std::ptr::null(),
+    }
+}
+
+/// Move the lexer to the start state, before the first byte of a lexeme.
+/// This is done automatically when the lexer is created and after each lexeme.
+/// Returns 0 on success and -1 on error (use llg_lexer_get_error() to get the exact error).
+#[no_mangle]
+pub extern "C" fn llg_lexer_start_state(lx: &mut LlgLexer) -> i32 {
+    if let Some(c) = &mut lx.cursor {
+        c.state = c.lexer.start_state(&c.allowed, None);
+        c.pending = false;
+    }
+    lx.get_error_code()
+}
+
+/// Feed the next byte of input to the lexer.
+/// After a lexeme ends, the lexer starts the next one.
+/// After kind -1 is returned, use llg_lexer_start_state() to continue.
+/// Returns 0 on success and -1 on error (use llg_lexer_get_error() to get the exact error).
+/// When 0 is returned, the result is written to *res_p.
+#[no_mangle]
+pub extern "C" fn llg_lexer_advance(
+    lx: &mut LlgLexer,
+    byte: u8,
+    res_p: *mut LlgLexerResult,
+) -> i32 {
+    let r = match &mut lx.cursor {
+        Some(c) if c.state.is_dead() => LexerResult::Error,
+        Some(c) => c.lexer.advance(c.state, byte, false),
+        None => return lx.get_error_code(),
+    };
+    lx.write_result(r, res_p)
+}
+
+/// Check if the input can end at the current position,
+/// that is if llg_lexer_finish() would return a lexeme.
+#[no_mangle]
+pub extern "C" fn llg_lexer_allows_eos(lx: &mut LlgLexer) -> bool {
+    match &mut lx.cursor {
+        Some(c) => !c.state.is_dead() && c.lexer.allows_eos(c.state),
+        None => false,
+    }
+}
+
+/// End the input, finishing the current lexeme if any bytes were fed for it
+/// (otherwise kind 0 is returned).
+/// Returns 0 on success and -1 on error (use llg_lexer_get_error() to get the exact error).
+/// When 0 is returned, the result is written to *res_p.
+#[no_mangle]
+pub extern "C" fn llg_lexer_finish(lx: &mut LlgLexer, res_p: *mut LlgLexerResult) -> i32 {
+    let r = match &mut lx.cursor {
+        Some(c) if c.state.is_dead() => LexerResult::Error,
+        Some(c) if !c.pending => {
+            unsafe { *res_p = LlgLexerResult::new(0) };
+            return 0;
+        }
+        Some(c) => c.lexer.finish_at_eos(c.state, true),
+        None => return lx.get_error_code(),
+    };
+    lx.write_result(r, res_p)
+}
+
+/// Return the name of the lexeme with the given index.
+/// The output is null-terminated.
+/// Returns the number of bytes that would be written to output if output_len was large enough.
+#[no_mangle]
+pub extern "C" fn llg_lexer_lexeme_name(
+    lx: &LlgLexer,
+    lexeme_idx: u32,
+    output: *mut c_char,
+    output_len: usize,
+) -> usize {
+    let name = match &lx.cursor {
+        Some(c) if (lexeme_idx as usize) < c.allowed.len() => c
+            .lexer
+            .lexer_spec()
+            .lexeme_spec(LexemeIdx::new(lexeme_idx as usize))
+            .name(),
+        _ => "",
+    };
+    let s = name.as_bytes();
+    if output_len > 0 {
+        let len = std::cmp::min(s.len(), output_len - 1);
+        unsafe {
+            std::ptr::copy_nonoverlapping(s.as_ptr(), output as *mut u8, len);
+            *output.add(len) = 0;
+        }
+    }
+    s.len() + 1
+}
+
+/// Free the lexer
+#[no_mangle]
+pub extern "C" fn llg_free_lexer(lx: *mut LlgLexer) {
+    unsafe {
+        drop(Box::from_raw(lx));
+    }
+}
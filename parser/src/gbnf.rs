@@ -0,0 +1,381 @@
+// Import of GBNF grammars (as used by llama.cpp).
+//
+// The GBNF source is parsed into the Lark AST, which is then compiled
+// with the Lark compiler. String literals and character classes become
+// lexemes, while GBNF rules become grammar rules.
+
+use anyhow::{anyhow, bail, ensure, Result};
+
+use crate::{
+    api::TopLevelGrammar,
+    lark::{ast::*, lark_to_llguidance, lexer::Location},
+};
+
+/// Parse a GBNF grammar and convert it to the llguidance format.
+/// The `root` rule is used as the start symbol.
+pub fn parse_gbnf(src: &str) -> Result<TopLevelGrammar> {
+    let items = parse_gbnf_items(src)?;
+    lark_to_llguidance(items)
+}
+
+fn parse_gbnf_items(src: &str) -> Result<Vec<Item>> {
+    let mut parser = Parser {
+        chars: src.chars().collect(),
+        pos: 0,
+        line: 1,
+        column: 1,
+    };
+    parser
+        .parse_grammar()
+        .map_err(|e| anyhow!("{}({}): {}", parser.line, parser.column, e))
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    line: usize,
+    column: usize,
+}
+
+impl Parser {
+    fn parse_grammar(&mut self) -> Result<Vec<Item>> {
+        let mut items = Vec::new();
+        let mut has_root = false;
+        self.skip_ws();
+        while !self.is_at_end() {
+            let rule = self.parse_rule()?;
+            has_root |= rule.name == "start";
+            items.push(Item::Rule(rule));
+            self.skip_ws();
+        }
+        ensure!(has_root, "no root rule found");
+        Ok(items)
+    }
+
+    fn parse_rule(&mut self) -> Result<Rule> {
+        let loc = self.location();
+        let name = self.parse_name()?;
+        self.skip_ws();
+        self.expect_str("::=")?;
+        let expansions = self.parse_alternatives(loc)?;
+        Ok(Rule {
+            name,
+            cond_inline: false,
+            pin_terminals: false,
+            params: None,
+            priority: None,
+            expansions,
+        })
+    }
+
+    fn parse_alternatives(&mut self, loc: Location) -> Result<Expansions> {
+        let mut alternatives = vec![self.parse_sequence()?];
+        while self.peek() == Some('|') {
+            self.bump();
+            alternatives.push(self.parse_sequence()?);
+        }
+        let aliases = alternatives
+            .into_iter()
+            .map(|expansion| Alias {
+                expansion,
+                alias: None,
+            })
+            .collect();
+        Ok(Expansions(loc, aliases))
+    }
+
+    fn parse_sequence(&mut self) -> Result<Expansion> {
+        let mut exprs = Vec::new();
+        loop {
+            self.skip_ws();
+            match self.peek() {
+                None | Some('|') | Some(')') => break,
+                _ if self.at_rule_start() => break,
+                _ => exprs.push(self.parse_expr()?),
+            }
+        }
+        Ok(Expansion(exprs))
+    }
+
+    fn parse_expr(&mut self) -> Result<Expr> {
+        let atom = self.parse_atom()?;
+        let mut expr = Expr {
+            atom,
+            op: None,
+            range: None,
+        };
+        match self.peek() {
+            Some(c @ ('*' | '+' | '?')) => {
+                self.bump();
+                expr.op = Some(Op(c.to_string()));
+            }
+            Some('{') => {
+                let loc = self.location();
+                self.bump();
+                self.skip_ws();
+                let min = self.parse_number()?;
+                self.skip_ws();
+                let max = if self.peek() == Some(',') {
+                    self.bump();
+                    self.skip_ws();
+                    if self.peek() == Some('}') {
+                        None
+                    } else {
+                        Some(self.parse_number()?)
+                    }
+                } else {
+                    Some(min)
+                };
+                self.skip_ws();
+                self.expect_str("}")?;
+                match max {
+                    Some(max) => {
+                        ensure!(max >= min, "repetition end must be >= start");
+                        expr.range = Some((min, max));
+                    }
+                    None => {
+                        // x{n,} is x{n} x*
+                        let exprs = vec![
+                            Expr {
+                                atom: expr.atom.clone(),
+                                op: None,
+                                range: Some((min, min)),
+                            },
+                            Expr {
+                                atom: expr.atom,
+                                op: Some(Op("*".to_string())),
+                                range: None,
+                            },
+                        ];
+                        expr = Expr {
+                            atom: Atom::Group(Expansions(
+                                loc,
+                                vec![Alias {
+                                    expansion: Expansion(exprs),
+                                    alias: None,
+                                }],
+                            )),
+                            op: None,
+                            range: None,
+                        };
+                    }
+                }
+            }
+            _ => {}
+        }
+        Ok(expr)
+    }
+
+    fn parse_atom(&mut self) -> Result<Atom> {
+        match self.peek() {
+            Some('(') => {
+                let loc = self.location();
+                self.bump();
+                let expansions = self.parse_alternatives(loc)?;
+                self.skip_ws();
+                self.expect_str(")")?;
+                Ok(Atom::Group(expansions))
+            }
+            Some('"') => {
+                self.bump();
+                let mut val = String::new();
+                loop {
+                    match self.peek() {
+                        None => bail!("unterminated string"),
+                        Some('"') => {
+                            self.bump();
+                            break;
+                        }
+                        Some(_) => val.push(self.parse_char()?),
+                    }
+                }
+                Ok(Atom::Value(Value::LiteralString(val, "".to_string())))
+            }
+            Some('[') => {
+                self.bump();
+                let mut rx = "[".to_string();
+                if self.peek() == Some('^') {
+                    self.bump();
+                    rx.push('^');
+                }
+                let mut empty = true;
+                loop {
+                    match self.peek() {
+                        None => bail!("unterminated character class"),
+                        Some(']') => {
+                            ensure!(!empty, "empty character class");
+                            self.bump();
+                            break;
+                        }
+                        Some(_) => {
+                            let start = self.parse_char()?;
+                            rx.push_str(&escape_class_char(start));
+                            if self.peek() == Some('-') && self.peek_at(1) != Some(']') {
+                                self.bump();
+                                let end = self.parse_char()?;
+                                ensure!(start <= end, "invalid range order: {:?}-{:?}", start, end);
+                                rx.push('-');
+                                rx.push_str(&escape_class_char(end));
+                            }
+                            empty = false;
+                        }
+                    }
+                }
+                rx.push(']');
+                Ok(Atom::Value(Value::LiteralRegex(rx, "".to_string())))
+            }
+            Some('.') => {
+                self.bump();
+                Ok(Atom::Value(Value::LiteralRegex(
+                    "(?s:.)".to_string(),
+                    "".to_string(),
+                )))
+            }
+            Some(c) if is_name_char(c) => Ok(Atom::Value(Value::Name(self.parse_name()?))),
+            Some(c) => bail!("unexpected character {:?}", c),
+            None => bail!("unexpected end of input"),
+        }
+    }
+
+    /// Parses a single (possibly escaped) character inside a string or a class.
+    fn parse_char(&mut self) -> Result<char> {
+        let c = self
+            .bump()
+            .ok_or_else(|| anyhow!("unexpected end of input"))?;
+        if c != '\\' {
+            return Ok(c);
+        }
+        let c = self
+            .bump()
+            .ok_or_else(|| anyhow!("unexpected end of input"))?;
+        let num_digits = match c {
+            'n' => return Ok('\n'),
+            'r' => return Ok('\r'),
+            't' => return Ok('\t'),
+            'x' => 2,
+            'u' => 4,
+            'U' => 8,
+            _ => return Ok(c),
+        };
+        let mut code = 0;
+        for _ in 0..num_digits {
+            let d = self
+                .bump()
+                .and_then(|d| d.to_digit(16))
+                .ok_or_else(|| anyhow!("invalid escape sequence \\{}", c))?;
+            code = code * 16 + d;
+        }
+        char::from_u32(code).ok_or_else(|| anyhow!("invalid character code: {:#x}", code))
+    }
+
+    fn parse_name(&mut self) -> Result<String> {
+        let mut name = String::new();
+        while let Some(c) = self.peek() {
+            if !is_name_char(c) {
+                break;
+            }
+            name.push(c);
+            self.bump();
+        }
+        ensure!(!name.is_empty(), "expecting rule name");
+        // Lark compiler expects the start symbol to be called "start"
+        match name.as_str() {
+            "root" => Ok("start".to_string()),
+            "start" => bail!("rule name \"start\" is reserved"),
+            _ => Ok(name),
+        }
+    }
+
+    fn parse_number(&mut self) -> Result<i32> {
+        let mut num = String::new();
+        while let Some(c) = self.peek() {
+            if !c.is_ascii_digit() {
+                break;
+            }
+            num.push(c);
+            self.bump();
+        }
+        ensure!(!num.is_empty(), "expecting number");
+        Ok(num.parse::<i32>()?)
+    }
+
+    /// Checks if the input at the current position looks like `name ::=`.
+    fn at_rule_start(&self) -> bool {
+        let mut p = self.pos;
+        while p < self.chars.len() && is_name_char(self.chars[p]) {
+            p += 1;
+        }
+        if p == self.pos {
+            return false;
+        }
+        while p < self.chars.len() && (self.chars[p] == ' ' || self.chars[p] == '\t') {
+            p += 1;
+        }
+        self.chars[p..].starts_with(&[':', ':', '='])
+    }
+
+    fn skip_ws(&mut self) {
+        while let Some(c) = self.peek() {
+            if c == '#' {
+                while let Some(c) = self.bump() {
+                    if c == '\n' {
+                        break;
+                    }
+                }
+            } else if c.is_whitespace() {
+                self.bump();
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn expect_str(&mut self, s: &str) -> Result<()> {
+        for c in s.chars() {
+            if self.peek() != Some(c) {
+                bail!("expecting {:?}", s);
+            }
+            self.bump();
+        }
+        Ok(())
+    }
+
+    fn location(&self) -> Location {
+        Location {
+            line: self.line,
+            column: self.column,
+        }
+    }
+
+    fn is_at_end(&self) -> bool {
+        self.pos >= self.chars.len()
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.peek_at(0)
+    }
+
+    fn peek_at(&self, offset: usize) -> Option<char> {
+        self.chars.get(self.pos + offset).copied()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += 1;
+        if c == '\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
+        Some(c)
+    }
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '-' || c == '_'
+}
+
+fn escape_class_char(c: char) -> String {
+    format!("\\x{{{:x}}}", c as u32)
+}
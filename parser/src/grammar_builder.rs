@@ -1,6 +1,6 @@
 use std::{collections::HashMap, sync::atomic::AtomicU32};
 
-use anyhow::{ensure, Result};
+use anyhow::{bail, ensure, Result};
 
 use crate::api::{
     GrammarWithLexer, Node, NodeId, NodeProps, RegexId, RegexNode, RegexSpec, TopLevelGrammar,
@@ -14,6 +14,9 @@ pub struct NodeRef {
 
 const K: usize = 4;
 
+/// Maximum nesting depth for `GrammarBuilder::balanced_delimiters()`.
+const MAX_DELIMITER_DEPTH: usize = 100;
+
 pub struct GrammarBuilder {
     pub top_grammar: TopLevelGrammar,
     placeholder: Node,
@@ -340,6 +343,52 @@ impl GrammarBuilder {
         }
     }
 
+    /// Sequence of balanced groups of delimiters from `pairs`
+    /// (eg., `([]{})` for `[('(', ')'), ('[', ']'), ('{', '}')]`),
+    /// nested at most `max_depth` deep.
+    /// Each delimiter is a separate string lexeme; the grammar size is
+    /// proportional to `max_depth` times the number of pairs.
+    /// Fails if `max_depth` is zero or above 100, or if
+    /// a delimiter is used more than once (including `open == close`).
+    pub fn balanced_delimiters(
+        &mut self,
+        pairs: &[(char, char)],
+        max_depth: usize,
+    ) -> Result<NodeRef> {
+        ensure!(!pairs.is_empty(), "no delimiter pairs");
+        ensure!(max_depth > 0, "max_depth must be positive");
+        ensure!(
+            max_depth <= MAX_DELIMITER_DEPTH,
+            "max_depth {} exceeds {}",
+            max_depth,
+            MAX_DELIMITER_DEPTH
+        );
+        let mut seen = HashMap::new();
+        for (i, &(open, close)) in pairs.iter().enumerate() {
+            ensure!(open != close, "delimiter {:?} both opens and closes", open);
+            for ch in [open, close] {
+                if let Some(j) = seen.insert(ch, i) {
+                    bail!("delimiter {:?} used in pairs #{} and #{}", ch, j, i);
+                }
+            }
+        }
+
+        let mut seq = self.empty();
+        for _ in 0..max_depth {
+            let groups = pairs
+                .iter()
+                .map(|&(open, close)| {
+                    let open = self.string(&open.to_string());
+                    let close = self.string(&close.to_string());
+                    self.join(&[open, seq, close])
+                })
+                .collect::<Vec<_>>();
+            let group = self.select(&groups);
+            seq = self.zero_or_more(group);
+        }
+        Ok(seq)
+    }
+
     pub fn placeholder(&mut self) -> NodeRef {
         self.add_node(self.placeholder.clone())
     }
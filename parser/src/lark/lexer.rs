@@ -5,7 +5,7 @@ use derivre::{RegexAst, RegexBuilder};
 
 use crate::earley::{
     lexer::{Lexer, LexerResult},
-    lexerspec::{LexemeIdx, LexerSpec},
+    lexerspec::{LexemeIdx, LexerError, LexerSpec},
 };
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -55,6 +55,57 @@ pub struct Location {
     pub column: usize,
 }
 
+/// Maps byte offsets in source text to line/column locations.
+/// Both are 1-based, and columns count characters, not bytes.
+pub struct SourceMap<'a> {
+    text: &'a str,
+    line_starts: Vec<usize>,
+}
+
+impl<'a> SourceMap<'a> {
+    pub fn new(text: &'a str) -> Self {
+        let mut line_starts = vec![0];
+        line_starts.extend(text.match_indices('\n').map(|(idx, _)| idx + 1));
+        SourceMap { text, line_starts }
+    }
+
+    pub fn location(&self, offset: usize) -> Location {
+        let mut offset = offset.min(self.text.len());
+        // if we're in the middle of a multi-byte character, report that character
+        while !self.text.is_char_boundary(offset) {
+            offset -= 1;
+        }
+        let line = match self.line_starts.binary_search(&offset) {
+            Ok(line) => line,
+            Err(line) => line - 1,
+        };
+        let line_start = self.line_starts[line];
+        Location {
+            line: line + 1,
+            column: self.text[line_start..offset].chars().count() + 1,
+        }
+    }
+
+    /// Like `err.to_string()`, but with the input range of `LexerError::InvalidInput`
+    /// given as `line:column` locations in the text, instead of byte offsets.
+    pub fn describe_error(&self, err: &LexerError) -> String {
+        match err {
+            LexerError::InvalidInput { start, end } => format!(
+                "invalid input at {}..{}",
+                self.location(*start),
+                self.location(*end)
+            ),
+            _ => err.to_string(),
+        }
+    }
+}
+
+impl Display for Location {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
 impl Location {
     pub fn augment(&self, err: impl Display) -> anyhow::Error {
         let err = err.to_string();
@@ -62,7 +113,7 @@ impl Location {
             // don't add more location info
             anyhow::anyhow!("{err}")
         } else {
-            anyhow::anyhow!("at {}({}): {}", self.line, self.column, err)
+            anyhow::anyhow!("at {}: {}", self, err)
         }
     }
 }
@@ -129,8 +180,6 @@ pub fn lex_lark(input: &str) -> Result<Vec<Lexeme>> {
     let mut lexer = Lexer::from(&spec, &mut Default::default()).unwrap();
     let all_lexemes = spec.all_lexemes();
     let state0 = lexer.start_state(&all_lexemes, None);
-    let mut line_no = 1;
-    let mut column_no = 1;
     let mut curr_lexeme = Lexeme {
         token: Token::EOF,
         value: String::new(),
@@ -142,19 +191,18 @@ pub fn lex_lark(input: &str) -> Result<Vec<Lexeme>> {
     let mut start_idx = 0;
 
     let input = format!("{}\n", input);
+    let source_map = SourceMap::new(&input);
     let input_bytes = input.as_bytes();
     for idx in 0..=input_bytes.len() {
-        let mut b = b'\n';
         let res = if idx == input_bytes.len() {
             lexer.force_lexeme_end(state)
         } else {
-            b = input_bytes[idx];
-            lexer.advance(state, b, false)
+            lexer.advance(state, input_bytes[idx], false)
         };
 
         match res {
             LexerResult::Error => {
-                bail!("{}({}): lexer error", line_no, column_no);
+                bail!("at {}: lexer error", source_map.location(idx));
             }
             LexerResult::State(s, _) => {
                 state = s;
@@ -179,19 +227,17 @@ pub fn lex_lark(input: &str) -> Result<Vec<Lexeme>> {
                 }
 
                 state = lexer.start_state(&all_lexemes, transition_byte);
+                if state.is_dead() {
+                    // the byte that ended the lexeme cannot start another one
+                    bail!("at {}: lexer error", source_map.location(idx));
+                }
 
                 curr_lexeme.value.clear();
-                curr_lexeme.line = line_no;
-                curr_lexeme.column = column_no;
+                let loc = source_map.location(start_idx);
+                curr_lexeme.line = loc.line;
+                curr_lexeme.column = loc.column;
             }
         }
-
-        if b == b'\n' {
-            line_no += 1;
-            column_no = 1;
-        } else {
-            column_no += 1;
-        }
     }
 
     Ok(lexemes)
@@ -1,8 +1,9 @@
-mod ast;
+pub(crate) mod ast;
 mod compiler;
-mod lexer;
+pub(crate) mod lexer;
 mod parser;
 mod common;
 
 pub use parser::parse_lark;
-pub use compiler::lark_to_llguidance;
\ No newline at end of file
+pub use compiler::lark_to_llguidance;
+pub use lexer::{Location, SourceMap};
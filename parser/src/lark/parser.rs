@@ -466,14 +466,11 @@ pub fn parse_lark(input: &str) -> Result<Vec<Item>> {
     let mut parser = Parser::new(tokens);
     parser.parse_start().map_err(|e| {
         if let Some(tok) = parser.peek_token() {
-            anyhow!(
-                "{}({}): {} (at {:?} ({:?}))",
-                tok.line,
-                tok.column,
-                e,
-                tok.value,
-                tok.token
-            )
+            let loc = Location {
+                line: tok.line,
+                column: tok.column,
+            };
+            anyhow!("at {}: {} (at {:?} ({:?}))", loc, e, tok.value, tok.token)
         } else {
             anyhow!("at EOF: {}", e)
         }
@@ -22,6 +22,7 @@ pub use derivre;
 
 pub mod ffi;
 
+pub mod gbnf;
 pub mod lark;
 
 mod grammar_builder;
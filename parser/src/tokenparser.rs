@@ -3,12 +3,12 @@ use std::{sync::Arc, time::Duration};
 use crate::{
     api::{GenGrammarOptions, ParserLimits, StopReason, TopLevelGrammar},
     earley::{
-        grammars_from_json, BiasComputer, CGrammar, CSymIdx, DefaultBiasComputer, Parser,
-        ParserStats,
+        grammars_from_json, lexer::InputFilter, BiasComputer, CGrammar, CSymIdx,
+        DefaultBiasComputer, Parser, ParserStats,
     },
     infoln, warn, Logger,
 };
-use anyhow::Result;
+use anyhow::{ensure, Result};
 use serde_json::json;
 use toktrie::{InferenceCapabilities, SimpleVob, StepArg, StepResult, TokEnv, TokenId};
 
@@ -23,6 +23,7 @@ pub struct TokenParser {
     pub limits: ParserLimits,
     pub bias_computer: Arc<dyn BiasComputer>,
     pending_bogus_backtrack: u32,
+    num_backtracks: usize,
     // sampling any of these will pop the parser stack:
     pop_tokens: Option<SimpleVob>,
     test_trace: bool,
@@ -47,6 +48,8 @@ pub struct TokenParser {
     llm_bytes: Vec<u8>,
     grm_prefix: Vec<u8>,
     is_fresh: bool,
+    // applied to parsers of nested grammars too
+    input_filter: Option<InputFilter>,
 }
 
 #[derive(Clone)]
@@ -93,6 +96,7 @@ impl TokenParser {
             inference_caps,
             limits,
             pending_bogus_backtrack: 0,
+            num_backtracks: 0,
             mid_process_start_time,
             mid_process_was_accepting: false,
             no_bias_this_mid_process: false,
@@ -111,6 +115,7 @@ impl TokenParser {
             max_tokens_parser: max_tokens,
             last_bias_time: Duration::from_secs(0),
             is_fresh: true,
+            input_filter: None,
         })
     }
 
@@ -125,6 +130,27 @@ impl TokenParser {
         self.stop_reason
     }
 
+    /// How many times did the parser backtrack tokens generated by the LLM.
+    pub fn num_backtracks(&self) -> usize {
+        self.num_backtracks
+    }
+
+    /// Normalize the bytes of tokens before they reach the lexer (eg., to match
+    /// keywords case-insensitively with `InputFilter::AsciiLowercase`),
+    /// see `Lexer::set_input_filter()`; this also applies to nested grammars.
+    /// Generated and captured bytes are the original ones, but forced bytes
+    /// are computed on the filtered alphabet.
+    /// Must be called before any tokens are processed.
+    pub fn set_input_filter(&mut self, filter: Option<InputFilter>) -> Result<()> {
+        ensure!(
+            self.is_fresh(),
+            "input filter must be set before processing tokens"
+        );
+        self.parser.set_input_filter(filter.clone())?;
+        self.input_filter = filter;
+        Ok(())
+    }
+
     pub fn is_fresh(&self) -> bool {
         self.is_fresh
     }
@@ -141,10 +167,23 @@ impl TokenParser {
         &self.llm_bytes[self.grm_prefix.len()..]
     }
 
+    /// See `CGrammar::fingerprint()`; this covers all grammars, including nested ones.
+    pub fn grammar_fingerprint(&self) -> u64 {
+        self.compiled_grammars
+            .iter()
+            .fold(0, |acc, g| acc.rotate_left(5) ^ g.fingerprint())
+    }
+
     pub fn mid_process_was_accepting(&self) -> bool {
         self.mid_process_was_accepting
     }
 
+    /// Do the bytes so far (including a pending lexeme, which has to be
+    /// allowed to end at EOS) match the top-level grammar?
+    pub fn is_accepting(&mut self) -> bool {
+        self.is_top_level_parser() && self.parser.is_accepting()
+    }
+
     pub fn bytes_since(&mut self, mut idx: usize) -> &[u8] {
         idx += self.grm_prefix.len();
         let endp = std::cmp::min(
@@ -501,6 +540,31 @@ impl TokenParser {
                     String::from_utf8_lossy(&grm_bytes)
                 );
             }
+            self.num_backtracks += 1;
+            if self.num_backtracks > self.limits.max_backtracks {
+                return self.stop(
+                    &format!(
+                        "too many backtracks ({} > {})",
+                        self.num_backtracks, self.limits.max_backtracks
+                    ),
+                    StopReason::TooManyBacktracks,
+                );
+            }
+        }
+
+        let num_output_bytes = self.llm_bytes.len().saturating_sub(self.grm_prefix.len());
+        if num_output_bytes >= self.limits.max_output_bytes {
+            let accepting = self.parser_stack.is_empty() && self.parser.is_accepting();
+            self.mid_process_was_accepting = accepting;
+            let msg = if accepting {
+                "".to_string()
+            } else {
+                format!(
+                    "max_output_bytes reached ({} bytes)",
+                    self.limits.max_output_bytes
+                )
+            };
+            return self.stop(&msg, StopReason::MaxOutputBytes);
         }
 
         // if arg.tokens.contains(&trie.eos_token()) {
@@ -705,7 +769,10 @@ impl TokenParser {
             }
             let grm = Arc::clone(&self.compiled_grammars[gen_grammar.grammar.0]);
             let max_tokens = self.parser.grammar().sym_data(symidx).props.max_tokens;
-            let parser = Parser::new(grm, gen_grammar, self.limits.clone())?;
+            let mut parser = Parser::new(grm, gen_grammar, self.limits.clone())?;
+            if self.input_filter.is_some() {
+                parser.set_input_filter(self.input_filter.clone())?;
+            }
             let mut old_parser = std::mem::replace(&mut self.parser, parser);
             self.parser.take_global_state_from(&mut old_parser);
             let mut entry = ParserStackEntry {
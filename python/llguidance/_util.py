@@ -7,6 +7,8 @@ StopReason = Literal[
     "MaxTokensParser",
     "ParserTooComplex",
     "LexerTooComplex",
+    "TooManyBacktracks",
+    "MaxOutputBytes",
     "NoExtension",
     "NoExtensionBias",
     "EndOfSentence",
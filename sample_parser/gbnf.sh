@@ -0,0 +1,3 @@
+#!/bin/sh
+
+cargo run --bin gbnf_test -- data/json.gbnf data/json.gbnf.tests.json
@@ -0,0 +1,3 @@
+#!/bin/sh
+
+cargo run --bin lexer_fixture -- data/lexer_fixtures/*.json
@@ -0,0 +1,3 @@
+#!/bin/sh
+
+cargo run --bin lexer_test
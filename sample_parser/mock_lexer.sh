@@ -0,0 +1,3 @@
+#!/bin/sh
+
+cargo run --bin mock_lexer
@@ -0,0 +1,370 @@
+use std::sync::Arc;
+
+use anyhow::{bail, ensure, Result};
+use llguidance_parser::{
+    api::{
+        GenOptions, GrammarWithLexer, Node, NodeId, NodeProps, ParserLimits, RegexSpec, StopReason,
+        TopLevelGrammar,
+    },
+    earley::lexer::InputFilter,
+    toktrie::{InferenceCapabilities, TokEnv, TokRxInfo, TokTrie, TokenId, TokenizerEnv},
+    Constraint, Logger, TokenParser,
+};
+
+/// Tokenizer with a token for every byte, so that inputs are generated byte-by-byte.
+struct ByteTokenizer {
+    tok_trie: TokTrie,
+}
+
+impl ByteTokenizer {
+    fn new() -> Self {
+        let mut words = (0..=255).map(|x| vec![x]).collect::<Vec<_>>();
+        words.push("<eos>".as_bytes().to_vec());
+        let info = TokRxInfo {
+            vocab_size: words.len() as u32,
+            tok_eos: words.len() as u32 - 1,
+            tok_bos: None,
+            tok_pad: None,
+            tok_unk: None,
+            tok_end_of_turn: None,
+        };
+        ByteTokenizer {
+            tok_trie: TokTrie::from(&info, &words),
+        }
+    }
+}
+
+impl TokenizerEnv for ByteTokenizer {
+    fn stop(&self) -> ! {
+        panic!("stop called")
+    }
+
+    fn tok_trie(&self) -> &TokTrie {
+        &self.tok_trie
+    }
+
+    fn tokenize_bytes(&self, s: &[u8]) -> Vec<TokenId> {
+        self.tok_trie.greedy_tokenize(s)
+    }
+}
+
+/// A gen() node; the string matching `stop_rx` is not part of the output.
+fn gen(body_rx: &str, stop_rx: &str) -> Node {
+    Node::Gen {
+        data: GenOptions {
+            body_rx: RegexSpec::Regex(body_rx.to_string()),
+            stop_rx: RegexSpec::Regex(stop_rx.to_string()),
+            stop_capture_name: None,
+            lazy: None,
+            temperature: None,
+        },
+        props: NodeProps::default(),
+    }
+}
+
+fn string(literal: &str) -> Node {
+    Node::String {
+        literal: literal.to_string(),
+        props: NodeProps::default(),
+    }
+}
+
+/// Grammar generating the `nodes` in sequence.
+fn sequence(nodes: Vec<Node>) -> TopLevelGrammar {
+    let join = Node::Join {
+        sequence: (1..=nodes.len()).map(NodeId).collect(),
+        props: NodeProps::default(),
+    };
+    TopLevelGrammar {
+        grammars: vec![GrammarWithLexer {
+            nodes: std::iter::once(join).chain(nodes).collect(),
+            ..GrammarWithLexer::default()
+        }],
+        max_tokens: None,
+        test_trace: false,
+    }
+}
+
+fn token_parser(grammar: TopLevelGrammar, limits: ParserLimits) -> Result<TokenParser> {
+    let tok_env: TokEnv = Arc::new(ByteTokenizer::new());
+    TokenParser::from_llguidance_json(
+        tok_env,
+        grammar,
+        Logger::new(0, 1),
+        InferenceCapabilities {
+            ff_tokens: true,
+            backtrack: true,
+            conditional_ff_tokens: false,
+            fork: false,
+        },
+        limits,
+        vec![],
+    )
+}
+
+fn constraint(grammar: TopLevelGrammar, limits: ParserLimits) -> Result<Constraint> {
+    Ok(Constraint::new(token_parser(grammar, limits)?))
+}
+
+/// Sample the bytes of `input` one by one, until the constraint stops.
+/// Returns the number of bytes sampled.
+fn generate(constraint: &mut Constraint, input: &str) -> Result<usize> {
+    let tokens = constraint.tok_trie().greedy_tokenize(input.as_bytes());
+    for (idx, token) in tokens.iter().enumerate() {
+        let res = constraint.compute_mask()?;
+        if res.is_stop() {
+            return Ok(idx);
+        }
+        match &res.sample_mask {
+            Some(mask) if mask.is_allowed(*token) => {}
+            Some(_) => bail!("{:?}: byte {} not allowed", input, idx),
+            None => bail!("{:?}: unexpected splice at byte {}", input, idx),
+        }
+        if constraint.commit_token(Some(*token))?.stop {
+            return Ok(idx + 1);
+        }
+    }
+    Ok(tokens.len())
+}
+
+/// Each stop string is generated by the model and then removed,
+/// so that every gen() costs a backtrack; one more than max_backtracks stops the parser.
+fn max_backtracks() -> Result<()> {
+    let grammar = sequence(vec![
+        gen("[a-z]+", ";"),
+        gen("[a-z]+", ";"),
+        gen("[a-z]+", ";"),
+    ]);
+    let input = "ab;c;de;";
+
+    let mut unlimited = constraint(grammar.clone(), ParserLimits::default())?;
+    generate(&mut unlimited, input)?;
+    let n = unlimited.parser.num_backtracks();
+    ensure!(n == 3, "expected 3 backtracks, got {}", n);
+    ensure!(
+        unlimited.parser.stop_reason() != StopReason::TooManyBacktracks,
+        "stopped without max_backtracks"
+    );
+
+    let limits = ParserLimits {
+        max_backtracks: 2,
+        ..ParserLimits::default()
+    };
+    let mut limited = constraint(grammar, limits)?;
+    let num_bytes = generate(&mut limited, input)?;
+    ensure!(
+        limited.parser.stop_reason() == StopReason::TooManyBacktracks,
+        "expected TooManyBacktracks, got {:?}",
+        limited.parser.stop_reason()
+    );
+    ensure!(
+        num_bytes == input.len() && limited.parser.num_backtracks() == 3,
+        "stopped at byte {} after {} backtracks",
+        num_bytes,
+        limited.parser.num_backtracks()
+    );
+    let msg = limited.parser.error_message().unwrap_or_default();
+    ensure!(msg.contains("too many backtracks (3 > 2)"), "got {:?}", msg);
+    Ok(())
+}
+
+/// Generation stops once max_output_bytes is reached, as accepting
+/// if the grammar allows it and with an error otherwise;
+/// generations below the limit are not affected.
+fn max_output_bytes() -> Result<()> {
+    let limits = ParserLimits {
+        max_output_bytes: 5,
+        ..ParserLimits::default()
+    };
+    for (body_rx, input, expected_len, accepting) in [
+        ("[a-z]*", "abcdefgh", 5, true),
+        ("[a-z]+;", "abcdefgh;", 5, false),
+        ("[a-z]+;", "ab;", 3, true),
+    ] {
+        let mut c = constraint(sequence(vec![gen(body_rx, "")]), limits.clone())?;
+        let num_bytes = generate(&mut c, input)?;
+        let stop_reason = c.parser.stop_reason();
+        let error = c.parser.error_message();
+        ensure!(
+            num_bytes == expected_len,
+            "{:?}: stopped after {} bytes",
+            input,
+            num_bytes
+        );
+        ensure!(
+            (stop_reason == StopReason::MaxOutputBytes) == (expected_len < input.len()),
+            "{:?}: unexpected {:?}",
+            input,
+            stop_reason
+        );
+        if accepting {
+            ensure!(error.is_none(), "{:?}: unexpected error {:?}", input, error);
+        } else {
+            let msg = error.unwrap_or_default();
+            ensure!(
+                msg.contains("max_output_bytes reached (5 bytes)"),
+                "{:?}: got {:?}",
+                input,
+                msg
+            );
+        }
+    }
+    Ok(())
+}
+
+/// For `number ";"`, generation can only stop after the ";",
+/// not after the number, even though the number itself could end there.
+fn is_accepting() -> Result<()> {
+    let grammar = sequence(vec![gen("[0-9]+", ""), string(";")]);
+    let mut c = constraint(grammar, ParserLimits::default())?;
+    let tokens = c.tok_trie().greedy_tokenize(b"12;");
+    let mut accepting = vec![];
+    for token in tokens {
+        let res = c.compute_mask()?;
+        ensure!(!res.is_stop(), "stopped early");
+        accepting.push(c.is_accepting());
+        c.commit_token(Some(token))?;
+    }
+    // with ff_tokens, commit_token() applies the token to the parser
+    accepting.push(c.is_accepting());
+    ensure!(
+        accepting == [false, false, false, true],
+        "before/after each byte of \"12;\": {:?}",
+        accepting
+    );
+    Ok(())
+}
+
+/// A session restored from a snapshot, taken either after commit_token()
+/// or after compute_mask(), continues to the same result as the original;
+/// snapshots are rejected for another grammar or format version.
+fn snapshot_restore() -> Result<()> {
+    let grammar = sequence(vec![
+        gen("[a-z]+", ";"),
+        gen("[a-z]+", ";"),
+        gen("[a-z]+", ";"),
+    ]);
+    let (prefix, suffix) = ("ab;c", ";de;");
+
+    let mut reference = constraint(grammar.clone(), ParserLimits::default())?;
+    generate(&mut reference, &format!("{prefix}{suffix}"))?;
+    let expected = reference.parser.bytes_since(0).to_vec();
+
+    for mask_computed in [false, true] {
+        let mut suffix = suffix;
+        let mut c = constraint(grammar.clone(), ParserLimits::default())?;
+        generate(&mut c, prefix)?;
+        if mask_computed {
+            c.compute_mask()?;
+        }
+        let snapshot = c.snapshot();
+        drop(c);
+
+        let parser = token_parser(grammar.clone(), ParserLimits::default())?;
+        let mut restored = Constraint::restore(parser, &snapshot)?;
+        if mask_computed {
+            let token = restored.tok_trie().greedy_tokenize(&suffix.as_bytes()[..1])[0];
+            restored.commit_token(Some(token))?;
+            suffix = &suffix[1..];
+        }
+        generate(&mut restored, suffix)?;
+        let output = restored.parser.bytes_since(0).to_vec();
+        ensure!(
+            output == expected
+                && restored.parser.num_backtracks() == reference.parser.num_backtracks()
+                && restored.is_accepting(),
+            "mask_computed={}: got {:?}, expected {:?}",
+            mask_computed,
+            String::from_utf8_lossy(&output),
+            String::from_utf8_lossy(&expected)
+        );
+    }
+
+    let mut c = constraint(grammar.clone(), ParserLimits::default())?;
+    generate(&mut c, prefix)?;
+    let snapshot = c.snapshot();
+    let other_grammar = sequence(vec![gen("[a-z]+", ";"), gen("[a-z]+", ";")]);
+    let old_version =
+        String::from_utf8(snapshot.clone())?.replace("\"version\":1,", "\"version\":0,");
+    for (what, grammar, snapshot, expected) in [
+        (
+            "grammar",
+            other_grammar,
+            snapshot,
+            "snapshot was taken with a different grammar",
+        ),
+        (
+            "version",
+            grammar,
+            old_version.into_bytes(),
+            "snapshot version 0 not supported",
+        ),
+    ] {
+        let parser = token_parser(grammar, ParserLimits::default())?;
+        match Constraint::restore(parser, &snapshot) {
+            Ok(_) => bail!("{}: snapshot accepted", what),
+            Err(e) => ensure!(
+                e.to_string().contains(expected),
+                "{}: got {:?}",
+                what,
+                e.to_string()
+            ),
+        }
+    }
+    Ok(())
+}
+
+/// With `InputFilter::AsciiLowercase`, upper-case bytes are allowed where
+/// the grammar has lower-case ones, and the output keeps the original bytes;
+/// without it they are rejected, and the filter cannot be set once started.
+fn input_filter() -> Result<()> {
+    let grammar = sequence(vec![gen("[a-z]+;", "")]);
+    let input = "AbC;";
+
+    let mut c = constraint(grammar.clone(), ParserLimits::default())?;
+    c.set_input_filter(Some(InputFilter::AsciiLowercase))?;
+    let num_bytes = generate(&mut c, input)?;
+    let output = c.parser.bytes_since(0).to_vec();
+    ensure!(
+        num_bytes == input.len() && output == input.as_bytes() && c.is_accepting(),
+        "with filter: {} bytes, output {:?}",
+        num_bytes,
+        String::from_utf8_lossy(&output)
+    );
+    ensure!(
+        c.set_input_filter(None).is_err(),
+        "filter changed after starting"
+    );
+
+    let mut c = constraint(grammar, ParserLimits::default())?;
+    ensure!(
+        generate(&mut c, input).is_err(),
+        "upper-case accepted without a filter"
+    );
+    Ok(())
+}
+
+fn main() {
+    let tests: &[(&str, fn() -> Result<()>)] = &[
+        ("max_backtracks", max_backtracks),
+        ("max_output_bytes", max_output_bytes),
+        ("is_accepting", is_accepting),
+        ("snapshot_restore", snapshot_restore),
+        ("input_filter", input_filter),
+    ];
+
+    let mut num_failed = 0;
+    for (name, test) in tests {
+        match test() {
+            Ok(()) => println!("{} OK", name),
+            Err(e) => {
+                eprintln!("FAIL {}: {}", name, e);
+                num_failed += 1;
+            }
+        }
+    }
+    if num_failed > 0 {
+        eprintln!("{} test(s) failed", num_failed);
+        std::process::exit(1);
+    }
+}
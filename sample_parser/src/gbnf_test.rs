@@ -0,0 +1,91 @@
+use std::{env, fs::File, io::Read};
+
+use anyhow::{bail, Result};
+use llguidance_parser::{
+    api::{ParserLimits, TopLevelGrammar},
+    gbnf::parse_gbnf,
+    toktrie::{InferenceCapabilities, TokEnv},
+    Constraint, Logger, TokenParser,
+};
+use serde_json::Value;
+
+/// Check if the grammar accepts the given string, token by token.
+fn accepts(tok_env: &TokEnv, grammar: &TopLevelGrammar, s: &str) -> Result<bool> {
+    let parser = TokenParser::from_llguidance_json(
+        tok_env.clone(),
+        grammar.clone(),
+        Logger::new(0, 1),
+        InferenceCapabilities {
+            ff_tokens: false,
+            backtrack: false,
+            conditional_ff_tokens: false,
+            fork: false,
+        },
+        ParserLimits::default(),
+        vec![],
+    )?;
+    let mut constraint = Constraint::new(parser);
+    for token in tok_env.tokenize(s) {
+        let res = constraint.compute_mask()?;
+        match &res.sample_mask {
+            Some(mask) if mask.is_allowed(token) => {}
+            _ => return Ok(false),
+        }
+        constraint.commit_token(Some(token))?;
+    }
+    let res = constraint.compute_mask()?;
+    let eos = tok_env.tok_trie().eos_token();
+    Ok(res.is_stop() || res.sample_mask.as_ref().is_some_and(|m| m.is_allowed(eos)))
+}
+
+fn process_file(tok_env: &TokEnv, grammar_file: &str, tests_file: &str) -> Result<()> {
+    let grammar = parse_gbnf(&read_file_to_string(grammar_file))?;
+    let tests: Value = serde_json::from_str(&read_file_to_string(tests_file))?;
+
+    let mut num_failed = 0;
+    for (key, expected) in [("accept", true), ("reject", false)] {
+        let Some(strings) = tests[key].as_array() else {
+            continue;
+        };
+        for s in strings {
+            let Some(s) = s.as_str() else {
+                bail!("{}: expecting strings in {:?}", tests_file, key);
+            };
+            if accepts(tok_env, &grammar, s)? != expected {
+                eprintln!("FAIL ({}): {:?}", key, s);
+                num_failed += 1;
+            }
+        }
+    }
+    if num_failed > 0 {
+        bail!("{} test(s) failed", num_failed);
+    }
+    println!("{} OK", grammar_file);
+    Ok(())
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() != 3 {
+        eprintln!("Usage: {} <grammar.gbnf> <tests.json>", args[0]);
+        std::process::exit(1);
+    }
+
+    let tok_env: TokEnv =
+        toktrie_hf_tokenizers::ByteTokenizerEnv::from_name("microsoft/Phi-3.5-mini-instruct", None)
+            .unwrap()
+            .to_env();
+
+    if let Err(e) = process_file(&tok_env, &args[1], &args[2]) {
+        eprintln!("Error: {} {}", args[1], e);
+        std::process::exit(1);
+    }
+}
+
+fn read_file_to_string(filename: &str) -> String {
+    let mut file = File::open(filename).expect("Unable to open file");
+    let mut content = String::new();
+    file.read_to_string(&mut content)
+        .expect("Unable to read file");
+    content
+}
@@ -0,0 +1,111 @@
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use anyhow::{ensure, Result};
+use futures::{executor::block_on, Stream, StreamExt};
+use llguidance_parser::{
+    api::ParserLimits,
+    earley::{
+        lexer::Lexer,
+        lexerspec::{LexemeIdx, LexerSpec},
+    },
+};
+
+const SPEC: &str = r#"
+SKIP: /[ ]+/
+KW_IF: "if"
+ID: /[a-z]+/
+NUM: /[0-9]+/
+"#;
+
+/// Yields the bytes of the input, returning `Poll::Pending` before each one,
+/// like a channel where the data arrives with delays.
+struct SlowBytes {
+    bytes: Vec<u8>,
+    pos: usize,
+    ready: bool,
+}
+
+impl SlowBytes {
+    fn new(input: &str) -> Self {
+        SlowBytes {
+            bytes: input.as_bytes().to_vec(),
+            pos: 0,
+            ready: false,
+        }
+    }
+}
+
+impl Stream for SlowBytes {
+    type Item = u8;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<u8>> {
+        if !self.ready {
+            self.ready = true;
+            cx.waker().wake_by_ref();
+            return Poll::Pending;
+        }
+        self.ready = false;
+        let b = self.bytes.get(self.pos).copied();
+        self.pos += 1;
+        Poll::Ready(b)
+    }
+}
+
+/// Lexemes as (index, value) pairs, or the error message.
+type Lexed = Result<Vec<(LexemeIdx, Vec<u8>)>, String>;
+
+fn lex_async(spec: &LexerSpec, input: &str) -> Result<Lexed> {
+    let mut lexer = Lexer::from(spec, &mut ParserLimits::default())?;
+    let allowed = spec.all_lexemes();
+    let results = block_on(
+        lexer
+            .lex_async(&allowed, SlowBytes::new(input))
+            .collect::<Vec<_>>(),
+    );
+    let num_results = results.len();
+    let mut lexemes = vec![];
+    for (i, r) in results.into_iter().enumerate() {
+        match r {
+            Ok(lexeme) => lexemes.push((lexeme.idx, lexeme.value().to_vec())),
+            Err(e) => {
+                ensure!(i == num_results - 1, "stream continued after an error");
+                return Ok(Err(e.to_string()));
+            }
+        }
+    }
+    Ok(Ok(lexemes))
+}
+
+fn lex_views(spec: &LexerSpec, input: &str) -> Result<Lexed> {
+    let mut lexer = Lexer::from(spec, &mut ParserLimits::default())?;
+    let allowed = spec.all_lexemes();
+    Ok(match lexer.lex_views(&allowed, input.as_bytes()) {
+        Ok(views) => Ok(views
+            .iter()
+            .map(|v| (v.idx, v.value(spec).to_vec()))
+            .collect()),
+        Err(e) => Err(e.to_string()),
+    })
+}
+
+fn main() {
+    let spec = LexerSpec::parse_dsl(SPEC).unwrap();
+    let mut num_failed = 0;
+    // the last lexeme of each input is only returned when the source ends
+    for input in ["if x1 42", "  abc  ", "iffy 7", "", "x $ y"] {
+        let expected = lex_views(&spec, input).unwrap();
+        let got = lex_async(&spec, input).unwrap();
+        if got != expected {
+            eprintln!("FAIL {:?}: expected {:?}, got {:?}", input, expected, got);
+            num_failed += 1;
+        }
+    }
+    if num_failed > 0 {
+        eprintln!("{} test(s) failed", num_failed);
+        std::process::exit(1);
+    }
+    println!("lex_async OK");
+}
@@ -0,0 +1,108 @@
+use std::ffi::{c_char, CStr, CString};
+
+use llguidance_parser::ffi::{
+    llg_free_lexer, llg_lexer_advance, llg_lexer_allows_eos, llg_lexer_finish, llg_lexer_get_error,
+    llg_lexer_lexeme_name, llg_lexer_start_state, llg_new_lexer, LlgLexer, LlgLexerResult,
+};
+
+const SPEC: &str = r#"
+SKIP: /[ ]+/
+KW_IF: "if"
+ID: /[a-z]+/
+NUM: /[0-9]+/
+"#;
+
+fn empty_result() -> LlgLexerResult {
+    LlgLexerResult {
+        kind: 0,
+        lexeme_idx: 0,
+        hidden_len: 0,
+        byte_next_row: false,
+    }
+}
+
+fn lexeme_name(lx: &LlgLexer, idx: u32) -> String {
+    let mut buf = [0 as c_char; 64];
+    let len = llg_lexer_lexeme_name(lx, idx, buf.as_mut_ptr(), buf.len());
+    assert!(len <= buf.len());
+    unsafe { CStr::from_ptr(buf.as_ptr()) }
+        .to_str()
+        .unwrap()
+        .to_string()
+}
+
+fn new_lexer(spec: &str) -> *mut LlgLexer {
+    let spec = CString::new(spec).unwrap();
+    llg_new_lexer(spec.as_ptr())
+}
+
+// feed `input` the way a C caller would, returning the names of the lexemes,
+// and "error" for bytes that are not allowed (after which lexing restarts)
+fn lex(lx: &mut LlgLexer, input: &[u8]) -> Vec<String> {
+    let mut names = vec![];
+    let mut res = empty_result();
+    for &b in input {
+        assert_eq!(llg_lexer_advance(lx, b, &mut res), 0);
+        match res.kind {
+            0 => {}
+            1 => names.push(lexeme_name(lx, res.lexeme_idx)),
+            -1 => {
+                names.push("error".to_string());
+                assert_eq!(llg_lexer_start_state(lx), 0);
+            }
+            k => panic!("unexpected result kind {}", k),
+        }
+    }
+    assert_eq!(llg_lexer_finish(lx, &mut res), 0);
+    if res.kind == 1 {
+        names.push(lexeme_name(lx, res.lexeme_idx));
+    }
+    names
+}
+
+fn main() {
+    let lx = new_lexer(SPEC);
+    assert!(llg_lexer_get_error(unsafe { &*lx }).is_null());
+    let lx_ref = unsafe { &mut *lx };
+
+    assert_eq!(llg_lexer_start_state(lx_ref), 0);
+    assert!(!llg_lexer_allows_eos(lx_ref));
+    assert_eq!(
+        lex(lx_ref, b"if x1"),
+        ["KW_IF", "SKIP", "ID", "NUM"].map(String::from)
+    );
+
+    // the byte that ends a lexeme starts the next one
+    let mut res = empty_result();
+    assert_eq!(llg_lexer_start_state(lx_ref), 0);
+    assert_eq!(llg_lexer_advance(lx_ref, b'a', &mut res), 0);
+    assert_eq!(res.kind, 0);
+    assert!(llg_lexer_allows_eos(lx_ref));
+    assert_eq!(llg_lexer_advance(lx_ref, b'7', &mut res), 0);
+    assert_eq!(res.kind, 1);
+    assert!(res.byte_next_row);
+    assert_eq!(lexeme_name(lx_ref, res.lexeme_idx), "ID");
+    assert!(llg_lexer_allows_eos(lx_ref));
+
+    // bytes no lexeme can start with are reported, not panicked on
+    assert_eq!(llg_lexer_start_state(lx_ref), 0);
+    assert_eq!(lex(lx_ref, b"#ab"), ["error", "ID"].map(String::from));
+
+    // out of range lexeme index
+    assert_eq!(lexeme_name(lx_ref, 1000), "");
+    llg_free_lexer(lx);
+
+    // invalid specs are reported through llg_lexer_get_error()
+    let lx = new_lexer("ID /[a-z]+/");
+    let err = llg_lexer_get_error(unsafe { &*lx });
+    assert!(!err.is_null());
+    println!(
+        "expected error: {}",
+        unsafe { CStr::from_ptr(err) }.to_str().unwrap()
+    );
+    let mut res = empty_result();
+    assert_eq!(llg_lexer_advance(unsafe { &mut *lx }, b'a', &mut res), -1);
+    llg_free_lexer(lx);
+
+    println!("lexer C API OK");
+}
@@ -0,0 +1,38 @@
+use std::{env, fs::File, io::Read};
+
+use anyhow::{bail, Result};
+use llguidance_parser::earley::fixture::{run_fixture, LexerFixture};
+
+fn process_file(filename: &str) -> Result<()> {
+    let mut data = Vec::new();
+    File::open(filename)?.read_to_end(&mut data)?;
+    let fixture = LexerFixture::from_bytes(&data)?;
+    match (fixture.lexer_spec(), &fixture.spec_error) {
+        (Ok(spec), None) => run_fixture(&spec, &fixture)?,
+        (Err(e), Some(msg)) if e.to_string().contains(msg.as_str()) => {}
+        (Err(e), Some(msg)) => bail!("expected spec error {:?}, got {}", msg, e),
+        (Ok(_), Some(msg)) => bail!("expected spec error {:?}", msg),
+        (Err(e), None) => return Err(e),
+    }
+    println!("{} OK", filename);
+    Ok(())
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 2 {
+        eprintln!("Usage: {} <fixture.json...>", args[0]);
+        std::process::exit(1);
+    }
+
+    let mut failed = false;
+    for filename in &args[1..] {
+        if let Err(e) = process_file(filename) {
+            eprintln!("Error: {} {}", filename, e);
+            failed = true;
+        }
+    }
+    if failed {
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,1385 @@
+use std::sync::{Arc, Mutex};
+
+use anyhow::{bail, ensure, Result};
+use llguidance_parser::{
+    api::ParserLimits,
+    derivre::{RegexAst, RegexBuilder},
+    earley::{
+        lexer::{DfaBackend, LexemeDiagnostic, Lexer, LexerResult, WarmupMode},
+        lexerspec::{LexemeIdx, LexemeTemplate, LexerError, LexerSpec, SpecDiff},
+        regexvec::StateID,
+    },
+    lark::{lark_to_llguidance, parse_lark, SourceMap},
+    toktrie::SimpleVob,
+};
+
+/// Identifiers and numbers, separated by spaces.
+fn words_spec() -> Result<LexerSpec> {
+    let mut spec = LexerSpec::new(RegexBuilder::new(), RegexAst::Regex(" +".to_string()))?;
+    spec.add_greedy_lexeme(
+        "ID".to_string(),
+        RegexAst::Regex("[a-z]+".to_string()),
+        false,
+        None,
+    )?;
+    spec.add_greedy_lexeme(
+        "NUM".to_string(),
+        RegexAst::Regex("[0-9]+(\\.[0-9]+)?".to_string()),
+        false,
+        None,
+    )?;
+    Ok(spec)
+}
+
+/// Lex `input` with all lexemes allowed, as (name, text) pairs, without SKIP lexemes.
+fn lex<D: DfaBackend>(lexer: &mut Lexer<D>, input: &str) -> Result<Vec<(String, String)>> {
+    let allowed = lexer.lexer_spec().all_lexemes();
+    let views = lexer.lex_views(&allowed, input.as_bytes())?;
+    let spec = lexer.lexer_spec();
+    Ok(views
+        .iter()
+        .filter(|v| v.idx != LexemeIdx::SKIP)
+        .map(|v| {
+            (
+                spec.lexeme_spec(v.idx).name().to_string(),
+                String::from_utf8_lossy(v.visible_bytes()).to_string(),
+            )
+        })
+        .collect())
+}
+
+fn expect_lexemes(got: Vec<(String, String)>, expected: &[(&str, &str)]) -> Result<()> {
+    let expected = expected
+        .iter()
+        .map(|(name, text)| (name.to_string(), text.to_string()))
+        .collect::<Vec<_>>();
+    ensure!(got == expected, "expected {:?}, got {:?}", expected, got);
+    Ok(())
+}
+
+/// Feed `bytes` to the lexer starting in `state`, all within a single lexeme.
+fn walk<D: DfaBackend>(lexer: &mut Lexer<D>, mut state: StateID, bytes: &[u8]) -> Result<StateID> {
+    for &b in bytes {
+        state = match lexer.advance(state, b, false) {
+            LexerResult::State(s, _) => s,
+            r => bail!("byte {:?}: expected a state, got {:?}", b as char, r),
+        };
+    }
+    Ok(state)
+}
+
+/// Forks read the states realized before forking, and extend them independently.
+fn fork() -> Result<()> {
+    let spec = words_spec()?;
+    let mut base = Lexer::from(&spec, &mut ParserLimits::default())?.into_shared();
+    let n0 = base.warmup(&[b"abc 12"], WarmupMode::Lazy);
+
+    let mut f1 = base.fork();
+    let mut f2 = base.fork();
+    let mut f3 = base.fork();
+    ensure!(f1.dfa().num_sharing() == 4, "forks should share the DFA");
+
+    // same input as the warmup: only states realized by the base are used
+    ensure!(
+        f3.warmup(&[b"abc 12"], WarmupMode::Lazy) == n0,
+        "no new states expected"
+    );
+    ensure!(
+        !f3.dfa().is_copied(),
+        "fork copied the DFA for known states"
+    );
+
+    expect_lexemes(lex(&mut f1, "12.5 abc")?, &[("NUM", "12.5"), ("ID", "abc")])?;
+    ensure!(
+        f1.dfa().is_copied(),
+        "fork should copy the DFA for new states"
+    );
+    let n1 = f1.dfa().num_states();
+    ensure!(n1 > n0, "expected new states in the fork");
+
+    // the states realized by f1 are not visible to f2 and the base
+    ensure!(
+        f2.dfa().num_states() == n0,
+        "fork sees states of another fork"
+    );
+    ensure!(base.dfa().num_states() == n0, "base sees states of a fork");
+    expect_lexemes(lex(&mut f2, "7.25 x")?, &[("NUM", "7.25"), ("ID", "x")])?;
+    ensure!(
+        f2.dfa().num_states() == n1,
+        "forks realized different states"
+    );
+    ensure!(base.dfa().num_states() == n0, "base sees states of a fork");
+
+    // forking a fork shares the states it realized
+    let f4 = f1.fork();
+    ensure!(
+        f4.dfa().num_states() == n1 && !f4.dfa().is_copied(),
+        "fork of a fork should share its states"
+    );
+    Ok(())
+}
+
+/// Warmup realizes states ahead of time, without changing the results;
+/// input like the samples (lazy) or any input (eager) then needs no new states.
+fn warmup() -> Result<()> {
+    let spec = words_spec()?;
+    let input = "cab 21.5 x";
+    let expected = [("ID", "cab"), ("NUM", "21.5"), ("ID", "x")];
+
+    let mut cold = Lexer::from(&spec, &mut ParserLimits::default())?;
+    let n0 = cold.dfa().num_states();
+    expect_lexemes(lex(&mut cold, input)?, &expected)?;
+
+    for mode in [WarmupMode::Lazy, WarmupMode::Eager { max_states: 1000 }] {
+        let mut lexer = Lexer::from(&spec, &mut ParserLimits::default())?;
+        let samples: &[&[u8]] = match mode {
+            WarmupMode::Lazy => &[b"abc 12.0 y"],
+            WarmupMode::Eager { .. } => &[],
+        };
+        let n1 = lexer.warmup(samples, mode);
+        ensure!(n1 > n0, "{:?}: no states realized", mode);
+        ensure!(n1 == lexer.dfa().num_states(), "{:?}: wrong count", mode);
+        expect_lexemes(lex(&mut lexer, input)?, &expected)?;
+        ensure!(
+            lexer.dfa().num_states() == n1,
+            "{:?}: new states after warmup",
+            mode
+        );
+    }
+
+    let mut lexer = Lexer::from(&spec, &mut ParserLimits::default())?;
+    let n1 = lexer.warmup(&[], WarmupMode::Eager { max_states: n0 + 1 });
+    ensure!(n1 == n0 + 1, "eager warmup exceeded max_states");
+    lexer.check_error()?;
+    Ok(())
+}
+
+/// Specs corrupted through their public fields are reported.
+fn verify_invariants() -> Result<()> {
+    let spec = words_spec()?;
+    spec.verify_invariants()?;
+
+    let mut corrupted = vec![];
+    let mut swapped = spec.clone();
+    swapped.lexemes.swap(1, 2);
+    corrupted.push(("swapped lexemes", swapped, "at position 1 has index 2"));
+    let mut no_skip = spec.clone();
+    no_skip.lexemes.remove(0);
+    corrupted.push(("no SKIP", no_skip, "first lexeme is not SKIP"));
+    let mut after_eos = spec.clone();
+    after_eos.after_eos.push(LexemeIdx::new(7));
+    corrupted.push(("after_eos", after_eos, "unknown lexeme 7 allowed after EOS"));
+    let mut max_occurrences = spec.clone();
+    max_occurrences.max_occurrences.push((LexemeIdx::new(3), 1));
+    corrupted.push((
+        "max_occurrences",
+        max_occurrences,
+        "unknown lexeme 3 with max_occurrences",
+    ));
+    let mut extra = spec.clone();
+    extra.num_extra_lexemes = 3;
+    corrupted.push(("extra lexemes", extra, "too many extra lexemes"));
+
+    for (what, spec, expected) in corrupted {
+        match spec.verify_invariants() {
+            Ok(()) => bail!("{}: corruption not detected", what),
+            Err(e) => ensure!(
+                e.to_string().contains(expected),
+                "{}: unexpected error {}",
+                what,
+                e
+            ),
+        }
+    }
+    Ok(())
+}
+
+/// Errors in Lark grammars (from lexing, parsing and compiling), and lexer
+/// errors described with a `SourceMap`, have `line:column` locations,
+/// with columns counted in characters.
+fn error_locations() -> Result<()> {
+    let lark_error = |src: &str| match parse_lark(src).and_then(lark_to_llguidance) {
+        Ok(_) => "no error".to_string(),
+        Err(e) => e.to_string(),
+    };
+    for (src, expected) in [
+        ("start: \"\u{e9}\" @\n", "at 1:12: lexer error"),
+        ("start: \"a\"\nfoo \"b\"\n", "at 2:5: Expected token Colon"),
+        ("start: \"a\" foo\n", "at 1:8: unknown name"),
+    ] {
+        let msg = lark_error(src);
+        ensure!(
+            msg.starts_with(expected),
+            "{:?}: expected {:?}, got {:?}",
+            src,
+            expected,
+            msg
+        );
+    }
+
+    let map = SourceMap::new("ab\nc\u{e9}!");
+    let msg = map.describe_error(&LexerError::InvalidInput { start: 6, end: 7 });
+    ensure!(msg == "invalid input at 2:3..2:4", "got {:?}", msg);
+    Ok(())
+}
+
+/// A template expands to one lexeme per parameter, with the parameters
+/// matched literally (not as regexes).
+fn instantiate() -> Result<()> {
+    let mut spec = LexerSpec::new(RegexBuilder::new(), RegexAst::Regex(" +".to_string()))?;
+    let kw = spec.instantiate(&LexemeTemplate::new("KW", "{}"), &["get", "post", "put"])?;
+    let tag = spec.instantiate(&LexemeTemplate::new("TAG", "#{}"), &["a.b"])?;
+    ensure!(kw.len() == 3 && tag.len() == 1, "wrong number of lexemes");
+    let names = kw
+        .iter()
+        .chain(&tag)
+        .map(|idx| spec.lexeme_spec(*idx).name().to_string())
+        .collect::<Vec<_>>();
+    ensure!(
+        names == ["KW_get", "KW_post", "KW_put", "TAG_a.b"],
+        "got {:?}",
+        names
+    );
+
+    let mut lexer = Lexer::from(&spec, &mut ParserLimits::default())?;
+    expect_lexemes(
+        lex(&mut lexer, "put get #a.b post")?,
+        &[
+            ("KW_put", "put"),
+            ("KW_get", "get"),
+            ("TAG_a.b", "#a.b"),
+            ("KW_post", "post"),
+        ],
+    )?;
+    let all = spec.all_lexemes();
+    for input in ["pos", "gett", "#axb"] {
+        ensure!(
+            !lexer.is_full_match(&all, input.as_bytes()),
+            "{:?} matched",
+            input
+        );
+    }
+    Ok(())
+}
+
+/// With a single `[a-z]+` lexeme, only lowercase tokens are allowed,
+/// at the start and in the middle of the lexeme.
+fn compute_mask_for_vocab() -> Result<()> {
+    let mut spec = LexerSpec::new(RegexBuilder::new(), RegexAst::NoMatch)?;
+    spec.add_greedy_lexeme(
+        "ID".to_string(),
+        RegexAst::Regex("[a-z]+".to_string()),
+        false,
+        None,
+    )?;
+    let mut lexer = Lexer::from(&spec, &mut ParserLimits::default())?;
+    let vocab: &[&[u8]] = &[b"get", b"a", b"Abc", b"a1", b"", b"xyz", b"1", b"q_", b"zz"];
+
+    let start = lexer.start_state(&spec.all_lexemes(), None);
+    let mid = walk(&mut lexer, start, b"ab")?;
+    for state in [start, mid] {
+        let mask = lexer.compute_mask_for_vocab(state, vocab);
+        let allowed = mask.iter().collect::<Vec<_>>();
+        ensure!(allowed == [0, 1, 5, 8], "got {:?}", allowed);
+    }
+    Ok(())
+}
+
+/// The body ends at the first stop, which is hidden; the body can't end
+/// without a stop, and a stop on its own is the STOP lexeme.
+fn regex_with_stop() -> Result<()> {
+    let spec = LexerSpec::regex_with_stop("[a-z ]+", "\n\n")?;
+    let mut lexer = Lexer::from(&spec, &mut ParserLimits::default())?;
+    expect_lexemes(
+        lex(&mut lexer, "hi there\n\nok\n\n")?,
+        &[("BODY", "hi there"), ("BODY", "ok")],
+    )?;
+    expect_lexemes(lex(&mut lexer, "\n\n")?, &[("STOP", "")])?;
+    let all = spec.all_lexemes();
+    for (input, expected) in [
+        ("hi\n\n", true),
+        ("hi", false),
+        ("hi\n", false),
+        ("hi\n\n\n", false),
+    ] {
+        ensure!(
+            lexer.is_full_match(&all, input.as_bytes()) == expected,
+            "{:?}: expected full match={}",
+            input,
+            expected
+        );
+    }
+    ensure!(
+        LexerSpec::regex_with_stop("[a-z]+", "").is_err(),
+        "empty stop accepted"
+    );
+    Ok(())
+}
+
+/// Lexeme sets of a state are iterated in ascending index order,
+/// independently of the order of the regexes.
+fn lexeme_order() -> Result<()> {
+    let mut spec = LexerSpec::new(RegexBuilder::new(), RegexAst::NoMatch)?;
+    let mut ids = vec![];
+    for (name, rx) in [("Z", "[a-z]+"), ("AB", "ab"), ("AX", "a[a-z]"), ("A", "a")] {
+        ids.push(spec.add_greedy_lexeme(
+            name.to_string(),
+            RegexAst::Regex(rx.to_string()),
+            false,
+            None,
+        )?);
+    }
+    let mut lexer = Lexer::from(&spec, &mut ParserLimits::default())?;
+    let start = lexer.start_state(&spec.all_lexemes(), None);
+    let state = walk(&mut lexer, start, b"a")?;
+
+    let possible = lexer.possible_lexemes(state).iter().collect::<Vec<_>>();
+    let hint = lexer.current_lexeme_hint(state);
+    let accepting = hint.accepting.iter().collect::<Vec<_>>();
+    for (what, got, expected) in [
+        ("possible", possible, &ids[..]),
+        ("accepting", accepting, &[ids[0], ids[3]][..]),
+    ] {
+        let expected = expected
+            .iter()
+            .map(|idx| idx.as_usize() as u32)
+            .collect::<Vec<_>>();
+        ensure!(
+            got.windows(2).all(|w| w[0] < w[1]),
+            "{} not ascending: {:?}",
+            what,
+            got
+        );
+        ensure!(
+            got == expected,
+            "{}: expected {:?}, got {:?}",
+            what,
+            expected,
+            got
+        );
+    }
+    Ok(())
+}
+
+/// `is_byte_allowed()` agrees with `allowed_bytes()` on every byte,
+/// at the start, in the middle of a lexeme and in an accepting state.
+fn is_byte_allowed() -> Result<()> {
+    let spec = words_spec()?;
+    let mut lexer = Lexer::from(&spec, &mut ParserLimits::default())?;
+    let start = lexer.start_state(&spec.all_lexemes(), None);
+    let mid = walk(&mut lexer, start, b"12.")?;
+    let accepting = walk(&mut lexer, start, b"12.5")?;
+    for state in [start, mid, accepting] {
+        let allowed = lexer.allowed_bytes(state);
+        for b in 0..=255u8 {
+            ensure!(
+                lexer.is_byte_allowed(state, b) == allowed.get(b as usize),
+                "byte {} disagrees in {:?}",
+                b,
+                state
+            );
+        }
+    }
+    ensure!(
+        lexer.is_byte_allowed(mid, b'7') && !lexer.is_byte_allowed(mid, b'.'),
+        "wrong bytes allowed after \"12.\""
+    );
+    Ok(())
+}
+
+/// Construction failures are reported as specific `LexerError` variants.
+fn lexer_errors() -> Result<()> {
+    let mut spec = words_spec()?;
+    match spec.add_greedy_lexeme(
+        "BAD".to_string(),
+        RegexAst::Regex("[a-".to_string()),
+        false,
+        None,
+    ) {
+        Err(LexerError::InvalidRegex { lexeme, .. }) if lexeme == "BAD" => {}
+        r => bail!("malformed regex: expected InvalidRegex, got {:?}", r),
+    }
+    match spec.add_shadows(LexemeIdx::new(1), &[LexemeIdx::new(42)]) {
+        Err(LexerError::InvalidSpec(_)) => {}
+        r => bail!("unknown lexeme: expected InvalidSpec, got {:?}", r),
+    }
+
+    let mut limits = ParserLimits {
+        initial_lexer_fuel: 0,
+        ..ParserLimits::default()
+    };
+    match Lexer::from(&spec, &mut limits) {
+        Err(LexerError::BudgetExceeded(msg)) if msg.contains("fuel exhausted") => {}
+        Err(e) => bail!("no fuel: expected BudgetExceeded, got {:?}", e),
+        Ok(_) => bail!("no fuel: lexer built"),
+    }
+    Ok(())
+}
+
+/// The hint lists the lexemes still possible, and those accepting,
+/// at the start and in the middle of a lexeme.
+fn current_lexeme_hint() -> Result<()> {
+    let spec = words_spec()?;
+    let mut lexer = Lexer::from(&spec, &mut ParserLimits::default())?;
+    let start = lexer.start_state(&spec.all_lexemes(), None);
+    let (skip, id, num) = (0, 1, 2);
+    let expected: &[(&str, &[u32], &[u32])] = &[
+        ("", &[skip, id, num], &[]),
+        ("ab", &[id], &[id]),
+        ("12", &[num], &[num]),
+        ("12.", &[num], &[]),
+        ("12.5", &[num], &[num]),
+    ];
+    for (input, possible, accepting) in expected {
+        let state = walk(&mut lexer, start, input.as_bytes())?;
+        let hint = lexer.current_lexeme_hint(state);
+        ensure!(
+            hint.possible.iter().collect::<Vec<_>>() == *possible
+                && hint.accepting.iter().collect::<Vec<_>>() == *accepting
+                && hint.is_accepting == !accepting.is_empty(),
+            "{:?}: unexpected {:?}",
+            input,
+            hint
+        );
+    }
+    Ok(())
+}
+
+/// Recompiling with one lexeme changed and one added reuses the other lexemes,
+/// and gives the same results as a lexer built from scratch;
+/// the previous lexer is not affected.
+fn from_previous() -> Result<()> {
+    let spec = words_spec()?;
+    let mut prev = Lexer::from(&spec, &mut ParserLimits::default())?;
+    let num = LexemeIdx::new(2);
+    let diff = SpecDiff {
+        changed: vec![(num, RegexAst::Regex("[0-9]+".to_string()))],
+        added: vec![("OP".to_string(), RegexAst::Regex("[+*]".to_string()))],
+    };
+    let (mut lexer, num_reused) = Lexer::from_previous(&prev, &diff, &mut ParserLimits::default())?;
+    ensure!(
+        num_reused == 2,
+        "expected SKIP and ID reused, got {}",
+        num_reused
+    );
+
+    let mut scratch = words_spec()?;
+    diff.apply(&mut scratch)?;
+    let mut scratch = Lexer::from(&scratch, &mut ParserLimits::default())?;
+    let expected = [("NUM", "12"), ("ID", "ab"), ("OP", "+"), ("NUM", "3")];
+    expect_lexemes(lex(&mut lexer, "12 ab + 3")?, &expected)?;
+    expect_lexemes(lex(&mut scratch, "12 ab + 3")?, &expected)?;
+    ensure!(
+        lex(&mut lexer, "1.5").is_err(),
+        "changed lexeme kept its regex"
+    );
+    expect_lexemes(lex(&mut prev, "1.5")?, &[("NUM", "1.5")])?;
+
+    // a lexeme "changed" to its own regex compiles to the same expression
+    let same = SpecDiff {
+        changed: vec![(num, RegexAst::Regex("[0-9]+(\\.[0-9]+)?".to_string()))],
+        added: vec![],
+    };
+    let (_, num_reused) = Lexer::from_previous(&prev, &same, &mut ParserLimits::default())?;
+    ensure!(
+        num_reused == 3,
+        "expected all lexemes reused, got {}",
+        num_reused
+    );
+    Ok(())
+}
+
+/// With `enforce_utf8`, a lexeme matching any bytes only matches valid UTF-8,
+/// so a lone continuation byte (or a truncated character) is rejected.
+fn enforce_utf8() -> Result<()> {
+    let mut spec = LexerSpec::new(RegexBuilder::new(), RegexAst::NoMatch)?;
+    let any_byte = RegexAst::ByteSet(vec![u32::MAX; 8]);
+    spec.add_greedy_lexeme(
+        "ANY".to_string(),
+        RegexAst::Repeat(Box::new(any_byte), 1, u32::MAX),
+        false,
+        None,
+    )?;
+    let all = spec.all_lexemes();
+    let inputs: &[(&[u8], bool)] = &[
+        (b"abc", true),
+        ("\u{e9}".as_bytes(), true),
+        (b"\x80", false),
+        (b"a\x80", false),
+        (b"\xc3", false),
+    ];
+
+    let mut permissive = Lexer::from(&spec, &mut ParserLimits::default())?;
+    spec.enforce_utf8 = true;
+    let mut lexer = Lexer::from(&spec, &mut ParserLimits::default())?;
+    for (input, valid) in inputs {
+        ensure!(
+            permissive.is_full_match(&all, input),
+            "{:?} not matched without enforce_utf8",
+            input
+        );
+        ensure!(
+            lexer.is_full_match(&all, input) == *valid,
+            "{:?}: expected match={} with enforce_utf8",
+            input,
+            valid
+        );
+    }
+    Ok(())
+}
+
+/// Input valid up to some offset is lexed up to there, and the byte
+/// that could not be lexed is reported.
+fn parse_prefix() -> Result<()> {
+    let spec = words_spec()?;
+    let mut lexer = Lexer::from(&spec, &mut ParserLimits::default())?;
+    let all = spec.all_lexemes();
+    for (input, valid_len, failing_byte) in [
+        ("ab 12 c$d", 7, Some(b'$')),
+        ("$", 0, Some(b'$')),
+        ("ab 12.5 c", 9, None),
+    ] {
+        let r = lexer.parse_prefix(&all, input.as_bytes());
+        ensure!(
+            r.valid_len == valid_len && r.failing_byte == failing_byte,
+            "{:?}: expected {} valid bytes and {:?}, got {:?}",
+            input,
+            valid_len,
+            failing_byte,
+            r
+        );
+    }
+    Ok(())
+}
+
+/// A regex needing a state for every suffix of the input seen so far
+/// exceeds a low `max_lexer_states` while lexing, and is then reported as
+/// `DfaTooLarge`; the same input lexes fine under the default bound.
+fn max_lexer_states() -> Result<()> {
+    let mut spec = LexerSpec::new(RegexBuilder::new(), RegexAst::NoMatch)?;
+    spec.add_greedy_lexeme(
+        "X".to_string(),
+        RegexAst::Regex("[ab]*a[ab]{10}".to_string()),
+        false,
+        None,
+    )?;
+    // pseudo-random a/b sequence, so that many suffixes are seen
+    let mut seed = 1u32;
+    let input = (0..300)
+        .map(|_| {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            if (seed >> 16) & 1 == 0 {
+                'a'
+            } else {
+                'b'
+            }
+        })
+        .chain("a".repeat(11).chars())
+        .collect::<String>();
+
+    let mut lexer = Lexer::from(&spec, &mut ParserLimits::default())?;
+    let views = lex(&mut lexer, &input)?;
+    ensure!(views.len() == 1, "expected one lexeme, got {:?}", views);
+    lexer.check_error()?;
+
+    let mut limits = ParserLimits {
+        max_lexer_states: 50,
+        ..ParserLimits::default()
+    };
+    let mut lexer = Lexer::from(&spec, &mut limits)?;
+    ensure!(lex(&mut lexer, &input).is_err(), "lexed with 50 states");
+    match lexer.check_error() {
+        Err(LexerError::DfaTooLarge(_)) => {}
+        r => bail!("expected DfaTooLarge, got {:?}", r),
+    }
+    Ok(())
+}
+
+/// `step_options()` agrees with separate `allowed_bytes()` and `allows_eos()`
+/// calls, with all lexemes or only some of them allowed.
+fn step_options() -> Result<()> {
+    let spec = words_spec()?;
+    let mut lexer = Lexer::from(&spec, &mut ParserLimits::default())?;
+    let all = spec.all_lexemes();
+    let mut only_id = spec.alloc_lexeme_set();
+    only_id.set(1, true);
+    let eos = spec.eos_ending_lexemes();
+
+    let start = lexer.start_state(&all, None);
+    for input in ["", "ab", "12", "12."] {
+        let state = walk(&mut lexer, start, input.as_bytes())?;
+        for allowed in [&all, &only_id] {
+            let opts = lexer.step_options(state, allowed, &eos);
+            let limited = lexer.limit_state_to(state, allowed);
+            let bytes = lexer.allowed_bytes(limited).iter().collect::<Vec<_>>();
+            let eos_allowed = lexer.allows_eos(limited);
+            ensure!(
+                opts.allowed_bytes.iter().collect::<Vec<_>>() == bytes
+                    && opts.eos_allowed == eos_allowed,
+                "{:?}: {:?} disagrees with {:?}, eos={}",
+                input,
+                opts,
+                bytes,
+                eos_allowed
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Factoring common prefixes of literal alternatives doesn't change what
+/// the lexeme matches, and doesn't need more states.
+fn factor_prefixes() -> Result<()> {
+    let words = ["apple", "apply", "append", "apt", "banana", "band", "b"];
+    let mut lexers = vec![];
+    for factor_prefixes in [false, true] {
+        let mut spec = LexerSpec::new(RegexBuilder::new(), RegexAst::NoMatch)?;
+        spec.factor_prefixes = factor_prefixes;
+        let alternatives = words
+            .iter()
+            .map(|w| RegexAst::Literal(w.to_string()))
+            .collect();
+        spec.add_greedy_lexeme("FRUIT".to_string(), RegexAst::Or(alternatives), false, None)?;
+        let mut lexer = Lexer::from(&spec, &mut ParserLimits::default())?;
+        let num_states = lexer.warmup(&[], WarmupMode::Eager { max_states: 10_000 });
+        lexers.push((spec, lexer, num_states));
+    }
+
+    let (n_plain, n_factored) = (lexers[0].2, lexers[1].2);
+    ensure!(
+        n_factored <= n_plain,
+        "factored: {} states, plain: {}",
+        n_factored,
+        n_plain
+    );
+    let others = ["", "app", "appl", "applee", "ban", "bandana", "bb", "c"];
+    for input in words.iter().chain(&others) {
+        let mut matches = vec![];
+        for (spec, lexer, _) in &mut lexers {
+            matches.push(lexer.is_full_match(&spec.all_lexemes(), input.as_bytes()));
+        }
+        ensure!(
+            matches[0] == matches[1] && matches[0] == words.contains(input),
+            "{:?}: plain match={}, factored match={}",
+            input,
+            matches[0],
+            matches[1]
+        );
+    }
+    Ok(())
+}
+
+/// The hook is called once for every newly realized state,
+/// and not again when the states are revisited.
+fn on_state_realized() -> Result<()> {
+    let spec = words_spec()?;
+    let mut lexer = Lexer::from(&spec, &mut ParserLimits::default())?;
+    let realized = Arc::new(Mutex::new(vec![]));
+    let hook_realized = realized.clone();
+    lexer.set_on_state_realized(Some(Arc::new(move |state: StateID| {
+        hook_realized.lock().unwrap().push(state)
+    })));
+
+    let mut num_states = lexer.dfa().num_states();
+    for (input, expect_new) in [("abc 12", true), ("abc 12", false), ("12.5", true)] {
+        realized.lock().unwrap().clear();
+        lex(&mut lexer, input)?;
+        let new_states = lexer.dfa().num_states() - num_states;
+        num_states = lexer.dfa().num_states();
+        let mut calls = realized.lock().unwrap().clone();
+        ensure!(
+            calls.len() == new_states && (new_states > 0) == expect_new,
+            "{:?}: {} calls for {} new states",
+            input,
+            calls.len(),
+            new_states
+        );
+        calls.sort_by_key(|s| s.as_usize());
+        calls.dedup();
+        ensure!(
+            calls.len() == new_states,
+            "{:?}: state reported twice",
+            input
+        );
+    }
+    Ok(())
+}
+
+/// After `precompute_eos()`, EOS is allowed exactly where one of the given
+/// lexemes is accepting, both in states realized before (cached) and after
+/// (computed on demand); `reset_session()` restores the lexemes from the spec.
+fn precompute_eos() -> Result<()> {
+    let spec = words_spec()?;
+    let mut lexer = Lexer::from(&spec, &mut ParserLimits::default())?;
+    let all = spec.all_lexemes();
+    let start = lexer.start_state(&all, None);
+    let mut only_num = spec.alloc_lexeme_set();
+    only_num.set(2, true);
+
+    let inputs = ["", "ab", "12", "12.", "12.5"];
+    let mut states = vec![];
+    for input in &inputs[..3] {
+        states.push((*input, walk(&mut lexer, start, input.as_bytes())?));
+    }
+    lexer.precompute_eos(&only_num);
+    for input in &inputs[3..] {
+        states.push((*input, walk(&mut lexer, start, input.as_bytes())?));
+    }
+    let check = |lexer: &mut Lexer, eos_lexemes: &SimpleVob, what: &str| -> Result<()> {
+        for (input, state) in &states {
+            let mut expected = eos_lexemes.clone();
+            expected.and(&lexer.current_lexeme_hint(*state).accepting);
+            ensure!(
+                lexer.allows_eos(*state) == !expected.is_zero(),
+                "{}: {:?}: wrong allows_eos()",
+                what,
+                input
+            );
+        }
+        Ok(())
+    };
+    check(&mut lexer, &only_num, "precomputed")?;
+    ensure!(
+        !lexer.allows_eos(states[1].1) && lexer.allows_eos(states[2].1),
+        "EOS should be allowed only after numbers"
+    );
+    lexer.reset_session();
+    check(&mut lexer, &spec.eos_ending_lexemes(), "reset")?;
+    Ok(())
+}
+
+/// Lexeme views point into the input buffer (nothing is copied),
+/// at their offsets, and together they cover the whole input.
+fn lex_views() -> Result<()> {
+    let spec = words_spec()?;
+    let mut lexer = Lexer::from(&spec, &mut ParserLimits::default())?;
+    let all = spec.all_lexemes();
+    let input = b"ab 12.5 c".to_vec();
+    let views = lexer.lex_views(&all, &input)?;
+    let expected: &[(usize, &str)] = &[(1, "ab"), (0, " "), (2, "12.5"), (0, " "), (1, "c")];
+    ensure!(
+        views.len() == expected.len(),
+        "expected {} lexemes, got {:?}",
+        expected.len(),
+        views
+    );
+    let buf = input.as_ptr_range();
+    let buf = buf.start as usize..buf.end as usize;
+    let mut offset = 0;
+    for (view, (idx, text)) in views.iter().zip(expected) {
+        ensure!(
+            view.idx.as_usize() == *idx && view.bytes == text.as_bytes() && view.start == offset,
+            "expected {:?} at {}, got {:?}",
+            text,
+            offset,
+            view
+        );
+        let range = view.bytes.as_ptr_range();
+        ensure!(
+            range.start as usize == buf.start + offset && range.end as usize <= buf.end,
+            "{:?}: bytes not borrowed from the input",
+            text
+        );
+        ensure!(
+            view.hidden_len == 0 && view.visible_bytes() == view.bytes,
+            "{:?}: hidden bytes",
+            text
+        );
+        offset += text.len();
+    }
+    ensure!(offset == input.len(), "input not fully covered");
+    Ok(())
+}
+
+/// Only digits and letters can start the lexemes below, and
+/// `initial_allowed_bytes()` reflects every lexeme of the allowed set.
+fn initial_allowed_bytes() -> Result<()> {
+    let mut spec = LexerSpec::new(RegexBuilder::new(), RegexAst::NoMatch)?;
+    spec.add_greedy_lexeme(
+        "ID".to_string(),
+        RegexAst::Regex("[a-z][a-z0-9_-]*".to_string()),
+        false,
+        None,
+    )?;
+    spec.add_greedy_lexeme(
+        "NUM".to_string(),
+        RegexAst::Regex("[0-9]+(\\.[0-9]+)?".to_string()),
+        false,
+        None,
+    )?;
+    let mut lexer = Lexer::from(&spec, &mut ParserLimits::default())?;
+    let digits = (b'0'..=b'9').map(|b| b as u32).collect::<Vec<_>>();
+    let letters = (b'a'..=b'z').map(|b| b as u32).collect::<Vec<_>>();
+
+    let mut only_num = spec.alloc_lexeme_set();
+    only_num.set(2, true);
+    for (what, allowed, expected) in [
+        (
+            "all",
+            spec.all_lexemes(),
+            [digits.clone(), letters].concat(),
+        ),
+        ("NUM", only_num, digits),
+    ] {
+        let got = lexer
+            .initial_allowed_bytes(&allowed)
+            .iter()
+            .collect::<Vec<_>>();
+        ensure!(
+            got == expected,
+            "{}: expected {:?}, got {:?}",
+            what,
+            expected,
+            got
+        );
+    }
+    Ok(())
+}
+
+/// A spec in the compact DSL lexes as defined, including literal and
+/// flagged regex lexemes; errors carry the line number, errors from the spec
+/// keep their kind, and two lines defining the same lexeme are rejected.
+fn parse_dsl() -> Result<()> {
+    let spec = LexerSpec::parse_dsl(
+        r#"
+        # keywords come first, so they win over ID
+        SKIP: /[ \t]+/
+        KW_IF: "if"
+        ID: /[a-z_][a-z0-9_]*/i
+        NUM: /[0-9]+/
+        "#,
+    )?;
+    let mut lexer = Lexer::from(&spec, &mut ParserLimits::default())?;
+    expect_lexemes(
+        lex(&mut lexer, "if Foo_1 \t 12 iff")?,
+        &[
+            ("KW_IF", "if"),
+            ("ID", "Foo_1"),
+            ("NUM", "12"),
+            ("ID", "iff"),
+        ],
+    )?;
+
+    for (src, expected) in [
+        ("ID: /[a-z]+/\nNUM: /[0-9]+", "line 2: unterminated regex"),
+        (
+            "ID: /[a-z]+/\n\nNUM: [0-9]+",
+            "line 3: expecting a string literal",
+        ),
+        ("ID: /[a-z]+/ greedy", "line 1: unknown flag \"greedy\""),
+        ("A: /[ab]/\nB: /[ab]/", "line 2: same lexeme as A on line 1"),
+        ("SKIP: / +/\nSKIP: /\\t+/", "line 2: SKIP defined twice"),
+    ] {
+        match LexerSpec::parse_dsl(src) {
+            Err(LexerError::InvalidSpec(msg)) if msg.starts_with(expected) => {}
+            r => bail!("{:?}: expected {:?}, got {:?}", src, expected, r.err()),
+        }
+    }
+    match LexerSpec::parse_dsl("ID: /[a-z]+/\nBAD: /[z-a]/") {
+        Err(LexerError::InvalidRegex { lexeme, .. }) if lexeme == "BAD" => {}
+        r => bail!("expected InvalidRegex for BAD, got {:?}", r.err()),
+    }
+    Ok(())
+}
+
+/// Two sessions through one lexer give the same results; the second one
+/// reuses the states realized by the first, so it spends no fuel,
+/// and `reset_session()` clears the fuel spent by the first one.
+fn reset_session() -> Result<()> {
+    let spec = words_spec()?;
+    let mut lexer = Lexer::from(&spec, &mut ParserLimits::default())?;
+    let input = "abc 12.5 x";
+    let expected = [("ID", "abc"), ("NUM", "12.5"), ("ID", "x")];
+
+    expect_lexemes(lex(&mut lexer, input)?, &expected)?;
+    let fuel = lexer.session_fuel_spent();
+    let num_states = lexer.dfa().num_states();
+    ensure!(fuel > 0, "first session spent no fuel");
+
+    lexer.reset_session();
+    ensure!(
+        lexer.session_fuel_spent() == 0,
+        "fuel not reset: {}",
+        lexer.session_fuel_spent()
+    );
+    expect_lexemes(lex(&mut lexer, input)?, &expected)?;
+    ensure!(
+        lexer.session_fuel_spent() == 0 && lexer.dfa().num_states() == num_states,
+        "second session realized states: fuel {} (was {}), {} states (was {})",
+        lexer.session_fuel_spent(),
+        fuel,
+        lexer.dfa().num_states(),
+        num_states
+    );
+    Ok(())
+}
+
+/// A lexeme with a 3-byte stop builds under `max_lookahead` of 3,
+/// but not of 2; the bound applies to the longest match of the stop,
+/// and an unbounded stop is rejected under any bound.
+fn max_lookahead() -> Result<()> {
+    for (stop, max_lookahead, expected) in [
+        ("end", 3, None),
+        (
+            "end",
+            2,
+            Some("lookahead needs up to 3 bytes, above the limit of 2"),
+        ),
+        ("en?", 2, None),
+        (
+            "en?d",
+            2,
+            Some("lookahead needs up to 3 bytes, above the limit of 2"),
+        ),
+        (
+            ";+",
+            100,
+            Some("lookahead length is unbounded, above the limit of 100"),
+        ),
+    ] {
+        let mut spec = LexerSpec::new(RegexBuilder::new(), RegexAst::NoMatch)?;
+        spec.max_lookahead = max_lookahead;
+        let r = spec.add_rx_and_stop(
+            "BODY".to_string(),
+            RegexAst::Regex("[a-z ]*".to_string()),
+            RegexAst::Regex(stop.to_string()),
+            false,
+        );
+        match (r, expected) {
+            (Ok(_), None) => {}
+            (Err(LexerError::InvalidRegex { lexeme, message }), Some(msg))
+                if lexeme == "BODY" && message == msg => {}
+            (r, _) => bail!(
+                "{:?} with max_lookahead {}: expected {:?}, got {:?}",
+                stop,
+                max_lookahead,
+                expected,
+                r
+            ),
+        }
+    }
+    Ok(())
+}
+
+/// `[a-z]*a[a-z]{12}` needs a new DFA state for most bytes of the input
+/// (it tracks which of the last 13 bytes were 'a'), while `[a-z]+` only needs one;
+/// under a budget that the benign lexeme stays within, the pathological one
+/// is stopped, and the error names it.
+fn lexeme_fuel_budget() -> Result<()> {
+    let input = "ab".repeat(20);
+    let lexer_for = |rx: &str| -> Result<Lexer> {
+        let mut spec = LexerSpec::new(RegexBuilder::new(), RegexAst::NoMatch)?;
+        spec.add_greedy_lexeme(
+            "X".to_string(),
+            RegexAst::Regex(rx.to_string()),
+            false,
+            None,
+        )?;
+        let mut lexer = Lexer::from(&spec, &mut ParserLimits::default())?;
+        lexer.reset_session();
+        Ok(lexer)
+    };
+    let fuel_for = |rx: &str| -> Result<u64> {
+        let mut lexer = lexer_for(rx)?;
+        lex(&mut lexer, &input)?;
+        Ok(lexer.session_fuel_spent())
+    };
+
+    let benign = "[a-z]+";
+    let pathological = "[a-z]*a[a-z]{12}";
+    let budget = fuel_for(benign)?;
+    ensure!(
+        fuel_for(pathological)? > budget,
+        "pathological lexeme is not more expensive"
+    );
+
+    let mut lexer = lexer_for(benign)?;
+    lexer.set_lexeme_fuel_budget(Some(budget));
+    expect_lexemes(lex(&mut lexer, &input)?, &[("X", input.as_str())])?;
+    ensure!(lexer.check_error().is_ok(), "benign lexeme over budget");
+
+    let mut lexer = lexer_for(pathological)?;
+    lexer.set_lexeme_fuel_budget(Some(budget));
+    ensure!(
+        lex(&mut lexer, &input).is_err(),
+        "pathological lexeme not stopped"
+    );
+    match lexer.check_error() {
+        Err(LexerError::LexemeBudgetExceeded { lexeme, budget: b })
+            if lexeme == "X" && b == budget => {}
+        r => bail!("expected LexemeBudgetExceeded, got {:?}", r),
+    }
+    Ok(())
+}
+
+/// Spaces and a comment (separate SKIP lexemes) before an identifier
+/// make up a single trivia range, and the input can be rebuilt from
+/// the trivia and lexemes, plus the trailing trivia.
+fn lex_views_with_trivia() -> Result<()> {
+    let mut spec = LexerSpec::new(
+        RegexBuilder::new(),
+        RegexAst::Regex(" +|#[^\\n]*\\n".to_string()),
+    )?;
+    spec.add_greedy_lexeme(
+        "ID".to_string(),
+        RegexAst::Regex("[a-z]+".to_string()),
+        false,
+        None,
+    )?;
+    let mut lexer = Lexer::from(&spec, &mut ParserLimits::default())?;
+    let input = "ab  # hi\n  cd #x\n";
+    let lexemes = lexer.lex_views_with_trivia(&spec.all_lexemes(), input.as_bytes())?;
+    let got = lexemes
+        .iter()
+        .map(|l| (l.leading_trivia.clone(), l.lexeme.bytes))
+        .collect::<Vec<_>>();
+    let expected = [(0..0, &b"ab"[..]), (2..11, &b"cd"[..])];
+    ensure!(got == expected, "expected {:?}, got {:?}", expected, got);
+
+    let mut rebuilt = vec![];
+    for l in &lexemes {
+        rebuilt.extend_from_slice(&input.as_bytes()[l.leading_trivia.clone()]);
+        rebuilt.extend_from_slice(l.lexeme.bytes);
+    }
+    let last = lexemes.last().unwrap().lexeme;
+    rebuilt.extend_from_slice(&input.as_bytes()[last.start + last.bytes.len()..]);
+    ensure!(
+        rebuilt == input.as_bytes(),
+        "got {:?}",
+        String::from_utf8_lossy(&rebuilt)
+    );
+    Ok(())
+}
+
+/// With the boundary check on, an ID ended by a byte that cannot start
+/// any lexeme ('$') gets a diagnostic, while one ended by a space
+/// (which starts SKIP) does not; with the check off, there are no diagnostics.
+fn boundary_check() -> Result<()> {
+    let spec = words_spec()?;
+    let mut lexer = Lexer::from(&spec, &mut ParserLimits::default())?;
+    let all = spec.all_lexemes();
+    let start = lexer.start_state(&all, None);
+    let state = walk(&mut lexer, start, b"ab")?;
+    for (enabled, byte, expected) in [
+        (true, b'$', Some(LexemeDiagnostic::NoLexemeStartsWith(b'$'))),
+        (true, b' ', None),
+        (false, b'$', None),
+    ] {
+        lexer.set_boundary_check(enabled);
+        match lexer.advance(state, byte, false) {
+            LexerResult::Lexeme(p) if p.idx == LexemeIdx::new(1) && p.diagnostic == expected => {}
+            r => bail!(
+                "check={} {:?}: expected ID with {:?}, got {:?}",
+                enabled,
+                byte as char,
+                expected,
+                r
+            ),
+        }
+    }
+    Ok(())
+}
+
+/// With `[0-9]{3}`, only tokens bringing the lexeme to exactly three digits
+/// complete it: one-digit tokens after two digits, two-digit ones after one;
+/// tokens that only continue it are allowed, but not completing.
+fn completing_tokens() -> Result<()> {
+    let mut spec = LexerSpec::new(RegexBuilder::new(), RegexAst::NoMatch)?;
+    spec.add_greedy_lexeme(
+        "NUM".to_string(),
+        RegexAst::Regex("[0-9]{3}".to_string()),
+        false,
+        None,
+    )?;
+    let mut lexer = Lexer::from(&spec, &mut ParserLimits::default())?;
+    let vocab: &[&[u8]] = &[b"3", b"34", b"345", b"a", b"", b"9", b"3a"];
+
+    let start = lexer.start_state(&spec.all_lexemes(), None);
+    let cases: &[(&str, &[u32], &[u32])] = &[
+        ("12", &[0, 5], &[0, 5]),
+        ("1", &[0, 1, 5], &[1]),
+        ("", &[0, 1, 2, 5], &[2]),
+    ];
+    for (prefix, allowed, completing) in cases {
+        let state = walk(&mut lexer, start, prefix.as_bytes())?;
+        let mask = lexer
+            .compute_mask_for_vocab(state, vocab)
+            .iter()
+            .collect::<Vec<_>>();
+        let done = lexer
+            .completing_tokens(state, vocab)
+            .iter()
+            .collect::<Vec<_>>();
+        ensure!(
+            mask == *allowed && done == *completing,
+            "{:?}: expected {:?} allowed and {:?} completing, got {:?} and {:?}",
+            prefix,
+            allowed,
+            completing,
+            mask,
+            done
+        );
+    }
+    Ok(())
+}
+
+/// A set of 5000 strings (all the 1 to 3 letter words, in order, up to
+/// "gjh"), many a prefix of another, matches greedily and reports the index
+/// of the matched string; lexing with it costs no more fuel (including
+/// lexer construction) than with the plain alternative of the literals.
+fn add_string_set() -> Result<()> {
+    let words = (0..5000)
+        .map(|mut i: usize| {
+            let mut w = vec![];
+            loop {
+                w.insert(0, b'a' + (i % 26) as u8);
+                if i < 26 {
+                    break;
+                }
+                i = i / 26 - 1;
+            }
+            String::from_utf8(w).unwrap()
+        })
+        .collect::<Vec<_>>();
+    let word_refs = words.iter().map(|w| w.as_str()).collect::<Vec<_>>();
+    let input = "a z aa zz aaa gjh ab gji";
+    let expected_words = ["a", "z", "aa", "zz", "aaa", "gjh", "ab", "gj", "i"];
+
+    let mut fuel = vec![];
+    for use_set in [true, false] {
+        let mut spec = LexerSpec::new(RegexBuilder::new(), RegexAst::Regex(" +".to_string()))?;
+        let idx = if use_set {
+            spec.add_string_set("WORD".to_string(), &word_refs)?
+        } else {
+            let literals = words.iter().map(|w| RegexAst::Literal(w.clone())).collect();
+            spec.add_greedy_lexeme("WORD".to_string(), RegexAst::Or(literals), false, None)?
+        };
+        let mut lexer = Lexer::from(&spec, &mut ParserLimits::default())?;
+        let got = lex(&mut lexer, input)?;
+        let expected = expected_words
+            .iter()
+            .map(|w| ("WORD", *w))
+            .collect::<Vec<_>>();
+        expect_lexemes(got.clone(), &expected)?;
+        if use_set {
+            for (_, w) in &got {
+                let i = spec.string_set_index(idx, w.as_bytes());
+                ensure!(
+                    i.map(|i| words[i].as_str()) == Some(w.as_str()),
+                    "{:?}: wrong index {:?}",
+                    w,
+                    i
+                );
+            }
+        }
+        fuel.push(lexer.session_fuel_spent());
+    }
+    ensure!(
+        fuel[0] <= fuel[1],
+        "string set spent {} fuel, the alternative {}",
+        fuel[0],
+        fuel[1]
+    );
+    Ok(())
+}
+
+/// Both errors are collected, each covering the bytes skipped up to the next
+/// byte that can start a lexeme, with the valid lexemes around them.
+fn lex_all_collecting_errors() -> Result<()> {
+    let spec = words_spec()?;
+    let mut lexer = Lexer::from(&spec, &mut ParserLimits::default())?;
+    let input = "ab $ 12 %% cd";
+    let (views, errors) = lexer.lex_all_collecting_errors(&spec.all_lexemes(), input.as_bytes());
+    let got = views
+        .iter()
+        .filter(|v| v.idx != LexemeIdx::SKIP)
+        .map(|v| (v.start, String::from_utf8_lossy(v.bytes).to_string()))
+        .collect::<Vec<_>>();
+    let expected = [(0, "ab"), (5, "12"), (11, "cd")]
+        .iter()
+        .map(|(start, text)| (*start, text.to_string()))
+        .collect::<Vec<_>>();
+    ensure!(got == expected, "expected {:?}, got {:?}", expected, got);
+    let expected_errors = [
+        LexerError::InvalidInput { start: 3, end: 4 },
+        LexerError::InvalidInput { start: 8, end: 10 },
+    ];
+    ensure!(
+        errors == expected_errors,
+        "expected {:?}, got {:?}",
+        expected_errors,
+        errors
+    );
+    Ok(())
+}
+
+/// Strip leading zeros, but keep the last digit ("000" becomes "0").
+fn strip_leading_zeros(bytes: &[u8]) -> Vec<u8> {
+    let n = bytes.iter().take_while(|&&b| b == b'0').count();
+    bytes[n.min(bytes.len().saturating_sub(1))..].to_vec()
+}
+
+/// A NUM lexeme stripping leading zeros on emit: the transformed value
+/// is available both on views and on streamed lexemes, while the original
+/// bytes and offsets are kept; other lexemes are unchanged.
+fn lexeme_transform() -> Result<()> {
+    let mut spec = words_spec()?;
+    let num = LexemeIdx::new(2);
+    spec.set_lexeme_transform(num, Some(strip_leading_zeros));
+    let mut lexer = Lexer::from(&spec, &mut ParserLimits::default())?;
+    let all = spec.all_lexemes();
+    let input = "007 x 000 120";
+    let to_string = |bytes: &[u8]| String::from_utf8_lossy(bytes).to_string();
+    let expected = [
+        (0, "007", "7"),
+        (4, "x", "x"),
+        (6, "000", "0"),
+        (10, "120", "120"),
+    ]
+    .iter()
+    .map(|(start, bytes, value)| (*start, bytes.to_string(), value.to_string()))
+    .collect::<Vec<_>>();
+
+    let views = lexer.lex_views(&all, input.as_bytes())?;
+    let got = views
+        .iter()
+        .filter(|v| v.idx != LexemeIdx::SKIP)
+        .map(|v| {
+            (
+                v.start,
+                to_string(v.visible_bytes()),
+                to_string(&v.value(&spec)),
+            )
+        })
+        .collect::<Vec<_>>();
+    ensure!(
+        got == expected,
+        "views: expected {:?}, got {:?}",
+        expected,
+        got
+    );
+
+    let mut stream = lexer.lex_stream(&all);
+    let mut lexemes = stream.push(input.as_bytes())?;
+    lexemes.extend(stream.finish()?);
+    let got = lexemes
+        .iter()
+        .filter(|l| l.idx != LexemeIdx::SKIP)
+        .map(|l| (to_string(l.visible_bytes()), to_string(l.value())))
+        .collect::<Vec<_>>();
+    let expected = expected
+        .into_iter()
+        .map(|(_, bytes, value)| (bytes, value))
+        .collect::<Vec<_>>();
+    ensure!(
+        got == expected,
+        "stream: expected {:?}, got {:?}",
+        expected,
+        got
+    );
+    Ok(())
+}
+
+/// The product of a `[a-z]+` lexer and a `.{3}` lexer matches exactly the
+/// 3-letter lowercase strings, and maps its lexeme back to the pair;
+/// intersecting with digits only is reported as empty.
+fn intersect() -> Result<()> {
+    let lexer_for = |name: &str, rx: &str| -> Result<Lexer> {
+        let mut spec = LexerSpec::new(RegexBuilder::new(), RegexAst::NoMatch)?;
+        spec.add_greedy_lexeme(
+            name.to_string(),
+            RegexAst::Regex(rx.to_string()),
+            false,
+            None,
+        )?;
+        Ok(Lexer::from(&spec, &mut ParserLimits::default())?)
+    };
+    let letters = lexer_for("ID", "[a-z]+")?;
+    let three = lexer_for("ANY3", "(?s:.{3})")?;
+
+    let (mut lexer, mapping) = letters.intersect(&three, &mut ParserLimits::default())?;
+    let one = LexemeIdx::new(1);
+    ensure!(
+        mapping == [(LexemeIdx::SKIP, LexemeIdx::SKIP), (one, one)],
+        "got mapping {:?}",
+        mapping
+    );
+    let name = lexer.lexer_spec().lexeme_spec(one).name().to_string();
+    ensure!(name == "ID&ANY3", "got name {:?}", name);
+    let all = lexer.lexer_spec().all_lexemes();
+    for (input, expected) in [
+        ("abc", true),
+        ("zzz", true),
+        ("ab", false),
+        ("abcd", false),
+        ("aBc", false),
+        ("a1c", false),
+    ] {
+        ensure!(
+            lexer.is_full_match(&all, input.as_bytes()) == expected,
+            "{:?}: expected match={}",
+            input,
+            expected
+        );
+    }
+
+    let digits = lexer_for("NUM", "[0-9]+")?;
+    match letters.intersect(&digits, &mut ParserLimits::default()) {
+        Err(LexerError::InvalidSpec(msg))
+            if msg.contains("intersection of the lexers is empty") => {}
+        r => bail!("expected an empty intersection, got {:?}", r.err()),
+    }
+    Ok(())
+}
+
+fn main() {
+    let tests: &[(&str, fn() -> Result<()>)] = &[
+        ("fork", fork),
+        ("verify_invariants", verify_invariants),
+        ("error_locations", error_locations),
+        ("warmup", warmup),
+        ("instantiate", instantiate),
+        ("compute_mask_for_vocab", compute_mask_for_vocab),
+        ("regex_with_stop", regex_with_stop),
+        ("lexeme_order", lexeme_order),
+        ("is_byte_allowed", is_byte_allowed),
+        ("lexer_errors", lexer_errors),
+        ("current_lexeme_hint", current_lexeme_hint),
+        ("from_previous", from_previous),
+        ("enforce_utf8", enforce_utf8),
+        ("parse_prefix", parse_prefix),
+        ("max_lexer_states", max_lexer_states),
+        ("step_options", step_options),
+        ("factor_prefixes", factor_prefixes),
+        ("on_state_realized", on_state_realized),
+        ("precompute_eos", precompute_eos),
+        ("lex_views", lex_views),
+        ("initial_allowed_bytes", initial_allowed_bytes),
+        ("parse_dsl", parse_dsl),
+        ("reset_session", reset_session),
+        ("max_lookahead", max_lookahead),
+        ("lexeme_fuel_budget", lexeme_fuel_budget),
+        ("lex_views_with_trivia", lex_views_with_trivia),
+        ("boundary_check", boundary_check),
+        ("completing_tokens", completing_tokens),
+        ("add_string_set", add_string_set),
+        ("lex_all_collecting_errors", lex_all_collecting_errors),
+        ("lexeme_transform", lexeme_transform),
+        ("intersect", intersect),
+    ];
+
+    let mut num_failed = 0;
+    for (name, test) in tests {
+        match test() {
+            Ok(()) => println!("{} OK", name),
+            Err(e) => {
+                eprintln!("FAIL {}: {}", name, e);
+                num_failed += 1;
+            }
+        }
+    }
+    if num_failed > 0 {
+        eprintln!("{} test(s) failed", num_failed);
+        std::process::exit(1);
+    }
+}
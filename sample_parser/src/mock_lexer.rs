@@ -0,0 +1,172 @@
+use llguidance_parser::{
+    derivre::{RegexAst, RegexBuilder},
+    earley::{
+        lexer::{DfaBackend, Lexer, LexerResult},
+        lexerspec::{LexemeIdx, LexerSpec},
+        regexvec::{NextByte, StateDesc, StateID},
+    },
+    toktrie::SimpleVob,
+};
+
+// state 0 is StateID::DEAD, and 1 is reserved for StateID::MISSING
+const START: u32 = 2;
+const AFTER_A: u32 = 3;
+const AFTER_AB: u32 = 4;
+
+/// Hand-built automaton for lexemes `a` and `ab`.
+/// Limiting states to a subset of lexemes is not supported.
+struct MockDfa {
+    descs: Vec<StateDesc>,
+    // (state, byte, next state); other transitions lead to the dead state
+    transitions: Vec<(u32, u8, u32)>,
+}
+
+impl MockDfa {
+    fn new(spec: &LexerSpec, a: LexemeIdx, ab: LexemeIdx) -> Self {
+        let set = |lexemes: &[LexemeIdx]| {
+            let mut v = spec.alloc_lexeme_set();
+            for idx in lexemes {
+                v.set(idx.as_usize(), true);
+            }
+            v
+        };
+        let desc = |state: StateID, possible: &[LexemeIdx], accepting: &[LexemeIdx]| {
+            StateDesc::new(
+                state,
+                accepting.first().map(|idx| idx.as_usize()),
+                set(accepting),
+                set(possible),
+                None,
+            )
+        };
+        MockDfa {
+            descs: vec![
+                desc(StateID::DEAD, &[], &[]),
+                desc(StateID::MISSING, &[], &[]),
+                desc(StateID::new(START), &[a, ab], &[]),
+                desc(StateID::new(AFTER_A), &[a, ab], &[a]),
+                desc(StateID::new(AFTER_AB), &[ab], &[ab]),
+            ],
+            transitions: vec![(START, b'a', AFTER_A), (AFTER_A, b'b', AFTER_AB)],
+        }
+    }
+
+    fn next_states(&self, state: StateID) -> impl Iterator<Item = &(u32, u8, u32)> {
+        self.transitions
+            .iter()
+            .filter(move |(from, _, _)| *from as usize == state.as_usize())
+    }
+}
+
+impl DfaBackend for MockDfa {
+    fn initial_state(&mut self, selected: &SimpleVob) -> StateID {
+        let mut possible = selected.clone();
+        possible.and(&self.descs[START as usize].possible);
+        if possible.is_zero() {
+            StateID::DEAD
+        } else {
+            StateID::new(START)
+        }
+    }
+
+    fn transition(&mut self, state: StateID, b: u8) -> StateID {
+        match self.next_states(state).find(|(_, byte, _)| *byte == b) {
+            Some(&(_, _, to)) => StateID::new(to),
+            None => StateID::DEAD,
+        }
+    }
+
+    fn state_desc(&self, state: StateID) -> &StateDesc {
+        &self.descs[state.as_usize()]
+    }
+
+    fn possible_lookahead_len(&mut self, _state: StateID) -> usize {
+        0
+    }
+
+    fn lookahead_len(&mut self, state: StateID) -> Option<usize> {
+        self.state_desc(state).lowest_accepting.map(|_| 0)
+    }
+
+    fn lowest_match(&mut self, _state: StateID) -> Option<(usize, usize)> {
+        None
+    }
+
+    fn next_byte(&mut self, state: StateID) -> NextByte {
+        let mut next = self.next_states(state);
+        match (next.next(), next.next()) {
+            (None, _) if self.state_desc(state).is_accepting() => NextByte::ForcedEOI,
+            (None, _) => NextByte::Dead,
+            (Some(&(_, b, _)), None) => NextByte::ForcedByte(b),
+            _ => NextByte::SomeBytes,
+        }
+    }
+
+    fn limit_state_to(&mut self, state: StateID, _allowed_lexemes: &SimpleVob) -> StateID {
+        state
+    }
+
+    fn has_error(&self) -> bool {
+        false
+    }
+
+    fn num_states(&self) -> usize {
+        // not counting StateID::MISSING
+        self.descs.len() - 1
+    }
+}
+
+fn expect_state(r: LexerResult, expected: u32) -> StateID {
+    match r {
+        LexerResult::State(s, _) if s.as_usize() == expected as usize => s,
+        _ => panic!("expected state {}, got {:?}", expected, r),
+    }
+}
+
+fn expect_lexeme(r: LexerResult, idx: LexemeIdx, byte_next_row: bool) {
+    match r {
+        LexerResult::Lexeme(p) if p.idx == idx && p.byte_next_row == byte_next_row => {}
+        _ => panic!("expected lexeme {:?}, got {:?}", idx, r),
+    }
+}
+
+fn main() {
+    let mut spec = LexerSpec::new(RegexBuilder::new(), RegexAst::NoMatch).unwrap();
+    let a = spec
+        .add_simple_literal("A".to_string(), "a", false)
+        .unwrap();
+    let ab = spec
+        .add_simple_literal("AB".to_string(), "ab", false)
+        .unwrap();
+    let mut lexer = Lexer::with_backend(&spec, MockDfa::new(&spec, a, ab));
+    let all = spec.all_lexemes();
+
+    let start = lexer.start_state(&all, None);
+    assert_eq!(start.as_usize(), START as usize);
+
+    // accepting, but `ab` is still possible, so the lexeme goes on
+    let after_a = expect_state(lexer.advance(start, b'a', false), AFTER_A);
+    let after_ab = expect_state(lexer.advance(after_a, b'b', false), AFTER_AB);
+
+    // dead after an accepting state: the lexeme ends before the byte
+    expect_lexeme(lexer.advance(after_a, b'c', false), a, true);
+    expect_lexeme(lexer.advance(after_ab, b'a', false), ab, true);
+
+    // dead after a non-accepting state: error
+    assert!(lexer.advance(start, b'b', false).is_error());
+
+    // at EOS, only accepting states end a lexeme
+    expect_lexeme(lexer.finish_at_eos(after_a, true), a, false);
+    assert!(lexer.finish_at_eos(start, true).is_error());
+
+    // the same control flow drives lex_views()
+    let views = lexer.lex_views(&all, b"aba").unwrap();
+    let views = views
+        .iter()
+        .map(|v| (v.idx, v.start, v.bytes))
+        .collect::<Vec<_>>();
+    assert_eq!(views, vec![(ab, 0, &b"ab"[..]), (a, 2, &b"a"[..])]);
+    assert!(lexer.lex_views(&all, b"b").is_err());
+
+    println!("mock lexer OK");
+}
@@ -0,0 +1,349 @@
+use std::{collections::HashMap, sync::Arc};
+
+use anyhow::{ensure, Result};
+use llguidance_parser::{
+    api::{GenGrammarOptions, GrammarWithLexer, ParserLimits, TopLevelGrammar},
+    derivre::{RegexAst, RegexBuilder},
+    earley::{
+        grammars_from_json,
+        lexerspec::{LexemeIdx, LexerSpec},
+        Grammar, Parser,
+    },
+    toktrie::{TokEnv, TokRxInfo, TokTrie, TokenId, TokenizerEnv},
+    GrammarBuilder, Logger,
+};
+
+/// Trie with a token for every byte, so that inputs are fed to the parser byte-by-byte.
+fn byte_trie() -> TokTrie {
+    let mut words = (0..=255).map(|x| vec![x]).collect::<Vec<_>>();
+    words.push("<eos>".as_bytes().to_vec());
+    let info = TokRxInfo {
+        vocab_size: words.len() as u32,
+        tok_eos: words.len() as u32 - 1,
+        tok_bos: None,
+        tok_pad: None,
+        tok_unk: None,
+        tok_end_of_turn: None,
+    };
+    TokTrie::from(&info, &words)
+}
+
+struct ByteTokenizer {
+    tok_trie: TokTrie,
+}
+
+impl TokenizerEnv for ByteTokenizer {
+    fn stop(&self) -> ! {
+        panic!("stop called")
+    }
+
+    fn tok_trie(&self) -> &TokTrie {
+        &self.tok_trie
+    }
+
+    fn tokenize_bytes(&self, s: &[u8]) -> Vec<TokenId> {
+        self.tok_trie.greedy_tokenize(s)
+    }
+}
+
+/// Compile a grammar in the JSON format (eg., from `GrammarBuilder`).
+fn json_parser(grammar: TopLevelGrammar, limits: ParserLimits) -> Result<Parser> {
+    let tok_env: TokEnv = Arc::new(ByteTokenizer {
+        tok_trie: byte_trie(),
+    });
+    let mut logger = Logger::new(0, 1);
+    let grammars = grammars_from_json(grammar, &tok_env, &mut logger, limits.clone(), vec![])?;
+    Parser::new(grammars[0].clone(), GenGrammarOptions::default(), limits)
+}
+
+/// Grammar built directly on a lexer spec, to exercise spec features
+/// not exposed in the JSON grammar format.
+/// Symbols are referred to by name; the start symbol is "start".
+struct TestGrammar {
+    spec: LexerSpec,
+    terminals: Vec<(String, LexemeIdx)>,
+    rules: Vec<(String, Vec<String>)>,
+}
+
+impl TestGrammar {
+    fn new() -> Result<Self> {
+        Ok(TestGrammar {
+            spec: LexerSpec::new(RegexBuilder::new(), RegexAst::NoMatch)?,
+            terminals: vec![],
+            rules: vec![],
+        })
+    }
+
+    /// Add a literal lexeme, with a terminal symbol of the same name.
+    fn literal(&mut self, name: &str, literal: &str) -> Result<LexemeIdx> {
+        let idx = self
+            .spec
+            .add_simple_literal(name.to_string(), literal, false)?;
+        self.terminal(name, idx);
+        Ok(idx)
+    }
+
+    /// Add a terminal symbol for a lexeme added to `spec` directly.
+    fn terminal(&mut self, name: &str, idx: LexemeIdx) {
+        self.terminals.push((name.to_string(), idx));
+    }
+
+    fn rule(&mut self, lhs: &str, rhs: &[&str]) {
+        let rhs = rhs.iter().map(|name| name.to_string()).collect();
+        self.rules.push((lhs.to_string(), rhs));
+    }
+
+    fn parser(&self, limits: ParserLimits) -> Result<Parser> {
+        let mut grammar = Grammar::new();
+        let mut symbols = HashMap::new();
+        symbols.insert("start".to_string(), grammar.fresh_symbol("start"));
+        for (name, idx) in &self.terminals {
+            let sym = grammar.fresh_symbol(name);
+            grammar.make_terminal(sym, *idx, &self.spec)?;
+            symbols.insert(name.clone(), sym);
+        }
+        for (lhs, rhs) in &self.rules {
+            for name in std::iter::once(lhs).chain(rhs) {
+                if !symbols.contains_key(name) {
+                    symbols.insert(name.clone(), grammar.fresh_symbol(name));
+                }
+            }
+            let rhs = rhs.iter().map(|name| symbols[name]).collect();
+            grammar.add_rule(symbols[lhs], rhs)?;
+        }
+        let cgrammar = grammar.compile(self.spec.clone());
+        Parser::new(Arc::new(cgrammar), GenGrammarOptions::default(), limits)
+    }
+}
+
+/// Feed `input` to the parser, and check if it's accepted in full.
+fn accepts(parser: &mut Parser, trie: &TokTrie, input: &str) -> Result<bool> {
+    let tokens = trie.greedy_tokenize(input.as_bytes());
+    let msg = parser.apply_tokens(trie, &tokens, 0)?;
+    Ok(msg.is_empty() && parser.is_accepting())
+}
+
+/// An ambiguous grammar where, with two interpretations kept, "a" leaves
+/// the longest match (start -> A . B) and tail -> . C, pruning tail -> . D;
+/// the pruned one is still scanned once the kept ones can't accept the lexeme.
+fn max_live_interpretations(trie: &TokTrie) -> Result<()> {
+    let mut g = TestGrammar::new()?;
+    g.literal("A", "a")?;
+    g.literal("B", "b")?;
+    g.literal("C", "c")?;
+    g.literal("D", "d")?;
+    g.rule("start", &["A", "B"]);
+    g.rule("start", &["A", "tail"]);
+    g.rule("tail", &["C"]);
+    g.rule("tail", &["D"]);
+    let limits = ParserLimits {
+        max_live_interpretations: 2,
+        ..ParserLimits::default()
+    };
+
+    for (input, expected) in [("ab", true), ("ac", true), ("ad", true), ("aa", false)] {
+        let mut parser = g.parser(limits.clone())?;
+        ensure!(
+            accepts(&mut parser, trie, input)? == expected,
+            "{:?}: expected accept={}",
+            input,
+            expected
+        );
+        ensure!(
+            parser.stats().pruned_items > 0,
+            "{:?}: nothing was pruned",
+            input
+        );
+    }
+    Ok(())
+}
+
+/// With A capped at two occurrences, the third one is rejected,
+/// even though the grammar allows any number of them.
+fn max_occurrences(trie: &TokTrie) -> Result<()> {
+    let mut g = TestGrammar::new()?;
+    let a = g.literal("A", "a")?;
+    g.rule("start", &["A"]);
+    g.rule("start", &["A", "start"]);
+    g.spec.set_max_occurrences(a, 2)?;
+    ensure!(
+        g.spec.set_max_occurrences(LexemeIdx::SKIP, 1).is_err(),
+        "SKIP accepted max_occurrences"
+    );
+
+    for (input, expected) in [("a", true), ("aa", true), ("aaa", false)] {
+        let mut parser = g.parser(ParserLimits::default())?;
+        ensure!(
+            accepts(&mut parser, trie, input)? == expected,
+            "{:?}: expected accept={}",
+            input,
+            expected
+        );
+    }
+    Ok(())
+}
+
+/// Once the grammar is complete, lexemes allowed after EOS can follow
+/// (any number of times), but other lexemes cannot.
+fn after_eos(trie: &TokTrie) -> Result<()> {
+    let mut g = TestGrammar::new()?;
+    g.literal("A", "a")?;
+    g.literal("B", "b")?;
+    g.rule("start", &["A", "B"]);
+    let nl = g.spec.add_simple_literal("NL".to_string(), "\n", false)?;
+    g.spec.add_simple_literal("X".to_string(), "x", false)?;
+    g.spec.allow_after_eos(nl)?;
+
+    for (input, expected) in [
+        ("ab", true),
+        ("ab\n", true),
+        ("ab\n\n", true),
+        ("abx", false),
+        ("ab\nb", false),
+        ("a\n", false),
+    ] {
+        let mut parser = g.parser(ParserLimits::default())?;
+        ensure!(
+            accepts(&mut parser, trie, input)? == expected,
+            "{:?}: expected accept={}",
+            input,
+            expected
+        );
+    }
+    Ok(())
+}
+
+/// Nesting up to `max_depth` is accepted, one more level is not;
+/// invalid arguments are reported as errors.
+fn balanced_delimiters(trie: &TokTrie) -> Result<()> {
+    let pairs = [('(', ')'), ('[', ']')];
+    let mut builder = GrammarBuilder::new();
+    builder.add_grammar(GrammarWithLexer::default());
+    for (pairs, max_depth) in [
+        (&pairs[..], 0),
+        (&pairs[..], 1000),
+        (&[('(', ')'), ('[', ')')][..], 2),
+        (&[('|', '|')][..], 2),
+        (&[][..], 2),
+    ] {
+        ensure!(
+            builder.balanced_delimiters(pairs, max_depth).is_err(),
+            "{:?} with max_depth={} accepted",
+            pairs,
+            max_depth
+        );
+    }
+    let root = builder.balanced_delimiters(&pairs, 3)?;
+    builder.set_start_node(root);
+    let grammar = builder.finalize()?;
+
+    for (input, expected) in [
+        ("", true),
+        ("()[]", true),
+        ("([()])[]", true),
+        ("([(())])", false),
+        ("(]", false),
+    ] {
+        let mut parser = json_parser(grammar.clone(), ParserLimits::default())?;
+        ensure!(
+            accepts(&mut parser, trie, input)? == expected,
+            "{:?}: expected accept={}",
+            input,
+            expected
+        );
+    }
+    Ok(())
+}
+
+/// Only the lexemes scanned by the parser are in the coverage set.
+fn lexeme_coverage(trie: &TokTrie) -> Result<()> {
+    let mut g = TestGrammar::new()?;
+    let a = g.literal("A", "a")?;
+    let b = g.literal("B", "b")?;
+    let c = g.literal("C", "c")?;
+    g.literal("D", "d")?;
+    g.rule("start", &["A", "B"]);
+    g.rule("start", &["A", "C", "start"]);
+    g.rule("start", &["D"]);
+
+    for (input, expected) in [("", vec![]), ("ab", vec![a, b]), ("acacab", vec![a, b, c])] {
+        let mut parser = g.parser(ParserLimits::default())?;
+        let tokens = trie.greedy_tokenize(input.as_bytes());
+        parser.apply_tokens(trie, &tokens, 0)?;
+        let covered = parser.lexeme_coverage().iter().collect::<Vec<_>>();
+        let expected = expected
+            .iter()
+            .map(|idx| idx.as_usize() as u32)
+            .collect::<Vec<_>>();
+        ensure!(
+            covered == expected,
+            "{:?}: expected {:?}, got {:?}",
+            input,
+            expected,
+            covered
+        );
+    }
+    Ok(())
+}
+
+/// FOLLOW sets look through the nullable `opt`, and do not include
+/// the end of input (after the final C, and after A in `start -> D A`).
+fn follow_set(_trie: &TokTrie) -> Result<()> {
+    let mut g = TestGrammar::new()?;
+    let a = g.literal("A", "a")?;
+    let b = g.literal("B", "b")?;
+    let c = g.literal("C", "c")?;
+    let d = g.literal("D", "d")?;
+    g.rule("start", &["A", "opt", "C"]);
+    g.rule("start", &["D", "A"]);
+    g.rule("opt", &["B"]);
+    g.rule("opt", &[]);
+
+    let parser = g.parser(ParserLimits::default())?;
+    for (lexeme, expected) in [(a, vec![b, c]), (b, vec![c]), (c, vec![]), (d, vec![a])] {
+        let follow = parser
+            .grammar()
+            .follow_set(lexeme)
+            .iter()
+            .collect::<Vec<_>>();
+        let expected = expected
+            .iter()
+            .map(|idx| idx.as_usize() as u32)
+            .collect::<Vec<_>>();
+        ensure!(
+            follow == expected,
+            "{:?}: expected {:?}, got {:?}",
+            lexeme,
+            expected,
+            follow
+        );
+    }
+    Ok(())
+}
+
+fn main() {
+    let trie = byte_trie();
+    let tests: &[(&str, fn(&TokTrie) -> Result<()>)] = &[
+        ("max_live_interpretations", max_live_interpretations),
+        ("max_occurrences", max_occurrences),
+        ("balanced_delimiters", balanced_delimiters),
+        ("after_eos", after_eos),
+        ("lexeme_coverage", lexeme_coverage),
+        ("follow_set", follow_set),
+    ];
+
+    let mut num_failed = 0;
+    for (name, test) in tests {
+        match test(&trie) {
+            Ok(()) => println!("{} OK", name),
+            Err(e) => {
+                eprintln!("FAIL {}: {}", name, e);
+                num_failed += 1;
+            }
+        }
+    }
+    if num_failed > 0 {
+        eprintln!("{} test(s) failed", num_failed);
+        std::process::exit(1);
+    }
+}